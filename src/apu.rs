@@ -0,0 +1,741 @@
+// The 2A03's sound side, NTSC timing: the frame counter, the two pulse
+// channels and the DMC. The bus clocks it once per CPU cycle and hands the samples to
+// an AudioSink at the end of each frame.
+
+const CPU_CLOCK_HZ: u32 = 1_789_773;
+const DEFAULT_SAMPLE_RATE: u32 = 44_100;
+
+// DMC output periods in CPU cycles, indexed by the low bits of $4010
+const DMC_RATES: [u16; 16] = [428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54];
+
+// Pulse waveforms, one per duty setting, in sequencer order
+const DUTY_TABLE: [[u8; 8]; 4] = [
+    [0, 1, 0, 0, 0, 0, 0, 0],
+    [0, 1, 1, 0, 0, 0, 0, 0],
+    [0, 1, 1, 1, 1, 0, 0, 0],
+    [1, 0, 0, 1, 1, 1, 1, 1],
+];
+
+// Length counter loads, indexed by the top 5 bits of $4003/$4007
+const LENGTH_TABLE: [u8; 32] = [
+    10, 254, 20, 2, 40, 4, 80, 6, 160, 8, 60, 10, 14, 12, 26, 14,
+    12, 16, 24, 18, 48, 20, 96, 22, 192, 24, 72, 26, 16, 28, 32, 30,
+];
+
+// Frame counter steps, in CPU cycles since the sequencer was reset
+const STEP_1: u32 = 7457;
+const STEP_2: u32 = 14913;
+const STEP_3: u32 = 22371;
+// the frame IRQ flag goes up on the last three cycles of the 4-step sequence
+const FOUR_STEP_IRQ: u32 = 29828;
+const FOUR_STEP_4: u32 = 29829;
+const FOUR_STEP_PERIOD: u32 = 29830;
+const FIVE_STEP_5: u32 = 37281;
+const FIVE_STEP_PERIOD: u32 = 37282;
+
+// Where the samples go, e.g. an SDL or cpal queue. Gets a frame's worth at a
+// time, as mono f32 in 0.0..1.0.
+pub trait AudioSink {
+    fn push(&mut self, samples: &[f32]);
+}
+
+// Drops the samples, for running without sound
+pub struct NullSink;
+
+impl AudioSink for NullSink {
+    fn push(&mut self, _samples: &[f32]) {}
+}
+
+#[derive(Debug, Default, Clone)]
+struct Envelope {
+    // set by the length/timer high write, acted on at the next quarter frame
+    start: bool,
+    looping: bool,
+    constant: bool,
+    // the volume too, when constant
+    period: u8,
+    divider: u8,
+    decay: u8,
+}
+
+impl Envelope {
+    fn clock(&mut self) {
+        if self.start {
+            self.start = false;
+            self.decay = 15;
+            self.divider = self.period;
+        } else if self.divider == 0 {
+            self.divider = self.period;
+            if self.decay > 0 {
+                self.decay -= 1;
+            } else if self.looping {
+                self.decay = 15;
+            }
+        } else {
+            self.divider -= 1;
+        }
+    }
+
+    fn volume(&self) -> u8 {
+        if self.constant {
+            self.period
+        } else {
+            self.decay
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+struct Pulse {
+    // pulse 1 negates its sweep with one's complement, pulse 2 with two's
+    ones_complement: bool,
+    enabled: bool,
+    duty: u8,
+    // position in the duty waveform, reset by the length/timer high write
+    step: u8,
+    timer: u16,
+    envelope: Envelope,
+    // the envelope loop flag doubles as the length counter halt
+    length: u8,
+    sweep_enabled: bool,
+    sweep_period: u8,
+    sweep_negate: bool,
+    sweep_shift: u8,
+    sweep_divider: u8,
+    sweep_reload: bool,
+    timer_period: u16,
+}
+
+impl Pulse {
+    fn write_register(&mut self, register: u16, data: u8) {
+        match register {
+            0 => {
+                self.duty = data >> 6;
+                self.envelope.looping = data & 0x20 != 0;
+                self.envelope.constant = data & 0x10 != 0;
+                self.envelope.period = data & 0x0F;
+            }
+            1 => {
+                self.sweep_enabled = data & 0x80 != 0;
+                self.sweep_period = (data >> 4) & 0x07;
+                self.sweep_negate = data & 0x08 != 0;
+                self.sweep_shift = data & 0x07;
+                self.sweep_reload = true;
+            }
+            2 => self.timer_period = (self.timer_period & 0x0700) | data as u16,
+            _ => {
+                self.timer_period = (self.timer_period & 0x00FF) | ((data as u16 & 0x07) << 8);
+                if self.enabled {
+                    self.length = LENGTH_TABLE[(data >> 3) as usize];
+                }
+                self.envelope.start = true;
+                self.step = 0;
+            }
+        }
+    }
+
+    fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        if !enabled {
+            self.length = 0;
+        }
+    }
+
+    fn sweep_target(&self) -> u16 {
+        let change = self.timer_period >> self.sweep_shift;
+        if !self.sweep_negate {
+            self.timer_period + change
+        } else if self.ones_complement {
+            self.timer_period.saturating_sub(change + 1)
+        } else {
+            self.timer_period.saturating_sub(change)
+        }
+    }
+
+    // Silenced by the sweep unit, whether or not the sweep is enabled
+    fn sweep_muted(&self) -> bool {
+        self.timer_period < 8 || self.sweep_target() > 0x7FF
+    }
+
+    // Every other CPU cycle
+    fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period;
+            self.step = (self.step + 1) % 8;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if self.length == 0 || self.sweep_muted() || DUTY_TABLE[self.duty as usize][self.step as usize] == 0 {
+            0
+        } else {
+            self.envelope.volume()
+        }
+    }
+
+    fn clock_quarter_frame(&mut self) {
+        self.envelope.clock();
+    }
+
+    fn clock_half_frame(&mut self) {
+        if !self.envelope.looping && self.length > 0 {
+            self.length -= 1;
+        }
+
+        if self.sweep_divider == 0 && self.sweep_enabled && self.sweep_shift > 0 && !self.sweep_muted() {
+            self.timer_period = self.sweep_target();
+        }
+        if self.sweep_divider == 0 || self.sweep_reload {
+            self.sweep_divider = self.sweep_period;
+            self.sweep_reload = false;
+        } else {
+            self.sweep_divider -= 1;
+        }
+    }
+}
+
+// Plays 1-bit delta samples fetched from CPU memory. The fetches are DMA
+// reads the bus runs for it, see dmc_fetch_address.
+#[derive(Debug, Default, Clone)]
+struct Dmc {
+    irq_enabled: bool,
+    looping: bool,
+    rate: u16,
+    timer: u16,
+    level: u8,
+    sample_address: u16,
+    sample_length: u16,
+    address: u16,
+    bytes_remaining: u16,
+    buffer: Option<u8>,
+    shift: u8,
+    bits_remaining: u8,
+    silence: bool,
+    irq: bool,
+}
+
+impl Dmc {
+    fn new() -> Self {
+        Dmc { rate: DMC_RATES[0], bits_remaining: 8, silence: true, ..Dmc::default() }
+    }
+
+    fn write_register(&mut self, register: u16, data: u8) {
+        match register {
+            0 => {
+                self.irq_enabled = data & 0x80 != 0;
+                if !self.irq_enabled {
+                    self.irq = false;
+                }
+                self.looping = data & 0x40 != 0;
+                self.rate = DMC_RATES[(data & 0x0F) as usize];
+            }
+            1 => self.level = data & 0x7F,
+            2 => self.sample_address = 0xC000 | ((data as u16) << 6),
+            _ => self.sample_length = ((data as u16) << 4) | 1,
+        }
+    }
+
+    // Disabling stops the sample once the buffered byte plays out, enabling
+    // restarts it unless it's still going. Acknowledges the IRQ either way.
+    fn set_enabled(&mut self, enabled: bool) {
+        self.irq = false;
+        if !enabled {
+            self.bytes_remaining = 0;
+        } else if self.bytes_remaining == 0 {
+            self.restart();
+        }
+    }
+
+    fn restart(&mut self) {
+        self.address = self.sample_address;
+        self.bytes_remaining = self.sample_length;
+    }
+
+    fn fetch_address(&self) -> Option<u16> {
+        if self.buffer.is_none() && self.bytes_remaining > 0 {
+            Some(self.address)
+        } else {
+            None
+        }
+    }
+
+    fn fill_buffer(&mut self, data: u8) {
+        self.buffer = Some(data);
+        // wraps into the top of ROM, not the zero page
+        self.address = if self.address == 0xFFFF { 0x8000 } else { self.address + 1 };
+        self.bytes_remaining -= 1;
+        if self.bytes_remaining == 0 {
+            if self.looping {
+                self.restart();
+            } else if self.irq_enabled {
+                self.irq = true;
+            }
+        }
+    }
+
+    fn clock(&mut self) {
+        if self.timer > 0 {
+            self.timer -= 1;
+            return;
+        }
+        self.timer = self.rate - 1;
+
+        if !self.silence {
+            if self.shift & 1 == 1 {
+                if self.level <= 125 {
+                    self.level += 2;
+                }
+            } else if self.level >= 2 {
+                self.level -= 2;
+            }
+        }
+        self.shift >>= 1;
+        self.bits_remaining -= 1;
+        if self.bits_remaining == 0 {
+            self.bits_remaining = 8;
+            match self.buffer.take() {
+                Some(data) => {
+                    self.shift = data;
+                    self.silence = false;
+                }
+                None => self.silence = true,
+            }
+        }
+    }
+}
+
+pub struct Apu {
+    pulse: [Pulse; 2],
+    dmc: Dmc,
+    five_step: bool,
+    irq_inhibit: bool,
+    frame_irq: bool,
+    // CPU cycles since the sequencer was last reset
+    sequencer: u32,
+    // cycles left before a $4017 write resets the sequencer, 0 when none is due
+    reset_delay: u8,
+    cycles: u64,
+    sample_rate: u32,
+    // counts up by the sample rate each cycle, a sample is due past the CPU clock
+    sample_clock: u32,
+    // since the last end_frame
+    samples: Vec<f32>,
+    sink: Box<dyn AudioSink>,
+}
+
+impl Apu {
+    pub fn new() -> Self {
+        Apu {
+            pulse: [Pulse { ones_complement: true, ..Pulse::default() }, Pulse::default()],
+            dmc: Dmc::new(),
+            five_step: false,
+            irq_inhibit: false,
+            frame_irq: false,
+            sequencer: 0,
+            reset_delay: 0,
+            cycles: 0,
+            sample_rate: DEFAULT_SAMPLE_RATE,
+            sample_clock: 0,
+            samples: vec![],
+            sink: Box::new(NullSink),
+        }
+    }
+
+    // As on a console reset: $4015 is cleared, so the channels go quiet and
+    // the DMC gives up a fetch it was waiting on
+    pub fn reset(&mut self) {
+        self.write_status(0);
+        self.frame_irq = false;
+    }
+
+    pub fn set_audio_sink(&mut self, sink: Box<dyn AudioSink>) {
+        self.sink = sink;
+    }
+
+    // Samples per second, 44100 by default
+    pub fn set_sample_rate(&mut self, rate: u32) {
+        self.sample_rate = rate;
+    }
+
+    // Output since the last end_frame, oldest first
+    pub fn samples(&self) -> &[f32] {
+        &self.samples
+    }
+
+    // Hands this frame's samples to the sink. The bus calls it as each
+    // frame completes.
+    pub fn end_frame(&mut self) {
+        self.sink.push(&self.samples);
+        self.samples.clear();
+    }
+
+    // $4000-$4013 and $4017, $4015 goes through write_status
+    pub fn write_register(&mut self, address: u16, data: u8) {
+        match address {
+            0x4000 ..= 0x4003 => self.pulse[0].write_register(address - 0x4000, data),
+            0x4004 ..= 0x4007 => self.pulse[1].write_register(address - 0x4004, data),
+            0x4010 ..= 0x4013 => self.dmc.write_register(address - 0x4010, data),
+            0x4017 => self.write_frame_counter(data),
+            // triangle and noise aren't emulated
+            _ => {}
+        }
+    }
+
+    pub fn write_status(&mut self, data: u8) {
+        self.pulse[0].set_enabled(data & 0x01 != 0);
+        self.pulse[1].set_enabled(data & 0x02 != 0);
+        self.dmc.set_enabled(data & 0x10 != 0);
+    }
+
+    // Acknowledges the frame IRQ
+    pub fn read_status(&mut self) -> u8 {
+        let status = self.peek_status();
+        self.frame_irq = false;
+        status
+    }
+
+    pub fn peek_status(&self) -> u8 {
+        let mut status = 0;
+        for (bit, pulse) in self.pulse.iter().enumerate() {
+            if pulse.length > 0 {
+                status |= 1 << bit;
+            }
+        }
+        if self.dmc.bytes_remaining > 0 {
+            status |= 0x10;
+        }
+        if self.frame_irq {
+            status |= 0x40;
+        }
+        if self.dmc.irq {
+            status |= 0x80;
+        }
+        status
+    }
+
+    // Bit 7 picks the 5-step sequence, bit 6 inhibits (and acknowledges) the
+    // frame IRQ. The sequencer restarts 3 cycles after a write on an even
+    // cycle, 4 after one on an odd cycle.
+    fn write_frame_counter(&mut self, data: u8) {
+        self.five_step = data & 0x80 != 0;
+        self.irq_inhibit = data & 0x40 != 0;
+        if self.irq_inhibit {
+            self.frame_irq = false;
+        }
+        self.reset_delay = if self.cycles & 1 == 0 { 3 } else { 4 };
+    }
+
+    // Level of the frame counter's and the DMC's IRQ lines
+    pub fn irq_pending(&self) -> bool {
+        self.frame_irq || self.dmc.irq
+    }
+
+    // Where the DMC wants its next sample byte read from, once its buffer
+    // is empty. The bus reads it and passes it to fill_dmc_buffer.
+    pub fn dmc_fetch_address(&self) -> Option<u16> {
+        self.dmc.fetch_address()
+    }
+
+    pub fn fill_dmc_buffer(&mut self, data: u8) {
+        self.dmc.fill_buffer(data);
+    }
+
+    pub fn tick(&mut self, cycles: u16) {
+        for _ in 0..cycles {
+            self.clock();
+        }
+    }
+
+    fn clock(&mut self) {
+        self.cycles += 1;
+        if self.cycles & 1 == 0 {
+            for pulse in self.pulse.iter_mut() {
+                pulse.clock_timer();
+            }
+        }
+        self.dmc.clock();
+
+        self.sample_clock += self.sample_rate;
+        if self.sample_clock >= CPU_CLOCK_HZ {
+            self.sample_clock -= CPU_CLOCK_HZ;
+            let sample = self.mix();
+            self.samples.push(sample);
+        }
+
+        self.clock_frame_counter();
+    }
+
+    // The hardware's nonlinear mixer, with the missing triangle and noise
+    // at 0
+    fn mix(&self) -> f32 {
+        let pulses = self.pulse.iter().map(|pulse| pulse.output() as f32).sum::<f32>();
+        let pulse_out = if pulses == 0.0 { 0.0 } else { 95.88 / (8128.0 / pulses + 100.0) };
+        let dmc = self.dmc.level as f32;
+        let dmc_out = if dmc == 0.0 { 0.0 } else { 159.79 / (22638.0 / dmc + 100.0) };
+        pulse_out + dmc_out
+    }
+
+    fn clock_frame_counter(&mut self) {
+        if self.reset_delay > 0 {
+            self.reset_delay -= 1;
+            if self.reset_delay == 0 {
+                self.sequencer = 0;
+                // the 5-step mode clocks everything right away
+                if self.five_step {
+                    self.clock_quarter_frame();
+                    self.clock_half_frame();
+                }
+                return;
+            }
+        }
+
+        self.sequencer += 1;
+        if self.five_step {
+            match self.sequencer {
+                STEP_1 | STEP_3 => self.clock_quarter_frame(),
+                STEP_2 | FIVE_STEP_5 => {
+                    self.clock_quarter_frame();
+                    self.clock_half_frame();
+                }
+                FIVE_STEP_PERIOD => self.sequencer = 0,
+                _ => {}
+            }
+        } else {
+            match self.sequencer {
+                STEP_1 | STEP_3 => self.clock_quarter_frame(),
+                STEP_2 | FOUR_STEP_4 => {
+                    self.clock_quarter_frame();
+                    self.clock_half_frame();
+                }
+                _ => {}
+            }
+            if self.sequencer >= FOUR_STEP_IRQ && !self.irq_inhibit {
+                self.frame_irq = true;
+            }
+            if self.sequencer == FOUR_STEP_PERIOD {
+                self.sequencer = 0;
+            }
+        }
+    }
+
+    fn clock_quarter_frame(&mut self) {
+        for pulse in self.pulse.iter_mut() {
+            pulse.clock_quarter_frame();
+        }
+    }
+
+    fn clock_half_frame(&mut self) {
+        for pulse in self.pulse.iter_mut() {
+            pulse.clock_half_frame();
+        }
+    }
+}
+
+impl Default for Apu {
+    fn default() -> Self {
+        Apu::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::cartridge::test::nrom;
+    use crate::cpu::Mem;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    // Keeps each push apart
+    struct RecordingSink(Rc<RefCell<Vec<Vec<f32>>>>);
+
+    impl AudioSink for RecordingSink {
+        fn push(&mut self, samples: &[f32]) {
+            self.0.borrow_mut().push(samples.to_vec());
+        }
+    }
+
+    // Pulse 1 on, envelope period 5, length 254 (index 1)
+    fn apu_with_pulse() -> Apu {
+        let mut apu = Apu::new();
+        apu.write_status(0x01);
+        apu.write_register(0x4000, 0x05);
+        apu.write_register(0x4003, 0x08);
+        apu
+    }
+
+    #[test]
+    fn test_four_step_sequence() {
+        let mut apu = apu_with_pulse();
+        // on an even cycle: takes effect 3 cycles later
+        apu.write_register(0x4017, 0x00);
+        apu.tick(3 + STEP_1 as u16 - 1);
+        assert_eq!(apu.pulse[0].envelope.decay, 0);
+        apu.tick(1);
+        assert_eq!(apu.pulse[0].envelope.decay, 15);
+        assert_eq!(apu.pulse[0].length, 254);
+
+        apu.tick((STEP_2 - STEP_1) as u16);
+        assert_eq!(apu.pulse[0].length, 253);
+        apu.tick((STEP_3 - STEP_2) as u16);
+        assert_eq!(apu.pulse[0].length, 253);
+
+        apu.tick((FOUR_STEP_IRQ - STEP_3) as u16 - 1);
+        assert!(!apu.irq_pending());
+        apu.tick(1);
+        assert!(apu.irq_pending());
+        apu.tick(1);
+        assert_eq!(apu.pulse[0].length, 252);
+        assert_eq!(apu.peek_status(), 0x41);
+
+        // acknowledged by the read, then raised again by the sequence's last cycle
+        assert_eq!(apu.read_status(), 0x41);
+        assert!(!apu.irq_pending());
+        apu.tick(1);
+        assert!(apu.irq_pending());
+        assert_eq!(apu.sequencer, 0);
+        apu.read_status();
+
+        // and the next round starts over
+        apu.tick(STEP_1 as u16);
+        assert!(!apu.irq_pending());
+        assert_eq!(apu.pulse[0].envelope.divider, 1);
+    }
+
+    #[test]
+    fn test_five_step_sequence() {
+        let mut apu = apu_with_pulse();
+        apu.tick(1);
+        // on an odd cycle: 4 cycles, and everything gets clocked at once
+        apu.write_register(0x4017, 0x80);
+        apu.tick(3);
+        assert_eq!(apu.pulse[0].length, 254);
+        apu.tick(1);
+        assert_eq!(apu.pulse[0].length, 253);
+        assert_eq!(apu.pulse[0].envelope.decay, 15);
+
+        apu.tick(STEP_2 as u16);
+        assert_eq!(apu.pulse[0].length, 252);
+        // step 4 of the 4-step sequence does nothing here
+        apu.tick((FOUR_STEP_PERIOD - STEP_2) as u16);
+        assert_eq!(apu.pulse[0].length, 252);
+        apu.tick((FIVE_STEP_5 - FOUR_STEP_PERIOD) as u16);
+        assert_eq!(apu.pulse[0].length, 251);
+
+        apu.tick(FIVE_STEP_PERIOD as u16);
+        assert!(!apu.irq_pending());
+    }
+
+    #[test]
+    fn test_frame_irq_inhibit() {
+        let mut apu = Apu::new();
+        apu.tick(FOUR_STEP_PERIOD as u16);
+        assert!(apu.irq_pending());
+
+        apu.write_register(0x4017, 0x40);
+        assert!(!apu.irq_pending());
+        apu.tick(FOUR_STEP_PERIOD as u16 * 2);
+        assert!(!apu.irq_pending());
+    }
+
+    #[test]
+    fn test_length_halt_and_disable() {
+        let mut apu = apu_with_pulse();
+        apu.write_register(0x4000, 0x20);
+        apu.write_register(0x4017, 0x80);
+        apu.tick(4);
+        assert_eq!(apu.pulse[0].length, 254);
+
+        apu.write_status(0x00);
+        assert_eq!(apu.peek_status() & 0x01, 0);
+        // no reload while disabled
+        apu.write_register(0x4003, 0x08);
+        assert_eq!(apu.pulse[0].length, 0);
+    }
+
+    #[test]
+    fn test_sweep() {
+        let mut apu = Apu::new();
+        // period 0x100, enabled, divider period 0, shift 1, upwards
+        apu.write_register(0x4002, 0x00);
+        apu.write_register(0x4003, 0x01);
+        apu.write_register(0x4001, 0x81);
+        apu.clock_half_frame();
+        assert_eq!(apu.pulse[0].timer_period, 0x180);
+
+        // pulse 1 subtracts one more than pulse 2
+        apu.write_register(0x4001, 0x89);
+        apu.clock_half_frame();
+        assert_eq!(apu.pulse[0].timer_period, 0x180 - 0xC0 - 1);
+        apu.write_register(0x4006, 0x80);
+        apu.write_register(0x4005, 0x89);
+        apu.clock_half_frame();
+        assert_eq!(apu.pulse[1].timer_period, 0x40);
+    }
+
+    #[test]
+    fn test_sink_gets_each_frame() {
+        let pushes = Rc::new(RefCell::new(vec![]));
+        let mut bus = Bus::new(nrom());
+        bus.apu_mut().set_audio_sink(Box::new(RecordingSink(pushes.clone())));
+        // both pulses on: 50% and 25% duty, constant volume 15 and 8,
+        // different periods so the mix isn't a plain square wave
+        let mut reference = Apu::new();
+        for &(addr, data) in [(0x4015, 0x03), (0x4000, 0xBF), (0x4002, 0x40), (0x4003, 0x08),
+                              (0x4004, 0x78), (0x4006, 0x90), (0x4007, 0x08)].iter() {
+            bus.mem_write(addr, data);
+            if addr == 0x4015 {
+                reference.write_status(data);
+            } else {
+                reference.write_register(addr, data);
+            }
+        }
+
+        let mut cycles = 0;
+        while bus.ppu().frame_count() == 0 {
+            bus.tick(1);
+            cycles += 1;
+        }
+        assert_eq!(pushes.borrow().len(), 1);
+        assert!(bus.apu().samples().is_empty());
+
+        reference.tick(cycles);
+        let pushed = &pushes.borrow()[0];
+        assert_eq!(pushed.as_slice(), reference.samples());
+        // a frame is 1/60s
+        assert!((730..740).contains(&pushed.len()));
+        assert!(pushed.iter().any(|sample| *sample > 0.0));
+        assert!(pushed.contains(&0.0));
+    }
+
+    #[test]
+    fn test_dmc_fetches() {
+        let mut apu = Apu::new();
+        // IRQ on, no loop, fastest rate; 17 bytes from 0xC040
+        apu.write_register(0x4010, 0x8F);
+        apu.write_register(0x4012, 0x01);
+        apu.write_register(0x4013, 0x01);
+        assert_eq!(apu.dmc_fetch_address(), None);
+        apu.write_status(0x10);
+        assert_eq!(apu.peek_status(), 0x10);
+
+        let mut fetched = vec![];
+        while let Some(address) = apu.dmc_fetch_address() {
+            fetched.push(address);
+            // all ones: the level climbs by 2 per bit
+            apu.fill_dmc_buffer(0xFF);
+            assert_eq!(apu.dmc_fetch_address(), None);
+            apu.tick(8 * 54);
+        }
+        assert_eq!(fetched, (0xC040..0xC051).collect::<Vec<u16>>());
+        assert!(apu.irq_pending());
+        assert_eq!(apu.peek_status(), 0x80);
+        assert!(apu.dmc.level > 0);
+
+        // acknowledged through $4015
+        apu.write_status(0x00);
+        assert!(!apu.irq_pending());
+    }
+}
@@ -1,74 +1,882 @@
+use std::cell::{Cell, Ref, RefCell};
 use crate::cpu::Mem;
-use crate::cartridge::Rom;
+use crate::cartridge::{Mirroring, Rom};
+use crate::ppu::NesPPU;
+use crate::apu::Apu;
+use crate::mapper::{self, SharedMapper};
+use crate::joypad::{Joypad, JoypadButton};
+use crate::frame::Frame;
+use crate::gamegenie::GameGenie;
+use crate::timing::{self, Region};
 const RAM: u16 = 0x0000;
 const RAM_END: u16 = 0x1FFF;
 const PPU_REG: u16 = 0x2000;
 const PPU_REG_END: u16 = 0x3FFF;
+const APU_IO_REG: u16 = 0x4000;
+const APU_IO_REG_END: u16 = 0x401F;
+const APU_REG: u16 = 0x4000;
+const APU_REG_END: u16 = 0x4013;
+const APU_STATUS: u16 = 0x4015;
+// shares its address with the second controller, writes go here
+const FRAME_COUNTER: u16 = 0x4017;
+const OAM_DMA: u16 = 0x4014;
+const JOYPAD1: u16 = 0x4016;
+const JOYPAD2: u16 = 0x4017;
+// CPU test mode registers, disabled on retail consoles
+const TEST_REG: u16 = 0x4018;
+const TEST_REG_END: u16 = 0x401F;
+const PRG_RAM: u16 = 0x6000;
+const PRG_RAM_END: u16 = 0x7FFF;
+const PRG_RAM_SIZE: usize = 0x2000;
+const PRG_ROM: u16 = 0x8000;
+const PRG_ROM_END: u16 = 0xFFFF;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct RegionStats {
+    pub reads: usize,
+    pub writes: usize,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct AccessStats {
+    pub ram: RegionStats,
+    pub ppu_regs: RegionStats,
+    pub apu_io: RegionStats,
+    pub prg_ram: RegionStats,
+    pub prg_rom: RegionStats,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum RomWritePolicy {
+    Panic,
+    Ignore,
+    // keep the write in rom_write_log() for later inspection
+    Log,
+    RouteToMapper,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct RomWrite {
+    pub pc: u16,
+    pub addr: u16,
+    pub data: u8,
+}
+
+// Gets the address and the value the bus would have returned
+pub type ReadPatch = Box<dyn Fn(u16, u8) -> u8>;
+
+// Called as each frame completes with the finished picture, the frame's
+// audio and controller 1, so a frontend can present, queue the sound and
+// poll its input in one place
+pub type FrameCallback = Box<dyn FnMut(&Frame, &[f32], &mut Joypad)>;
+
+// FNV-1a, for state hashes that come out the same on every run and build
+pub const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+
+pub fn fnv1a(hash: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(hash, |hash, byte| (hash ^ *byte as u64).wrapping_mul(0x100000001b3))
+}
 
 pub struct Bus {
-    cpu_vram: [u8; 2048],
-    rom: Rom,
+    cpu_vram: [u8; 0x2000],
+    ram_mask: u16,
+    mapper: SharedMapper,
+    // the cartridge saves PRG RAM
+    battery: bool,
+    prg_ram: [u8; PRG_RAM_SIZE],
+    // register reads have side effects (vblank ack, data buffer) but
+    // mem_read only borrows the bus
+    ppu: RefCell<NesPPU>,
+    // reading $4015 acknowledges the frame IRQ
+    apu: RefCell<Apu>,
+    // reading shifts the button register
+    joypad1: RefCell<Joypad>,
+    // the current instruction read $4016 or $4017
+    joypad_read: Cell<bool>,
+    dmc_read_corruption: bool,
+    // mem_read only borrows the bus, hence the Cell
+    stats: Option<Cell<AccessStats>>,
+    cheats: Vec<GameGenie>,
+    read_patches: Vec<(u16, ReadPatch)>,
+    rom_write_policy: RomWritePolicy,
+    rom_writes: Vec<RomWrite>,
+    instruction_pc: u16,
+    nmi_interrupt: Option<()>,
+    irq_line: bool,
+    // page written to 0x4014, copied once the writing instruction is done
+    oam_dma: Option<u8>,
+    frame_callback: Option<FrameCallback>,
 }
 
 impl Bus {
     pub fn new(rom: Rom) -> Self {
+        let battery = rom.battery;
+        let mirroring = rom.screen_mirroring;
+        let mut bus = Bus::with_mapper(mapper::from_rom(rom), mirroring);
+        bus.battery = battery;
+        bus
+    }
+
+    // A board the ROM header can't describe, e.g. a test stub
+    pub fn with_mapper(mapper: SharedMapper, mirroring: Mirroring) -> Self {
+        let ppu = NesPPU::new(mapper.clone(), mirroring);
         Bus {
-            cpu_vram: [0; 2048],
-            rom: rom,
+            cpu_vram: [0; 0x2000],
+            ram_mask: 0b0000011111111111,
+            mapper,
+            battery: false,
+            prg_ram: [0; PRG_RAM_SIZE],
+            ppu: RefCell::new(ppu),
+            apu: RefCell::new(Apu::new()),
+            joypad1: RefCell::new(Joypad::new()),
+            joypad_read: Cell::new(false),
+            dmc_read_corruption: false,
+            stats: None,
+            cheats: vec![],
+            read_patches: vec![],
+            rom_write_policy: RomWritePolicy::RouteToMapper,
+            rom_writes: vec![],
+            instruction_pc: 0,
+            nmi_interrupt: None,
+            irq_line: false,
+            oam_dma: None,
+            frame_callback: None,
+        }
+    }
+
+    // Runs before the APU hands the same samples to its AudioSink
+    pub fn set_frame_callback<F>(&mut self, callback: F)
+    where
+        F: FnMut(&Frame, &[f32], &mut Joypad) + 'static,
+    {
+        self.frame_callback = Some(Box::new(callback));
+    }
+
+    pub fn clear_frame_callback(&mut self) {
+        self.frame_callback = None;
+    }
+
+    pub fn set_rom_write_policy(&mut self, policy: RomWritePolicy) {
+        self.rom_write_policy = policy;
+    }
+
+    pub fn rom_write_log(&self) -> &[RomWrite] {
+        &self.rom_writes
+    }
+
+    // Set by the CPU on each opcode fetch, so bus diagnostics can tell
+    // which instruction made an access
+    pub fn set_instruction_pc(&mut self, pc: u16) {
+        self.instruction_pc = pc;
+        self.joypad_read.set(false);
+    }
+
+    // Accuracy option, off by default: a DMC fetch landing on a controller
+    // read makes the CPU repeat the read, which clocks the port once more
+    // and drops a button bit, as on the 2A03. Timing is per instruction, a
+    // fetch during an instruction that read the port counts as landing on
+    // the read.
+    pub fn set_dmc_read_corruption(&mut self, enabled: bool) {
+        self.dmc_read_corruption = enabled;
+    }
+
+    // Contents of the battery-backed PRG RAM, for the frontend to persist.
+    // None when the cartridge has no battery.
+    pub fn save_ram(&self) -> Option<&[u8]> {
+        if self.battery {
+            Some(&self.prg_ram)
+        } else {
+            None
+        }
+    }
+
+    pub fn load_ram(&mut self, data: &[u8]) -> Result<(), String> {
+        if !self.battery {
+            return Err("Cartridge has no battery-backed RAM".to_string());
+        }
+        if data.len() != PRG_RAM_SIZE {
+            return Err(format!("Save RAM is {} bytes, expected {}", data.len(), PRG_RAM_SIZE));
         }
+        self.prg_ram.copy_from_slice(data);
+        Ok(())
     }
 
-    fn read_prg_rom(&self, mut addr: u16) -> u8 {
-        addr -= 0x8000;
-        if self.rom.prg_rom.len() == 0x4000 && addr >= 0x4000 {
-            //mirror if needed
-            addr = addr % 0x4000;
+    pub fn ppu(&self) -> Ref<'_, NesPPU> {
+        self.ppu.borrow()
+    }
+
+    pub fn ppu_mut(&mut self) -> &mut NesPPU {
+        self.ppu.get_mut()
+    }
+
+    pub fn apu(&self) -> Ref<'_, Apu> {
+        self.apu.borrow()
+    }
+
+    pub fn apu_mut(&mut self) -> &mut Apu {
+        self.apu.get_mut()
+    }
+
+    // Controller in port 1
+    pub fn set_button_pressed(&mut self, button: JoypadButton, pressed: bool) {
+        self.joypad1.get_mut().set_button_pressed(button, pressed);
+    }
+
+    pub fn buttons(&self) -> JoypadButton {
+        self.joypad1.borrow().buttons()
+    }
+
+    pub fn set_buttons(&mut self, buttons: JoypadButton) {
+        self.joypad1.get_mut().set_buttons(buttons);
+    }
+
+    // Latched until the CPU polls it, the PPU raises this on entering vblank
+    pub fn trigger_nmi(&mut self) {
+        self.nmi_interrupt = Some(());
+    }
+
+    pub fn poll_nmi_status(&mut self) -> Option<()> {
+        self.nmi_interrupt.take()
+    }
+
+    // Runs the PPU and the APU for the CPU cycles just spent
+    pub fn tick(&mut self, cycles: u16) {
+        self.mapper.borrow_mut().cpu_clock(cycles);
+        self.apu.get_mut().tick(cycles);
+        let dots = timing::cpu_cycles_to_ppu_dots(cycles as usize, Region::NTSC);
+        let frame = self.ppu.get_mut().frame_count();
+        if self.ppu.get_mut().tick(dots) {
+            self.trigger_nmi();
+        }
+        if self.ppu.get_mut().frame_count() != frame {
+            let apu = self.apu.get_mut();
+            if let Some(ref mut callback) = self.frame_callback {
+                callback(self.ppu.get_mut().screen(), apu.samples(), self.joypad1.get_mut());
+            }
+            apu.end_frame();
         }
-        self.rom.prg_rom[addr as usize]
+    }
+
+    // Runs the OAM DMA requested through 0x4014, if any. Called by the CPU
+    // after each instruction, true when a transfer ran so it can account
+    // for the stall.
+    pub fn poll_oam_dma(&mut self) -> bool {
+        match self.oam_dma.take() {
+            Some(page) => {
+                self.oam_dma(page);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Runs the sample fetch the DMC is waiting for, if any. Called by the
+    // CPU after each instruction, true when it ran so the CPU can account
+    // for the stall.
+    pub fn poll_dmc_dma(&mut self) -> bool {
+        let address = match self.apu.get_mut().dmc_fetch_address() {
+            Some(address) => address,
+            None => return false,
+        };
+        let data = self.mem_read(address);
+        self.apu.get_mut().fill_dmc_buffer(data);
+        if self.dmc_read_corruption && self.joypad_read.get() {
+            self.joypad1.get_mut().read();
+        }
+        true
+    }
+
+    // Drops a requested OAM DMA the CPU hasn't run yet, OAM is left as is
+    pub fn cancel_oam_dma(&mut self) {
+        self.oam_dma = None;
+    }
+
+    // Copies a 256 byte CPU page to OAM, starting at the current OAMADDR
+    fn oam_dma(&mut self, page: u8) {
+        let start = (page as u16) << 8;
+        let mut buffer = [0u8; 256];
+        for (i, byte) in buffer.iter_mut().enumerate() {
+            *byte = self.mem_read(start + i as u16);
+        }
+
+        let ppu = self.ppu.get_mut();
+        for byte in buffer.iter() {
+            ppu.write_to_oam_data(*byte);
+        }
+    }
+
+    // Level-triggered: stays asserted until the source (mapper, APU frame
+    // counter) releases it, and is only taken while INTERRUPT is clear
+    pub fn set_irq(&mut self, asserted: bool) {
+        self.irq_line = asserted;
+    }
+
+    // Wired-OR of the sources: set_irq, the APU frame counter and the
+    // cartridge
+    pub fn irq_asserted(&self) -> bool {
+        self.irq_line || self.apu.borrow().irq_pending() || self.mapper_irq_pending()
+    }
+
+    // Whatever IRQ source the board has: MMC3 scanline counter, CPU cycle
+    // counters
+    pub fn mapper_irq_pending(&self) -> bool {
+        self.mapper.borrow().irq_pending()
+    }
+
+    // Power-on RAM holds garbage on the hardware and some programs seed
+    // their RNG from it. Fills internal RAM with bytes derived from seed
+    // instead of zeroes, the same ones for the same seed on every run.
+    pub fn fill_ram(&mut self, seed: u64) {
+        // xorshift64, which must not start at 0
+        let mut state = seed ^ FNV_OFFSET;
+        for byte in self.cpu_vram.iter_mut() {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            *byte = (state >> 32) as u8;
+        }
+    }
+
+    // Folds RAM, PRG RAM and the PPU's memory and registers into hash
+    pub fn hash_state(&self, hash: u64) -> u64 {
+        let hash = fnv1a(hash, &self.cpu_vram);
+        let hash = fnv1a(hash, &self.prg_ram);
+        fnv1a(hash, &self.ppu.borrow().save_state())
+    }
+
+    // Debugging aid: backs all of 0x0000-0x1FFF with 8KB of distinct RAM
+    // instead of mirroring 2KB, so it's obvious where a program wrote.
+    // Not what the hardware does: code relying on the mirrors breaks.
+    pub fn with_flat_ram(rom: Rom) -> Self {
+        let mut bus = Bus::new(rom);
+        bus.ram_mask = RAM_END;
+        bus
+    }
+
+    pub fn enable_access_stats(&mut self) {
+        self.stats = Some(Cell::new(AccessStats::default()));
+    }
+
+    pub fn disable_access_stats(&mut self) {
+        self.stats = None;
+    }
+
+    pub fn access_stats(&self) -> Option<AccessStats> {
+        self.stats.as_ref().map(|stats| stats.get())
+    }
+
+    fn count_access(&self, address: u16, write: bool) {
+        if let Some(ref stats) = self.stats {
+            let mut current = stats.get();
+            let region = match address {
+                RAM ..= RAM_END => &mut current.ram,
+                PPU_REG ..= PPU_REG_END => &mut current.ppu_regs,
+                APU_IO_REG ..= APU_IO_REG_END => &mut current.apu_io,
+                PRG_RAM ..= PRG_RAM_END => &mut current.prg_ram,
+                PRG_ROM ..= PRG_ROM_END => &mut current.prg_rom,
+                _ => return,
+            };
+
+            if write {
+                region.writes += 1;
+            } else {
+                region.reads += 1;
+            }
+            stats.set(current);
+        }
+    }
+
+    pub fn add_game_genie(&mut self, code: GameGenie) {
+        self.cheats.push(code);
+    }
+
+    pub fn clear_game_genie(&mut self) {
+        self.cheats.clear();
+    }
+
+    // Applied after the normal read, in the order they were added
+    pub fn add_read_patch<F>(&mut self, addr: u16, patch: F)
+    where
+        F: Fn(u16, u8) -> u8 + 'static,
+    {
+        self.read_patches.push((addr, Box::new(patch)));
+    }
+
+    pub fn clear_read_patches(&mut self) {
+        self.read_patches.clear();
+    }
+
+    fn write_prg_rom(&mut self, addr: u16, data: u8) {
+        match self.rom_write_policy {
+            RomWritePolicy::Panic => panic!("Do not write on ROM space !!"),
+            RomWritePolicy::Ignore => {}
+            RomWritePolicy::Log => self.rom_writes.push(RomWrite {
+                pc: self.instruction_pc,
+                addr,
+                data,
+            }),
+            RomWritePolicy::RouteToMapper => self.mapper.borrow_mut().cpu_write(addr, data),
+        }
+    }
+
+    // Loader access to the ROM image, bypassing the write policy. Changes
+    // the bank currently mapped at address, e.g. on a 16KB NROM writing
+    // 0xC000 also changes 0x8000.
+    pub fn patch_prg_rom(&mut self, address: u16, data: u8) {
+        self.mapper.borrow_mut().patch_prg(address, data);
+    }
+
+    fn read_prg_rom(&self, address: u16) -> u8 {
+        let data = self.mapper.borrow().cpu_read(address);
+        self.cheats.iter().fold(data, |data, code| code.apply(address, data))
+    }
+}
+
+impl Bus {
+    // Same value as mem_read, minus the side effects: no vblank ack, no PPU
+    // read buffer or joypad shift, no access counting. For debuggers and
+    // tracers.
+    pub fn peek(&self, address: u16) -> u8 {
+        let data = match address {
+            RAM ..= RAM_END => self.cpu_vram[(address & self.ram_mask) as usize],
+
+            PPU_REG ..= PPU_REG_END => {
+                let ppu = self.ppu.borrow();
+                match address & 0b0010000000000111 {
+                    0x2002 => ppu.peek_status(),
+                    0x2004 => ppu.peek_oam_data(),
+                    0x2007 => ppu.peek_data(),
+                    _ => ppu.open_bus(),
+                }
+            }
+
+            APU_STATUS => self.apu.borrow().peek_status(),
+
+            JOYPAD1 => self.joypad1.borrow().peek(),
+
+            TEST_REG ..= TEST_REG_END => (address >> 8) as u8,
+
+            PRG_RAM ..= PRG_RAM_END => self.prg_ram[(address - PRG_RAM) as usize],
+
+            PRG_ROM ..= PRG_ROM_END => self.read_prg_rom(address),
+
+            _ => 0,
+        };
+
+        self.apply_read_patches(address, data)
+    }
+
+    fn apply_read_patches(&self, address: u16, data: u8) -> u8 {
+        self.read_patches
+            .iter()
+            .filter(|(addr, _)| *addr == address)
+            .fold(data, |data, (_, patch)| patch(address, data))
     }
 }
 
 impl Mem for Bus {
     fn mem_read(&self, address: u16) -> u8 {
-        match address {
+        self.count_access(address, false);
+
+        let data = match address {
             RAM ..= RAM_END => {
-                let mir_down_address = address & 0b0000011111111111;
+                let mir_down_address = address & self.ram_mask;
                 self.cpu_vram[mir_down_address as usize]
             }
 
             PPU_REG ..= PPU_REG_END => {
                 let mir_down_address = address & 0b0010000000000111;
-                todo!("Impl PPU")
+                let mut ppu = self.ppu.borrow_mut();
+                match mir_down_address {
+                    0x2002 => ppu.read_status(),
+                    0x2004 => ppu.read_oam_data(),
+                    0x2007 => ppu.read_data(),
+                    // write only
+                    _ => ppu.open_bus(),
+                }
             }
 
-            0x8000 ..= 0xFFFF => self.read_prg_rom(address),
+            APU_STATUS => self.apu.borrow_mut().read_status(),
+
+            JOYPAD1 => {
+                self.joypad_read.set(true);
+                self.joypad1.borrow_mut().read()
+            }
+
+            // nothing plugged in port 2
+            JOYPAD2 => {
+                self.joypad_read.set(true);
+                0
+            }
+
+            // Open bus: nothing drives the data lines, so the read sees the last
+            // byte fetched, i.e. the high byte of the absolute operand
+            TEST_REG ..= TEST_REG_END => (address >> 8) as u8,
+
+            PRG_RAM ..= PRG_RAM_END => self.prg_ram[(address - PRG_RAM) as usize],
+
+            PRG_ROM ..= PRG_ROM_END => self.read_prg_rom(address),
 
             _ => {
                 println!("Ignoring memory access at {}", address);
                 0
             }
-        }
+        };
+
+        self.apply_read_patches(address, data)
     }
 
     fn mem_write(&mut self, address: u16, data: u8) {
+        self.count_access(address, true);
+
         match address {
             RAM ..= RAM_END => {
-                let mir_down_address = address & 0b0000011111111111;
+                let mir_down_address = address & self.ram_mask;
                 self.cpu_vram[mir_down_address as usize] = data;
             }
 
             PPU_REG ..= PPU_REG_END => {
                 let mir_down_address = address & 0b0010000000000111;
-                todo!("Impl PPU")
+                let ppu = self.ppu.get_mut();
+                match mir_down_address {
+                    0x2000 => ppu.write_to_ctrl(data),
+                    0x2001 => ppu.write_to_mask(data),
+                    0x2003 => ppu.write_to_oam_addr(data),
+                    0x2004 => ppu.write_to_oam_data(data),
+                    0x2005 => ppu.write_to_scroll(data),
+                    0x2006 => ppu.write_to_ppu_addr(data),
+                    0x2007 => ppu.write_to_data(data),
+                    // PPUSTATUS is read only
+                    _ => {}
+                }
             }
 
-            0x8000 ..= 0xFFFF => {
-                panic!("Do not write on ROM space !!")
-            }
+            OAM_DMA => self.oam_dma = Some(data),
+
+            JOYPAD1 => self.joypad1.get_mut().write(data),
+
+            APU_REG ..= APU_REG_END | FRAME_COUNTER => self.apu.get_mut().write_register(address, data),
+
+            APU_STATUS => self.apu.get_mut().write_status(data),
+
+            TEST_REG ..= TEST_REG_END => {}
+
+            PRG_RAM ..= PRG_RAM_END => self.prg_ram[(address - PRG_RAM) as usize] = data,
+
+            PRG_ROM ..= PRG_ROM_END => self.write_prg_rom(address, data),
 
             _ => {
                 println!("Ignoring memory access at {}", address);
             }
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::test::{ines_image, nrom, test_rom};
+    use crate::ppu::StatusRegister;
+    use std::rc::Rc;
+
+    #[test]
+    fn test_peek_does_not_ack_vblank() {
+        let mut bus = Bus::new(nrom());
+        bus.ppu_mut().status.insert(StatusRegister::VBLANK_STARTED);
+
+        assert_eq!(bus.peek(0x2002) & 0x80, 0x80);
+        assert_eq!(bus.peek(0x2002) & 0x80, 0x80);
+        assert!(bus.ppu().status.contains(StatusRegister::VBLANK_STARTED));
+
+        assert_eq!(bus.mem_read(0x2002) & 0x80, 0x80);
+        assert!(!bus.ppu().status.contains(StatusRegister::VBLANK_STARTED));
+        assert_eq!(bus.peek(0x2002) & 0x80, 0);
+    }
+
+    #[test]
+    fn test_disabled_test_registers() {
+        let mut bus = Bus::new(nrom());
+        // open bus: the high byte of the address is the last thing on it
+        assert_eq!(bus.mem_read(0x4018), 0x40);
+        bus.mem_write(0x4018, 0xFF);
+        bus.mem_write(0x401F, 0x12);
+        assert_eq!(bus.mem_read(0x4018), 0x40);
+        assert_eq!(bus.mem_read(0x401F), 0x40);
+        assert_eq!(bus.peek(0x401A), 0x40);
+    }
+
+    // Through PPUADDR/PPUDATA, as a game would
+    fn write_vram(bus: &mut Bus, addr: u16, value: u8) {
+        bus.mem_write(0x2006, (addr >> 8) as u8);
+        bus.mem_write(0x2006, addr as u8);
+        bus.mem_write(0x2007, value);
+    }
+
+    fn read_vram(bus: &mut Bus, addr: u16) -> u8 {
+        bus.mem_write(0x2006, (addr >> 8) as u8);
+        bus.mem_write(0x2006, addr as u8);
+        // the first read only fills the buffer
+        bus.mem_read(0x2007);
+        bus.mem_read(0x2007)
+    }
+
+    fn write_mmc1(bus: &mut Bus, addr: u16, value: u8) {
+        for bit in 0..5 {
+            bus.mem_write(addr, (value >> bit) & 1);
+        }
+    }
+
+    #[test]
+    fn test_mmc1_mirroring_switch() {
+        let mut bus = Bus::new(test_rom(1, &[0; 0x8000], &[]));
+        // PRG mode 3, horizontal
+        write_mmc1(&mut bus, 0x8000, 0b01111);
+        write_vram(&mut bus, 0x2400, 0xAA);
+        assert_eq!(read_vram(&mut bus, 0x2000), 0xAA);
+        assert_eq!(read_vram(&mut bus, 0x2800), 0x00);
+
+        // vertical: 0x2400 is the second physical table now, 0x2800 the first
+        write_mmc1(&mut bus, 0x8000, 0b01110);
+        assert_eq!(bus.ppu().current_mirroring(), Mirroring::Vertical);
+        assert_eq!(read_vram(&mut bus, 0x2400), 0x00);
+        assert_eq!(read_vram(&mut bus, 0x2800), 0xAA);
+    }
+
+    // UxROM with two 16KB banks, each filled with its number
+    fn uxrom_bus(policy: RomWritePolicy) -> Bus {
+        let mut prg_rom = vec![0; 0x4000];
+        prg_rom.extend(vec![1; 0x4000]);
+        let mut bus = Bus::new(test_rom(2, &prg_rom, &[]));
+        bus.set_rom_write_policy(policy);
+        bus
+    }
+
+    #[test]
+    fn test_rom_write_policies() {
+        let mut bus = uxrom_bus(RomWritePolicy::RouteToMapper);
+        bus.mem_write(0x8000, 1);
+        assert_eq!(bus.mem_read(0x8000), 1);
+
+        let mut bus = uxrom_bus(RomWritePolicy::Ignore);
+        bus.mem_write(0x8000, 1);
+        assert_eq!(bus.mem_read(0x8000), 0);
+        assert!(bus.rom_write_log().is_empty());
+
+        let mut bus = uxrom_bus(RomWritePolicy::Log);
+        bus.set_instruction_pc(0xC123);
+        bus.mem_write(0x8000, 1);
+        assert_eq!(bus.mem_read(0x8000), 0);
+        assert_eq!(bus.rom_write_log(), &[RomWrite { pc: 0xC123, addr: 0x8000, data: 1 }]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_rom_write_policy_panic() {
+        let mut bus = uxrom_bus(RomWritePolicy::Panic);
+        bus.mem_write(0x8000, 1);
+    }
+
+    #[test]
+    fn test_write_only_registers_read_the_latch() {
+        let mut bus = Bus::new(nrom());
+        write_vram(&mut bus, 0x2000, 0x5C);
+        assert_eq!(read_vram(&mut bus, 0x2000), 0x5C);
+        // PPUCTRL, PPUMASK, OAMADDR, PPUSCROLL and PPUADDR are write only
+        for addr in &[0x2000, 0x2001, 0x2003, 0x2005, 0x2006] {
+            assert_eq!(bus.mem_read(*addr), 0x5C, "{:04x}", addr);
+        }
+
+        // a write puts its value on the bus too
+        bus.mem_write(0x2001, 0x1E);
+        assert_eq!(bus.mem_read(0x2000), 0x1E);
+    }
+
+    #[test]
+    fn test_ppu_register_mirrors() {
+        let mut bus = Bus::new(nrom());
+        for addr in &[0x2002, 0x200A, 0x3FFA] {
+            bus.ppu_mut().status.insert(StatusRegister::VBLANK_STARTED);
+            assert_eq!(bus.mem_read(*addr) & 0x80, 0x80, "{:04x}", addr);
+            assert!(!bus.ppu().status.contains(StatusRegister::VBLANK_STARTED), "{:04x}", addr);
+        }
+
+        // a PPUADDR/PPUDATA pair through far mirrors
+        bus.mem_write(0x3FFE, 0x21);
+        bus.mem_write(0x2E0E, 0x08);
+        bus.mem_write(0x3457, 0x77);
+        assert_eq!(bus.ppu().vram_addr(), 0x2109);
+        assert_eq!(read_vram(&mut bus, 0x2108), 0x77);
+    }
+
+    #[test]
+    fn test_flat_ram() {
+        let mut bus = Bus::new(nrom());
+        bus.mem_write(0x0800, 0x42);
+        assert_eq!(bus.mem_read(0x0000), 0x42);
+        assert_eq!(bus.mem_read(0x1800), 0x42);
+
+        let mut bus = Bus::with_flat_ram(nrom());
+        bus.mem_write(0x0800, 0x42);
+        assert_eq!(bus.mem_read(0x0000), 0);
+        assert_eq!(bus.mem_read(0x1800), 0);
+        assert_eq!(bus.mem_read(0x0800), 0x42);
+    }
+
+    #[test]
+    fn test_peek_is_not_counted() {
+        let mut bus = Bus::new(nrom());
+        bus.enable_access_stats();
+        bus.peek(0x0010);
+        bus.peek(0x8000);
+        assert_eq!(bus.access_stats(), Some(AccessStats::default()));
+    }
+
+    #[test]
+    fn test_read_patch() {
+        // LDA $10; BRK
+        let mut cpu = crate::selftest::scratch_cpu(&[0xa5, 0x10, 0x00]);
+        cpu.bus.mem_write(0x0010, 0x12);
+        cpu.bus.add_read_patch(0x0010, |_, _| 0xFF);
+        cpu.run().unwrap();
+        assert_eq!(cpu.register_a, 0xFF);
+
+        // the closure sees the real value, other addresses are untouched
+        let mut bus = Bus::new(nrom());
+        bus.mem_write(0x0010, 0x12);
+        bus.mem_write(0x0011, 0x34);
+        bus.add_read_patch(0x0010, |_, value| value + 1);
+        assert_eq!(bus.mem_read(0x0010), 0x13);
+        assert_eq!(bus.mem_read(0x0011), 0x34);
+    }
+
+    #[test]
+    fn test_u16_wraps_at_top_of_memory() {
+        let mut bus = Bus::new(nrom());
+        bus.patch_prg_rom(0xFFFF, 0x34);
+        bus.mem_write(0x0000, 0x12);
+        assert_eq!(bus.mem_read_u16(0xFFFF), 0x1234);
+
+        // the ROM byte ignores the write, the high byte still lands in RAM
+        bus.mem_write_u16(0xFFFF, 0xABCD);
+        assert_eq!(bus.mem_read(0x0000), 0xAB);
+    }
+
+    #[test]
+    fn test_ppudata_increment() {
+        let mut bus = Bus::new(nrom());
+        // +1 across
+        write_vram(&mut bus, 0x2000, 0x11);
+        bus.mem_write(0x2007, 0x22);
+        assert_eq!(read_vram(&mut bus, 0x2001), 0x22);
+
+        // +32 down, with PPUCTRL bit 2
+        bus.mem_write(0x2000, 0b100);
+        write_vram(&mut bus, 0x2100, 0x33);
+        bus.mem_write(0x2007, 0x44);
+        bus.mem_write(0x2000, 0);
+        assert_eq!(read_vram(&mut bus, 0x2101), 0);
+        assert_eq!(read_vram(&mut bus, 0x2120), 0x44);
+
+        // reading PPUSTATUS resets the address latch mid-address
+        bus.mem_write(0x2006, 0x21);
+        bus.mem_read(0x2002);
+        assert_eq!(read_vram(&mut bus, 0x2100), 0x33);
+    }
+
+    #[test]
+    fn test_oam_dma() {
+        // LDA #$02; STA $4014
+        let mut cpu = crate::selftest::scratch_cpu(&[0xa9, 0x02, 0x8d, 0x14, 0x40]);
+        for i in 0..256u16 {
+            cpu.bus.mem_write(0x0200 + i, i as u8 ^ 0x5A);
+        }
+        cpu.step().unwrap();
+        // 4 for the STA, 513 for the copy and one to realign on an odd cycle
+        assert_eq!(cpu.step(), Ok(4 + 513 + 1));
+
+        let oam = cpu.bus.ppu().oam_data;
+        assert!((0..256).all(|i| oam[i] == i as u8 ^ 0x5A));
+        // the CPU already took the stall
+        assert!(!cpu.bus.poll_oam_dma());
+    }
+
+    #[test]
+    fn test_joypad_shift_register() {
+        let mut bus = Bus::new(nrom());
+        bus.set_button_pressed(JoypadButton::START, true);
+        bus.mem_write(0x4016, 1);
+        bus.mem_write(0x4016, 0);
+
+        // A, B, Select, Start, Up, Down, Left, Right
+        let bits: Vec<u8> = (0..8).map(|_| bus.mem_read(0x4016) & 1).collect();
+        assert_eq!(bits, vec![0, 0, 0, 1, 0, 0, 0, 0]);
+        assert_eq!(bus.mem_read(0x4016) & 1, 1);
+    }
+
+    #[test]
+    fn test_rom_writes_reach_the_mapper() {
+        // NROM has nothing to switch: the write is dropped
+        let mut bus = Bus::new(nrom());
+        bus.mem_write(0x8000, 0x42);
+        bus.mem_write(0xFFFF, 0x42);
+        assert_eq!(bus.mem_read(0x8000), 0);
+
+        // UxROM switches the 0x8000 bank, 0xC000 stays on the last one
+        let mut bus = uxrom_bus(RomWritePolicy::RouteToMapper);
+        assert_eq!((bus.mem_read(0x8000), bus.mem_read(0xC000)), (0, 1));
+        bus.mem_write(0xC000, 1);
+        assert_eq!((bus.mem_read(0x8000), bus.mem_read(0xC000)), (1, 1));
+        bus.mem_write(0x8000, 0);
+        assert_eq!(bus.mem_read(0xBFFF), 0);
+    }
+
+    #[test]
+    fn test_prg_ram() {
+        let mut bus = Bus::new(nrom());
+        bus.mem_write(0x6000, 0x11);
+        bus.mem_write(0x6FFF, 0x22);
+        bus.mem_write(0x7FFF, 0x33);
+        assert_eq!((bus.mem_read(0x6000), bus.mem_read(0x6FFF), bus.mem_read(0x7FFF)), (0x11, 0x22, 0x33));
+        // no battery, nothing to save
+        assert!(bus.save_ram().is_none());
+        assert!(bus.load_ram(&[0; 0x2000]).is_err());
+
+        let mut raw = ines_image(0, &[0; 0x4000], &[]);
+        raw[6] |= 0b10;
+        let mut bus = Bus::new(Rom::new(&raw).unwrap());
+        bus.mem_write(0x7FFF, 0x44);
+        let saved = bus.save_ram().unwrap().to_vec();
+        assert_eq!((saved.len(), saved[0x1FFF]), (0x2000, 0x44));
+
+        let mut bus = Bus::new(Rom::new(&raw).unwrap());
+        bus.load_ram(&saved).unwrap();
+        assert_eq!(bus.mem_read(0x7FFF), 0x44);
+        assert!(bus.load_ram(&saved[..0x100]).is_err());
+    }
+
+    #[test]
+    fn test_frame_callback() {
+        let calls = Rc::new(RefCell::new(vec![]));
+        let mut bus = Bus::new(nrom());
+        let seen = calls.clone();
+        bus.set_frame_callback(move |frame: &Frame, samples: &[f32], joypad: &mut Joypad| {
+            seen.borrow_mut().push((frame.width, frame.height, frame.data.len(), samples.len()));
+            joypad.set_button_pressed(JoypadButton::BUTTON_A, true);
+        });
+
+        while bus.ppu().frame_count() < 3 {
+            bus.tick(100);
+        }
+        let calls = calls.borrow();
+        assert_eq!(calls.len(), 3);
+        for &(width, height, bytes, samples) in calls.iter() {
+            assert_eq!((width, height, bytes), (256, 240, 256 * 240 * 3));
+            assert!(samples > 0);
+        }
+
+        // the button pressed from the callback reaches the program
+        bus.mem_write(0x4016, 1);
+        bus.mem_write(0x4016, 0);
+        assert_eq!(bus.mem_read(0x4016), 1);
+    }
+}
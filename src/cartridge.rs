@@ -1,55 +1,274 @@
+use std::path::Path;
+use crate::mapper;
+
 const NES_TAG: [u8; 4] = [0x4E, 0x45, 0x53, 0x1A];
+const HEADER_SIZE: usize = 16;
+const TRAINER_SIZE: usize = 512;
 const PRG_ROM_PAGE_SIZE: usize = 16384;
 const CHR_ROM_PAGE_SIZE: usize = 8192;
+const DEFAULT_RAM_SIZE: usize = 8192;
 
-#[derive(Debug, PartialEq)]
+// Nametable layout: flag 6 bit 0 picks vertical/horizontal, bit 3 overrides
+// it with a four-screen board
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub enum Mirroring {
-    VERTICAL,
-    HORIZONTAL,
-    FOUR_SCREEN,
+    Vertical,
+    Horizontal,
+    FourScreen,
+    // only set by mappers, e.g. MMC1: every nametable is the first or the
+    // second 1KB of VRAM
+    SingleScreenLower,
+    SingleScreenUpper,
 }
 
 pub struct Rom {
     pub prg_rom: Vec<u8>,
     pub chr_rom: Vec<u8>,
-    pub mapper: u8,
+    // 12 bits with NES 2.0 headers
+    pub mapper: u16,
+    // NES 2.0 only, 0 otherwise
+    pub submapper: u8,
     pub screen_mirroring: Mirroring,
+    // PRG RAM at 0x6000-0x7FFF is battery backed and should be saved
+    pub battery: bool,
+    // work/save RAM and CHR RAM sizes in bytes. iNES 1.0 headers don't say,
+    // they're assumed to be 8KB (CHR RAM only without CHR ROM).
+    pub prg_ram_size: usize,
+    pub chr_ram_size: usize,
 }
 
 impl Rom {
-    pub fn new(raw: &Vec<u8>) -> Result<Rom, String> {
-        if &raw[0..4] != NES_TAG {
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Rom, String> {
+        let path = path.as_ref();
+        let raw = std::fs::read(path).map_err(|e| format!("Can't read {}: {}", path.display(), e))?;
+        Rom::new(&raw)
+    }
+
+    pub fn new(raw: &[u8]) -> Result<Rom, String> {
+        if raw.len() < HEADER_SIZE {
+            return Err(format!("File is truncated: {} bytes, the iNES header alone is {}", raw.len(), HEADER_SIZE));
+        }
+
+        if raw[0..4] != NES_TAG {
             return Err("File is not in iNES file format".to_string());
         }
 
-        let mapper = (raw[7] & 0b1111_0000) | (raw[6] >> 4);
+        let nes2 = match (raw[7] >> 2) & 0b11 {
+            0 => false,
+            2 => true,
+            _ => return Err("Unknown iNES header version".to_string()),
+        };
 
-        let ines_ver = (raw[7] >> 2) & 0b11;
-        if ines_ver != 0 {
-            return Err("NES2.0 format is not supported".to_string());
+        let mut mapper = ((raw[7] & 0b1111_0000) | (raw[6] >> 4)) as u16;
+        let mut submapper = 0;
+        if nes2 {
+            mapper |= ((raw[8] & 0b1111) as u16) << 8;
+            submapper = raw[8] >> 4;
+        }
+        if !mapper::is_supported(mapper) {
+            return Err(format!("Mapper {} is not supported", mapper));
         }
 
         let four_screen = raw[6] & 0b1000 != 0;
         let vertical_mirroring = raw[6] & 0b1 != 0;
         let screen_mirroring = match (four_screen, vertical_mirroring) {
-            (true, _) => Mirroring::FOUR_SCREEN,
-            (false, true) => Mirroring::VERTICAL,
-            (false, false) => Mirroring::HORIZONTAL,
+            (true, _) => Mirroring::FourScreen,
+            (false, true) => Mirroring::Vertical,
+            (false, false) => Mirroring::Horizontal,
         };
 
-        let prg_rom_size = raw[4] as usize * PRG_ROM_PAGE_SIZE;
-        let chr_rom_size = raw[5] as usize * CHR_ROM_PAGE_SIZE;
+        let (prg_rom_size, chr_rom_size, prg_ram_size, chr_ram_size) = if nes2 {
+            (
+                nes2_rom_size(raw[4], raw[9] & 0b1111, PRG_ROM_PAGE_SIZE),
+                nes2_rom_size(raw[5], raw[9] >> 4, CHR_ROM_PAGE_SIZE),
+                // volatile + battery backed
+                nes2_ram_size(raw[10] & 0b1111) + nes2_ram_size(raw[10] >> 4),
+                nes2_ram_size(raw[11] & 0b1111) + nes2_ram_size(raw[11] >> 4),
+            )
+        } else {
+            let chr_rom_size = raw[5] as usize * CHR_ROM_PAGE_SIZE;
+            (
+                raw[4] as usize * PRG_ROM_PAGE_SIZE,
+                chr_rom_size,
+                DEFAULT_RAM_SIZE,
+                if chr_rom_size == 0 { DEFAULT_RAM_SIZE } else { 0 },
+            )
+        };
 
+        // every mapper needs at least a bank to put at the reset vector
+        if prg_rom_size == 0 {
+            return Err("The header announces no PRG ROM".to_string());
+        }
+
+        let battery = raw[6] & 0b10 != 0;
         let skip_trainer = raw[6] & 0b100 != 0;
 
-        let prg_rom_start = 16 + if skip_trainer { 512 } else { 0 };
-        let chr_rom_start = prg_rom_start + prg_rom_size;
+        let prg_rom_start = HEADER_SIZE + if skip_trainer { TRAINER_SIZE } else { 0 };
+        // the NES 2.0 exponent form saturates to usize::MAX, which can't fit
+        let chr_rom_start = prg_rom_start.saturating_add(prg_rom_size);
+
+        let expected = chr_rom_start.saturating_add(chr_rom_size);
+        if raw.len() < expected {
+            return Err(format!(
+                "File is truncated: {} bytes, the header announces {} ({}KB PRG, {}KB CHR{})",
+                raw.len(),
+                expected,
+                prg_rom_size / 1024,
+                chr_rom_size / 1024,
+                if skip_trainer { ", trainer" } else { "" },
+            ));
+        }
 
         Ok(Rom {
             prg_rom: raw[prg_rom_start..(prg_rom_start + prg_rom_size)].to_vec(),
             chr_rom: raw[chr_rom_start..(chr_rom_start + chr_rom_size)].to_vec(),
             mapper: mapper,
+            submapper,
             screen_mirroring: screen_mirroring,
+            battery,
+            prg_ram_size,
+            chr_ram_size,
         })
     }
-}
\ No newline at end of file
+}
+
+// NES 2.0 ROM sizes: the MSB nibble extends the bank count, unless it's 0xF
+// and the LSB byte is an exponent-multiplier pair, 2^E * (MM * 2 + 1) bytes
+fn nes2_rom_size(lsb: u8, msb: u8, page_size: usize) -> usize {
+    if msb == 0b1111 {
+        let exponent = (lsb >> 2) as u32;
+        let multiplier = (lsb & 0b11) as usize * 2 + 1;
+        2usize.saturating_pow(exponent).saturating_mul(multiplier)
+    } else {
+        (((msb as usize) << 8) | lsb as usize) * page_size
+    }
+}
+
+// NES 2.0 RAM sizes are shift counts: 0 means none, otherwise 64 << n bytes
+fn nes2_ram_size(shift: u8) -> usize {
+    if shift == 0 {
+        0
+    } else {
+        64 << shift
+    }
+}
+
+// iNES images for the unit tests of other modules
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    // prg_rom and chr_rom must be whole 16KB/8KB banks, an empty chr_rom
+    // means CHR RAM
+    pub fn ines_image(mapper: u8, prg_rom: &[u8], chr_rom: &[u8]) -> Vec<u8> {
+        let mut raw = vec![
+            0x4E, 0x45, 0x53, 0x1A,
+            (prg_rom.len() / PRG_ROM_PAGE_SIZE) as u8,
+            (chr_rom.len() / CHR_ROM_PAGE_SIZE) as u8,
+            mapper << 4,
+            mapper & 0xF0,
+            0, 0, 0, 0, 0, 0, 0, 0,
+        ];
+        raw.extend_from_slice(prg_rom);
+        raw.extend_from_slice(chr_rom);
+        raw
+    }
+
+    pub fn test_rom(mapper: u8, prg_rom: &[u8], chr_rom: &[u8]) -> Rom {
+        Rom::new(&ines_image(mapper, prg_rom, chr_rom)).unwrap()
+    }
+
+    // 16KB NROM with CHR RAM and the reset vector at 0x8000
+    pub fn nrom() -> Rom {
+        let mut prg_rom = vec![0; PRG_ROM_PAGE_SIZE];
+        prg_rom[0x3FFD] = 0x80;
+        test_rom(0, &prg_rom, &[])
+    }
+
+    #[test]
+    fn test_parse_nrom_header() {
+        let mut prg_rom = vec![0; PRG_ROM_PAGE_SIZE];
+        prg_rom[0] = 0xA9;
+        let chr_rom = vec![0x11; CHR_ROM_PAGE_SIZE];
+        let rom = test_rom(0, &prg_rom, &chr_rom);
+        assert_eq!(rom.mapper, 0);
+        assert_eq!(rom.prg_rom, prg_rom);
+        assert_eq!(rom.chr_rom, chr_rom);
+        assert_eq!(rom.chr_ram_size, 0);
+
+        // the trainer sits between the header and PRG ROM
+        let mut raw = ines_image(1, &prg_rom, &[]);
+        raw[6] |= 0b100;
+        raw.splice(HEADER_SIZE..HEADER_SIZE, vec![0xFF; TRAINER_SIZE]);
+        let rom = Rom::new(&raw).unwrap();
+        assert_eq!(rom.mapper, 1);
+        assert_eq!(rom.prg_rom, prg_rom);
+        assert!(rom.chr_rom.is_empty());
+        assert_eq!(rom.chr_ram_size, DEFAULT_RAM_SIZE);
+
+        let mut raw = ines_image(0, &prg_rom, &[]);
+        raw[3] = 0;
+        assert_eq!(Rom::new(&raw).err(), Some("File is not in iNES file format".to_string()));
+        let raw = ines_image(0, &prg_rom, &[]);
+        assert!(Rom::new(&raw[..HEADER_SIZE + 100]).err().unwrap().starts_with("File is truncated"));
+        assert!(Rom::new(&raw[..4]).err().unwrap().starts_with("File is truncated"));
+
+        let raw = ines_image(0, &[], &chr_rom);
+        assert_eq!(Rom::new(&raw).err(), Some("The header announces no PRG ROM".to_string()));
+    }
+
+    #[test]
+    fn test_parse_mirroring() {
+        let prg_rom = vec![0; PRG_ROM_PAGE_SIZE];
+        let mirroring = |flags6: u8| {
+            let mut raw = ines_image(0, &prg_rom, &[]);
+            raw[6] |= flags6;
+            Rom::new(&raw).ok().unwrap().screen_mirroring
+        };
+        assert_eq!(mirroring(0b0000), Mirroring::Horizontal);
+        assert_eq!(mirroring(0b0001), Mirroring::Vertical);
+        assert_eq!(mirroring(0b1000), Mirroring::FourScreen);
+        assert_eq!(mirroring(0b1001), Mirroring::FourScreen);
+    }
+
+    #[test]
+    fn test_from_file() {
+        let rom = Rom::from_file(concat!(env!("CARGO_MANIFEST_DIR"), "/snake.nes")).unwrap();
+        assert_eq!(rom.mapper, 0);
+        assert_eq!(rom.prg_rom.len(), 2 * PRG_ROM_PAGE_SIZE);
+        // CHR RAM only
+        assert!(rom.chr_rom.is_empty());
+        assert_eq!(rom.screen_mirroring, Mirroring::Vertical);
+
+        let missing = Rom::from_file(concat!(env!("CARGO_MANIFEST_DIR"), "/missing.nes"));
+        assert!(missing.err().unwrap().starts_with("Can't read"));
+    }
+
+    #[test]
+    fn test_parse_nes2_header() {
+        let prg_rom = vec![0; PRG_ROM_PAGE_SIZE];
+        let nes2 = |mapper: u16, submapper: u8| {
+            let mut raw = ines_image(mapper as u8, &prg_rom, &[]);
+            raw[7] |= 0b1000;
+            raw[8] = (submapper << 4) | (mapper >> 8) as u8;
+            // 32KB of CHR RAM, 8KB of battery backed PRG RAM
+            raw[10] = 0x70;
+            raw[11] = 0x09;
+            raw
+        };
+
+        // the high nibble in byte 8 extends the mapper number past 255
+        assert_eq!(Rom::new(&nes2(0x101, 0)).err(), Some("Mapper 257 is not supported".to_string()));
+
+        let rom = Rom::new(&nes2(4, 1)).unwrap();
+        assert_eq!((rom.mapper, rom.submapper), (4, 1));
+        assert_eq!((rom.prg_ram_size, rom.chr_ram_size), (0x2000, 0x8000));
+        assert_eq!(rom.prg_rom.len(), PRG_ROM_PAGE_SIZE);
+
+        // exponent form, 2^63 * 7 bytes of PRG ROM
+        let mut raw = nes2(0, 0);
+        raw[4] = 0xFF;
+        raw[9] = 0x0F;
+        assert!(Rom::new(&raw).err().unwrap().starts_with("File is truncated"));
+    }
+}
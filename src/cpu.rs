@@ -1,6 +1,10 @@
-use std::collections::HashMap;
+use std::collections::BTreeSet;
 use crate::opcodes;
-use crate::bus::Bus;
+use crate::bus::{self, Bus};
+use crate::disasm;
+use crate::log::log;
+use std::io;
+use std::io::Write;
 
 bitflags! {
 
@@ -19,6 +23,9 @@ bitflags! {
 
 const STACK: u16 = 0x0100;
 const STACK_R: u8 = 0xfd;
+const PROGRAM_START: u16 = 0x0600;
+// internal RAM is 2KB, anything above is a mirror of the zero page & stack
+const RAM_SIZE: u16 = 0x0800;
 
 pub struct CPU {
     pub register_a: u8,
@@ -28,9 +35,27 @@ pub struct CPU {
     pub stack_pointer: u8,
     pub program_counter: u16,
     pub bus: Bus,
+    code_boundaries: Option<BTreeSet<u16>>,
+    jam_policy: JamPolicy,
+    halt_on_brk: bool,
+    interrupt_hijacking: bool,
+    cycles: usize,
+    // cycles of the current instruction the bus has already been ticked for
+    ticked: usize,
+    // cycle count when the current PPU frame started, and how long the last
+    // one took, see last_frame_cycles
+    frame_start: usize,
+    frames_seen: u64,
+    last_frame_cycles: usize,
+    // None unless enable_interrupt_log was called
+    interrupt_log: Option<Vec<InterruptEvent>>,
+    // base cycle counts replacing the opcode table's, see set_opcode_cycles
+    cycle_overrides: [Option<u8>; 256],
+    // opcodes treated as unimplemented, see set_opcode_enabled
+    disabled_opcodes: [bool; 256],
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Clone, Copy)]
 #[allow(non_camel_case_types)]
 pub enum AddressingMode {
     Immediate,
@@ -42,9 +67,68 @@ pub enum AddressingMode {
     Absolute_Y,
     Indirect_X,
     Indirect_Y,
+    // ASL A and friends, as opposed to implied instructions like INX
+    Accumulator,
     NoneAddressing,
 }
 
+#[derive(Debug, PartialEq, Clone)]
+pub enum LoadError {
+    TooLarge { len: usize, available: usize },
+    // 1-based line number in the listing
+    InvalidListing { line: usize },
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum JamPolicy {
+    // the CPU locks up on the jam opcode, like the hardware
+    Halt,
+    // treat it as a 1-byte NOP, for fuzzing and resilient frontends
+    SkipAndContinue,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum RunOutcome {
+    ValueReached,
+    OutOfCycles,
+    // BRK with halt_on_brk or a jam opcode
+    Halted,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum InterruptKind {
+    Nmi,
+    Irq,
+    Brk,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct InterruptEvent {
+    pub kind: InterruptKind,
+    // total_cycles when the interrupt was taken
+    pub cycle: usize,
+    // the instruction interrupted, or the BRK itself
+    pub pc: u16,
+    pub vector: u16,
+    // where the vector pointed
+    pub handler: u16,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum CpuError {
+    Load(LoadError),
+    // PC landed on a byte that isn't the start of a reachable instruction
+    ExecutingData { pc: u16 },
+    // no implementation yet, whether or not the opcode table lists it
+    Unimplemented { code: u8, pc: u16 },
+}
+
+impl From<LoadError> for CpuError {
+    fn from(err: LoadError) -> Self {
+        CpuError::Load(err)
+    }
+}
+
 pub trait Mem {
     fn mem_read(&self, addr: u16) -> u8; 
 
@@ -52,7 +136,8 @@ pub trait Mem {
     
     fn mem_read_u16(&self, pos: u16) -> u16 {
         let lo = self.mem_read(pos) as u16;
-        let hi = self.mem_read(pos + 1) as u16;
+        // the high byte of 0xFFFF comes from 0x0000
+        let hi = self.mem_read(pos.wrapping_add(1)) as u16;
         (hi << 8) | (lo as u16)
     }
 
@@ -60,7 +145,7 @@ pub trait Mem {
         let hi = (data >> 8) as u8;
         let lo = (data & 0xff) as u8;
         self.mem_write(pos, lo);
-        self.mem_write(pos + 1, hi);
+        self.mem_write(pos.wrapping_add(1), hi);
     }
 }
 
@@ -83,6 +168,36 @@ impl Mem for CPU {
     }
 }
 
+// Room for a program loaded at start: up to the end of internal RAM, of
+// PRG RAM, or of the address space for PRG ROM. Nothing elsewhere is
+// loadable.
+fn load_space(start: u16) -> usize {
+    match start {
+        0x8000 ..= 0xFFFF => 0x10000 - start as usize,
+        0x6000 ..= 0x7FFF => 0x8000 - start as usize,
+        _ => RAM_SIZE.saturating_sub(start) as usize,
+    }
+}
+
+fn crosses_page(a: u16, b: u16) -> bool {
+    a & 0xFF00 != b & 0xFF00
+}
+
+// The offset is relative to the instruction following the branch, i.e. one
+// byte past the operand
+pub fn branch_target(pc_after_opcode: u16, offset: i8) -> u16 {
+    pc_after_opcode.wrapping_add(1).wrapping_add(offset as u16)
+}
+
+// Jumps, calls, returns, BRK and branches leave the PC where execution goes on.
+// That can be right after the opcode byte (a branch by -1, a JMP onto its
+// own operand), so the run loop must not skip their operands.
+fn transfers_control(code: u8) -> bool {
+    matches!(code,
+        0x00 | 0x4c | 0x6c | 0x20 | 0x40 | 0x60 |
+        0x90 | 0xb0 | 0xf0 | 0x30 | 0xd0 | 0x10 | 0x50 | 0x70)
+}
+
 #[warn(unused_assignments)]
 impl CPU {
     pub fn new(bus: Bus) -> Self {
@@ -94,53 +209,82 @@ impl CPU {
             stack_pointer: STACK_R,
             program_counter: 0,
             bus: bus,
+            code_boundaries: None,
+            jam_policy: JamPolicy::Halt,
+            halt_on_brk: false,
+            interrupt_hijacking: false,
+            cycles: 0,
+            ticked: 0,
+            frame_start: 0,
+            frames_seen: 0,
+            last_frame_cycles: 0,
+            interrupt_log: None,
+            cycle_overrides: [None; 256],
+            disabled_opcodes: [false; 256],
         }
     }
 
-    pub fn get_absolute_address(&self, mode: &AddressingMode, addr: u16) -> u16 {
+    // Also reports whether indexing crossed a page, which costs indexed reads
+    // an extra cycle
+    pub fn get_absolute_address(&self, mode: &AddressingMode, addr: u16) -> (u16, bool) {
+        self.resolve_address(mode, addr, |addr| self.mem_read(addr))
+    }
+
+    // Same, reading the operand and pointers through peek: no side effects
+    // and no access counting, for debuggers and tracers
+    pub fn peek_absolute_address(&self, mode: &AddressingMode, addr: u16) -> (u16, bool) {
+        self.resolve_address(mode, addr, |addr| self.peek(addr))
+    }
+
+    fn resolve_address<F>(&self, mode: &AddressingMode, addr: u16, read: F) -> (u16, bool)
+    where
+        F: Fn(u16) -> u8,
+    {
+        let read_u16 = |pos: u16| (read(pos.wrapping_add(1)) as u16) << 8 | read(pos) as u16;
+
         match mode {
-            AddressingMode::ZeroPage => self.mem_read(addr) as u16,
+            AddressingMode::ZeroPage => (read(addr) as u16, false),
 
-            AddressingMode::Absolute => self.mem_read_u16(addr),
+            AddressingMode::Absolute => (read_u16(addr), false),
 
             AddressingMode::ZeroPage_X => {
-                let pos = self.mem_read(addr);
+                let pos = read(addr);
                 let addr = pos.wrapping_add(self.register_x) as u16;
-                addr
+                (addr, false)
             }
             AddressingMode::ZeroPage_Y => {
-                let pos = self.mem_read(addr);
+                let pos = read(addr);
                 let addr = pos.wrapping_add(self.register_y) as u16;
-                addr
+                (addr, false)
             }
 
             AddressingMode::Absolute_X => {
-                let base = self.mem_read_u16(addr);
+                let base = read_u16(addr);
                 let addr = base.wrapping_add(self.register_x as u16);
-                addr
+                (addr, crosses_page(base, addr))
             }
             AddressingMode::Absolute_Y => {
-                let base = self.mem_read_u16(addr);
+                let base = read_u16(addr);
                 let addr = base.wrapping_add(self.register_y as u16);
-                addr
+                (addr, crosses_page(base, addr))
             }
 
             AddressingMode::Indirect_X => {
-                let base = self.mem_read(addr);
+                let base = read(addr);
 
                 let ptr: u8 = (base as u8).wrapping_add(self.register_x);
-                let lo = self.mem_read(ptr as u16);
-                let hi = self.mem_read(ptr.wrapping_add(1) as u16);
-                (hi as u16) << 8 | (lo as u16)
+                let lo = read(ptr as u16);
+                let hi = read(ptr.wrapping_add(1) as u16);
+                ((hi as u16) << 8 | (lo as u16), false)
             }
             AddressingMode::Indirect_Y => {
-                let base = self.mem_read(addr);
+                let base = read(addr);
 
-                let lo = self.mem_read(base as u16);
-                let hi = self.mem_read((base as u8).wrapping_add(1) as u16);
+                let lo = read(base as u16);
+                let hi = read((base as u8).wrapping_add(1) as u16);
                 let deref_base = (hi as u16) << 8 | (lo as u16);
                 let deref = deref_base.wrapping_add(self.register_y as u16);
-                deref
+                (deref, crosses_page(deref_base, deref))
             }
 
             _ => {
@@ -149,13 +293,33 @@ impl CPU {
         }
     }
 
-    fn get_operand_address(&self, mode: &AddressingMode) -> u16 {
+    // Data address the instruction at the PC will access, computed without
+    // executing it. None when it only uses registers or an immediate.
+    pub fn effective_address(&self, opcode: &opcodes::OpCode) -> Option<u16> {
+        match opcode.mode {
+            AddressingMode::Immediate | AddressingMode::Accumulator | AddressingMode::NoneAddressing => None,
+            _ => Some(self.peek_absolute_address(&opcode.mode, self.program_counter.wrapping_add(1)).0),
+        }
+    }
+
+    fn get_operand_address(&self, mode: &AddressingMode) -> (u16, bool) {
         match mode {
-            AddressingMode::Immediate => self.program_counter,
+            AddressingMode::Immediate => (self.program_counter, false),
             _ => self.get_absolute_address(mode, self.program_counter),
         }
     }
 
+    // For instructions that only read their operand: stores and
+    // read-modify-write ops always take the page-cross cycle, it's already in
+    // their base count
+    fn read_operand(&mut self, mode: &AddressingMode) -> u8 {
+        let (address, page_cross) = self.get_operand_address(mode);
+        if page_cross {
+            self.cycles += 1;
+        }
+        self.mem_read(address)
+    }
+
     fn set_a(&mut self, data: u8) {
         self.register_a = data;
         self.update_z_n_flags(self.register_a);
@@ -181,7 +345,8 @@ impl CPU {
 
             let res = sum as u8;
 
-            if res ^ data & res ^ self.register_a ^ 0b10000000 != 0 {
+            // signed overflow: both inputs share a sign the result doesn't have
+            if (self.register_a ^ res) & (data ^ res) & 0b10000000 != 0 {
                 self.status.insert(Flags::OVERFLOW);
             } else {
                 self.status.remove(Flags::OVERFLOW);
@@ -221,6 +386,59 @@ impl CPU {
         self.stack_push(status_flags.bits());
     }
 
+    fn brk(&mut self) {
+        // BRK has a padding byte, the handler returns past it
+        self.stack_push_u16(self.program_counter.wrapping_add(1));
+        self.php();
+        self.status.insert(Flags::INTERRUPT);
+        let pc = self.program_counter.wrapping_sub(1);
+        let vector = self.irq_vector();
+        self.program_counter = self.mem_read_u16(vector);
+        self.log_interrupt(InterruptKind::Brk, pc, vector);
+    }
+
+    // 0xFFFE, unless interrupt hijacking is on and an NMI comes up while BRK
+    // or the IRQ pushes its state: the sequence then fetches the NMI vector,
+    // with the B flag it pushed being the only trace of what started it.
+    fn irq_vector(&mut self) -> u16 {
+        if !self.interrupt_hijacking {
+            return 0xFFFE;
+        }
+        // the pushes end on cycle 4, the vector is fetched after
+        self.bus.tick(4);
+        self.ticked += 4;
+        if self.bus.poll_nmi_status().is_some() {
+            0xFFFA
+        } else {
+            0xFFFE
+        }
+    }
+
+    // Hardware interrupt entry shared by NMI and IRQ
+    fn interrupt(&mut self, vector: u16) {
+        let pc = self.program_counter;
+        self.stack_push_u16(self.program_counter);
+        // unlike PHP/BRK, a hardware interrupt pushes B clear
+        let mut status_flags = self.status;
+        status_flags.remove(Flags::BREAK);
+        status_flags.insert(Flags::BREAKBIS);
+        self.stack_push(status_flags.bits());
+        self.status.insert(Flags::INTERRUPT);
+        let kind = if vector == 0xFFFA { InterruptKind::Nmi } else { InterruptKind::Irq };
+        let vector = if kind == InterruptKind::Irq { self.irq_vector() } else { vector };
+        self.program_counter = self.mem_read_u16(vector);
+        self.log_interrupt(kind, pc, vector);
+        self.cycles += 7;
+    }
+
+    // Called once the PC points at the handler
+    fn log_interrupt(&mut self, kind: InterruptKind, pc: u16, vector: u16) {
+        let event = InterruptEvent { kind, cycle: self.cycles, pc, vector, handler: self.program_counter };
+        if let Some(ref mut log) = self.interrupt_log {
+            log.push(event);
+        }
+    }
+
     fn pla(&mut self) {
         let stack_data = self.stack_pop();
         self.set_a(stack_data);
@@ -233,14 +451,12 @@ impl CPU {
     }
 
     fn adc(&mut self, mode: &AddressingMode) {
-        let address = self.get_operand_address(mode);
-        let value = self.mem_read(address);
+        let value = self.read_operand(mode);
         self.add_to_a(value);
     }
 
     fn cmp(&mut self, mode: &AddressingMode, comparing_value: u8) {
-        let address = self.get_operand_address(mode);
-        let value = self.mem_read(address);
+        let value = self.read_operand(mode);
 
         if value <= comparing_value {
             self.status.insert(Flags::CARRY);
@@ -252,19 +468,17 @@ impl CPU {
     }
 
     fn sbc(&mut self, mode: &AddressingMode) {
-        let address = self.get_operand_address(mode);
-        let data = self.mem_read(address) as i8;
+        let data = self.read_operand(mode) as i8;
         self.add_to_a(data.wrapping_neg().wrapping_sub(1) as u8); // 1 and not ~C because the add_to_a take care of compensing
     }
 
     fn and(&mut self, mode: &AddressingMode) {
-        let address = self.get_operand_address(mode);
-        let value = self.mem_read(address);
+        let value = self.read_operand(mode);
         self.set_a(value & self.register_a);
     }
 
     fn bit(&mut self, mode: &AddressingMode) {
-        let address = self.get_operand_address(mode);
+        let (address, _) = self.get_operand_address(mode);
         let data = self.mem_read(address);
 
         if self.register_a & data == 0 {
@@ -278,14 +492,12 @@ impl CPU {
     }
 
     fn eor(&mut self, mode: &AddressingMode) {
-        let address = self.get_operand_address(mode);
-        let value = self.mem_read(address);
+        let value = self.read_operand(mode);
         self.set_a(value ^ self.register_a);
     }
 
     fn ora(&mut self, mode: &AddressingMode) {
-        let address = self.get_operand_address(mode);
-        let value = self.mem_read(address);
+        let value = self.read_operand(mode);
         self.set_a(value | self.register_a);
     }
    
@@ -348,11 +560,11 @@ impl CPU {
         if carry_cond {
             self.status.insert(Flags::CARRY);
         }
-
+        self.set_a(data);
     }
 
     fn asl(&mut self, mode: &AddressingMode) -> u8 {
-        let address = self.get_operand_address(mode);
+        let (address, _) = self.get_operand_address(mode);
         let mut data = self.mem_read(address);
         if data >> 7 == 1 {
             self.status.insert(Flags::CARRY);
@@ -367,7 +579,7 @@ impl CPU {
     }
 
     fn lsr(&mut self, mode: &AddressingMode) -> u8 {
-        let address = self.get_operand_address(mode);
+        let (address, _) = self.get_operand_address(mode);
         let mut data = self.mem_read(address);
 
         if data & 0b00000001 == 1 {
@@ -383,7 +595,7 @@ impl CPU {
     }
 
     fn rol(&mut self, mode: &AddressingMode) -> u8{
-        let address = self.get_operand_address(mode);
+        let (address, _) = self.get_operand_address(mode);
         let mut data = self.mem_read(address);
 
         let carry_cond = data >> 7 == 1;
@@ -405,7 +617,7 @@ impl CPU {
     }
 
     fn ror(&mut self, mode: &AddressingMode) -> u8{
-        let address = self.get_operand_address(mode);
+        let (address, _) = self.get_operand_address(mode);
         let mut data = self.mem_read(address);
 
         let carry_cond = data & 0b00000001 == 1;
@@ -427,40 +639,37 @@ impl CPU {
     }
 
     fn lda(&mut self, mode: &AddressingMode) {
-        let addr = self.get_operand_address(&mode);
-        let value = self.mem_read(addr);
+        let value = self.read_operand(mode);
 
         self.set_a(value);
     }
 
     fn ldx(&mut self, mode: &AddressingMode) {
-        let address = self.get_operand_address(mode);
-        let value = self.mem_read(address);
+        let value = self.read_operand(mode);
 
         self.register_x = value;
         self.update_z_n_flags(self.register_x);
     }
 
     fn ldy(&mut self, mode: &AddressingMode) {
-        let address = self.get_operand_address(mode);
-        let value = self.mem_read(address);
+        let value = self.read_operand(mode);
 
         self.register_y = value;
         self.update_z_n_flags(self.register_y);
     }
 
     fn sta(&mut self, mode: &AddressingMode) {
-        let addr = self.get_operand_address(mode);
+        let (addr, _) = self.get_operand_address(mode);
         self.mem_write(addr, self.register_a);
     }
 
     fn stx(&mut self, mode: &AddressingMode) {
-        let address = self.get_operand_address(mode);
+        let (address, _) = self.get_operand_address(mode);
         self.mem_write(address, self.register_x);
     }
 
     fn sty(&mut self, mode: &AddressingMode) {
-        let address = self.get_operand_address(mode);
+        let (address, _) = self.get_operand_address(mode);
         self.mem_write(address, self.register_y);
     }
 
@@ -479,7 +688,7 @@ impl CPU {
     }
 
     fn dec(&mut self, mode: &AddressingMode) {
-        let address = self.get_operand_address(mode);
+        let (address, _) = self.get_operand_address(mode);
         let data = self.mem_read(address).wrapping_sub(1);
 
         self.mem_write(address, data);
@@ -497,7 +706,7 @@ impl CPU {
     }
 
     fn inc(&mut self, mode: &AddressingMode) {
-        let address = self.get_operand_address(mode);
+        let (address, _) = self.get_operand_address(mode);
         let data = self.mem_read(address).wrapping_add(1);
 
         self.mem_write(address, data);
@@ -514,26 +723,114 @@ impl CPU {
         self.update_z_n_flags(self.register_y)
     }
 
+    // A taken branch costs a cycle, and another one when the target is on a
+    // different page than the next instruction
     fn b(&mut self, cond: bool) {
         if cond {
-            let curr_at_counter = self.mem_read(self.program_counter) as i8;
-            let address = self.program_counter.wrapping_add(1).wrapping_add(curr_at_counter as u16);
+            let offset = self.mem_read(self.program_counter) as i8;
+            let next = self.program_counter.wrapping_add(1);
+            self.program_counter = branch_target(self.program_counter, offset);
 
-            self.program_counter = address;
+            self.cycles += 1;
+            if crosses_page(next, self.program_counter) {
+                self.cycles += 1;
+            }
+        } else {
+            self.program_counter += 1;
         }
     }
     
-    pub fn load_and_run(&mut self, program: Vec<u8>) {
-        self.load(program);
+    pub fn load_and_run(&mut self, program: Vec<u8>) -> Result<(), CpuError> {
+        self.load(program)?;
         self.reset();
         self.run()
     }
 
-    pub fn load(&mut self, program: Vec<u8>) {
-        for i in 0..(program.len() as u16) {
-            self.mem_write(0x0600 + i, program[i as usize]);
+    pub fn load(&mut self, program: Vec<u8>) -> Result<(), LoadError> {
+        self.load_at(program, PROGRAM_START)
+    }
+
+    // start can be in internal RAM, PRG RAM or PRG ROM, e.g. 0x8000 for cartridge
+    // code. The reset vector is pointed at start.
+    pub fn load_at(&mut self, program: Vec<u8>, start: u16) -> Result<(), LoadError> {
+        let available = load_space(start);
+        if program.len() > available {
+            return Err(LoadError::TooLarge { len: program.len(), available });
+        }
+
+        for (i, byte) in program.iter().enumerate() {
+            self.load_byte(start + i as u16, *byte);
+        }
+        self.set_reset_vector(start);
+        Ok(())
+    }
+
+    // ROM ignores bus writes, loading goes around that
+    fn load_byte(&mut self, addr: u16, data: u8) {
+        if addr >= 0x8000 {
+            self.bus.patch_prg_rom(addr, data);
+        } else {
+            self.mem_write(addr, data);
+        }
+    }
+
+    fn set_reset_vector(&mut self, start: u16) {
+        self.load_byte(0xFFFC, (start & 0xff) as u8);
+        self.load_byte(0xFFFD, (start >> 8) as u8);
+    }
+
+    // Loads a listing made of `address: bytes` lines, all in hex:
+    //
+    //     0600: a9 01     ; LDA #$01
+    //     0602: 8d 00 02  ; STA $0200
+    //
+    // The reset vector points to the first line's address.
+    pub fn load_listing(&mut self, listing: &str) -> Result<(), LoadError> {
+        let mut start = None;
+
+        for (i, line) in listing.lines().enumerate() {
+            let line = line.split(';').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let invalid = LoadError::InvalidListing { line: i + 1 };
+            let mut parts = line.splitn(2, ':');
+            let address = u16::from_str_radix(parts.next().unwrap().trim(), 16).map_err(|_| invalid.clone())?;
+            let bytes = parts
+                .next()
+                .ok_or(invalid.clone())?
+                .split_whitespace()
+                .map(|byte| u8::from_str_radix(byte, 16))
+                .collect::<Result<Vec<u8>, _>>()
+                .map_err(|_| invalid)?;
+
+            let available = load_space(address);
+            if bytes.len() > available {
+                return Err(LoadError::TooLarge { len: bytes.len(), available });
+            }
+
+            for (offset, byte) in bytes.iter().enumerate() {
+                self.load_byte(address + offset as u16, *byte);
+            }
+            start = start.or(Some(address));
         }
-        self.mem_write_u16(0xFFFC, 0x0600);
+
+        if let Some(start) = start {
+            self.set_reset_vector(start);
+        }
+        Ok(())
+    }
+
+    // Side-effect-free reads, see Bus::peek
+    pub fn peek(&self, addr: u16) -> u8 {
+        self.bus.peek(addr)
+    }
+
+    pub fn peek_u16(&self, addr: u16) -> u16 {
+        let lo = self.peek(addr) as u16;
+        let hi = self.peek(addr.wrapping_add(1)) as u16;
+        (hi << 8) | lo
     }
 
     pub fn reset(&mut self) {
@@ -544,306 +841,1610 @@ impl CPU {
         self.status = Flags::from_bits_truncate(0b100100);
 
         self.program_counter = self.mem_read_u16(0xFFFC);
+        // the reset sequence takes 7 cycles, nestest logs start at CYC:7
+        self.cycles = 7;
+        // a DMA requested before the reset never runs
+        self.bus.cancel_oam_dma();
+        self.bus.apu_mut().reset();
+        self.frame_start = 0;
+        self.frames_seen = self.bus.ppu().frame_count();
+    }
+
+    pub fn total_cycles(&self) -> usize {
+        self.cycles
+    }
+
+    // Changes with the registers, the cycle count, RAM and the PPU state,
+    // for checking that two runs ended up in the same place
+    pub fn state_hash(&self) -> u64 {
+        let mut regs = vec![self.register_a, self.register_x, self.register_y, self.status.bits(), self.stack_pointer];
+        regs.extend_from_slice(&self.program_counter.to_le_bytes());
+        regs.extend_from_slice(&(self.cycles as u64).to_le_bytes());
+        self.bus.hash_state(bus::fnv1a(bus::FNV_OFFSET, &regs))
+    }
+
+    // CPU cycles the last completed PPU frame took, 0 until one completes.
+    // Counted in whole instructions: the one that finishes the frame, DMA
+    // stall included, counts toward it and the next frame is that much
+    // shorter.
+    pub fn last_frame_cycles(&self) -> usize {
+        self.last_frame_cycles
+    }
+
+    // Off by default: some code legitimately jumps into the middle of an
+    // instruction (e.g. the BIT skip trick). Code is what's reachable from
+    // start and from the NMI, reset and IRQ/BRK vectors.
+    pub fn enable_data_trap(&mut self, start: u16) {
+        let starts = [start, self.peek_u16(0xFFFA), self.peek_u16(0xFFFC), self.peek_u16(0xFFFE)];
+        let code = disasm::trace_reachable_from(self, &starts);
+        self.code_boundaries = Some(code.keys().cloned().collect());
+    }
+
+    pub fn disable_data_trap(&mut self) {
+        self.code_boundaries = None;
     }
 
-    pub fn run(&mut self) {
-        self.run_with_callback(|_| {});
+    // Records every NMI, IRQ and BRK taken from now on, for debugging missed
+    // or spurious interrupts. Starts an empty log.
+    pub fn enable_interrupt_log(&mut self) {
+        self.interrupt_log = Some(Vec::new());
     }
 
-    pub fn run_with_callback<F>(&mut self, mut callback: F) 
+    pub fn disable_interrupt_log(&mut self) {
+        self.interrupt_log = None;
+    }
+
+    pub fn interrupt_log(&self) -> Option<&[InterruptEvent]> {
+        self.interrupt_log.as_deref()
+    }
+
+    // For timing experiments: charge code this many cycles instead of the
+    // table's. Page-crossing and branch penalties still apply on top.
+    pub fn set_opcode_cycles(&mut self, code: u8, cycles: u8) {
+        self.cycle_overrides[code as usize] = Some(cycles);
+    }
+
+    pub fn clear_opcode_cycles(&mut self, code: u8) {
+        self.cycle_overrides[code as usize] = None;
+    }
+
+    // Disabled opcodes are treated like the ones without a handler, e.g. to
+    // catch code relying on unofficial opcodes such as LAX. All of them are
+    // enabled to begin with.
+    pub fn set_opcode_enabled(&mut self, code: u8, enabled: bool) {
+        self.disabled_opcodes[code as usize] = !enabled;
+    }
+
+    pub fn set_jam_policy(&mut self, policy: JamPolicy) {
+        self.jam_policy = policy;
+    }
+
+    // Accuracy option, off by default: lets an NMI hijack a BRK or IRQ in
+    // progress, see irq_vector
+    pub fn set_interrupt_hijacking(&mut self, enabled: bool) {
+        self.interrupt_hijacking = enabled;
+    }
+
+    // Test harness behaviour: stop the run loop on BRK instead of taking
+    // the interrupt
+    pub fn set_halt_on_brk(&mut self, halt: bool) {
+        self.halt_on_brk = halt;
+    }
+
+    // Same as the bus raising the NMI line, taken before the next fetch
+    pub fn trigger_nmi(&mut self) {
+        self.bus.trigger_nmi();
+    }
+
+    pub fn run(&mut self) -> Result<(), CpuError> {
+        self.run_with_callback(|_| {})
+    }
+
+    pub fn run_with_callback<F>(&mut self, mut callback: F) -> Result<(), CpuError>
     where 
         F: FnMut(&mut CPU), 
     {
-        let ref opcodes: HashMap<u8, &'static opcodes::OpCode> = *opcodes::OPCODES_MAP;
+        while self.execute_next()? {
+            callback(self);
+        }
+        Ok(())
+    }
 
-        loop {
-            let code = self.mem_read(self.program_counter);
-            self.program_counter += 1;
-            let program_counter_state = self.program_counter;
+    // Same as run, minus the per-instruction callback
+    pub fn run_fast(&mut self) -> Result<(), CpuError> {
+        while self.execute_next()? {}
+        Ok(())
+    }
 
-            let opcode = opcodes.get(&code).expect(&format!("OpCode {:x} is not recognized", code));
+    // Executes one instruction, taking a pending interrupt first, and returns
+    // the cycles it consumed. u16 rather than u8: an OAM DMA stall alone is
+    // 513 cycles. 0 means the CPU stopped, even if it took an interrupt on
+    // the way (total_cycles still counts that one).
+    pub fn step(&mut self) -> Result<u16, CpuError> {
+        let start = self.cycles;
+        if !self.execute_next()? {
+            return Ok(0);
+        }
+        Ok((self.cycles - start) as u16)
+    }
 
-            match code {
+    // Runs whole instructions until at least budget cycles went by, for
+    // frontends pacing emulation against real time. Returns the cycles run:
+    // past the budget by less than an instruction, or short of it if the
+    // CPU stopped.
+    pub fn run_cycles(&mut self, budget: usize) -> Result<usize, CpuError> {
+        let start = self.cycles;
+        while self.cycles - start < budget {
+            if !self.execute_next()? {
+                break;
+            }
+        }
+        Ok(self.cycles - start)
+    }
 
-                /* Arith */
+    // Steps until the byte at addr equals value, checked after every
+    // instruction, or until the cycle budget is spent. Checks with peek so
+    // watching a register doesn't ack it.
+    pub fn run_until_mem(&mut self, addr: u16, value: u8, max_cycles: usize) -> Result<RunOutcome, CpuError> {
+        let start = self.cycles;
 
-                0x69 | 0x65 | 0x75 | 0x6D | 0x7D | 0x79 | 0x61 | 0x71 => {
-                    self.adc(&opcode.mode);
-                }
+        while self.cycles - start < max_cycles {
+            if !self.execute_next()? {
+                return Ok(RunOutcome::Halted);
+            }
+            if self.peek(addr) == value {
+                return Ok(RunOutcome::ValueReached);
+            }
+        }
 
-                0xc9 | 0xcd | 0xdd | 0xd9 | 0xc5 | 0xd5 | 0xc1 | 0xd1 => {
-                    self.cmp(&opcode.mode, self.register_a);
-                }
+        Ok(RunOutcome::OutOfCycles)
+    }
 
-                0xe0 | 0xec | 0xe4 => {
-                    self.cmp(&opcode.mode, self.register_x);
-                }
+    // Runs up to max_instructions, writing a nestest-style line for each one
+    // before it executes. Returns how many instructions were traced.
+    pub fn trace_to_writer(&mut self, mut w: impl Write, max_instructions: usize) -> io::Result<usize> {
+        let mut traced = 0;
 
-                0xc0 | 0xcc | 0xc4 => {
-                    self.cmp(&opcode.mode, self.register_y);
-                }
+        while traced < max_instructions {
+            writeln!(w, "{}", log(self))?;
+            traced += 1;
+            if traced % 1000 == 0 {
+                w.flush()?;
+            }
 
-                0xe9 | 0xed | 0xfd | 0xf9 | 0xe5 | 0xf5 | 0xe1 | 0xf1 => {
-                    self.sbc(&opcode.mode);
-                }
+            let running = self
+                .execute_next()
+                .map_err(|e| io::Error::other(format!("{:?}", e)))?;
+            if !running {
+                break;
+            }
+        }
 
-                /* Stack */
+        w.flush()?;
+        Ok(traced)
+    }
 
-                0x48 => self.stack_push(self.register_a),
-                0x08 => self.php(),
-                0x68 => self.pla(),
-                0x28 => self.plp(),
+    // Fetches, decodes and executes one instruction.
+    // Returns false when the CPU stopped: a BRK with halt_on_brk set or
+    // without an IRQ/BRK vector, or a jam opcode.
+    fn execute_next(&mut self) -> Result<bool, CpuError> {
+        let start = self.cycles;
+        self.ticked = 0;
+        if self.bus.poll_nmi_status().is_some() {
+            self.interrupt(0xFFFA);
+        } else if self.bus.irq_asserted() && !self.status.contains(Flags::INTERRUPT) {
+            self.interrupt(0xFFFE);
+        }
 
-                /* Logic */
+        if let Some(ref boundaries) = self.code_boundaries {
+            if !boundaries.contains(&self.program_counter) {
+                return Err(CpuError::ExecutingData { pc: self.program_counter });
+            }
+        }
 
-                0x29 | 0x25 | 0x35 | 0x2d | 0x3d | 0x39 | 0x21 | 0x31 => {
-                    self.and(&opcode.mode);
-                }
+        self.bus.set_instruction_pc(self.program_counter);
+        let code = self.mem_read(self.program_counter);
+        self.program_counter += 1;
+        let program_counter_state = self.program_counter;
 
-                0x24 | 0x2c => self.bit(&opcode.mode),
-                
-                0x49 | 0x4d | 0x5d | 0x59 | 0x45 | 0x55 | 0x41 | 0x51 => {
-                    self.eor(&opcode.mode);
-                }
+        let opcode = match opcodes::OPCODES_ARRAY[code as usize] {
+            Some(opcode) if opcodes::is_handled(code) && !self.disabled_opcodes[code as usize] => opcode,
+            _ => return Err(CpuError::Unimplemented { code, pc: program_counter_state - 1 }),
+        };
 
-                0x09 | 0x0d | 0x1d | 0x19 | 0x05 | 0x15 | 0x01 | 0x11 => {
-                    self.ora(&opcode.mode);
-                }
+        match code {
 
-                /* Shift */
+            /* Arith */
 
-                0x0a => self.asl_acc(),
-                0x4a => self.lsr_acc(),
-                0x2a => self.rol_acc(),
-                0x6a => self.ror_acc(),
+            0x69 | 0x65 | 0x75 | 0x6D | 0x7D | 0x79 | 0x61 | 0x71 => {
+                self.adc(&opcode.mode);
+            }
 
-                0x06 | 0x16 | 0x0e | 0x1e => {
-                    self.asl(&opcode.mode);
-                }
+            0xc9 | 0xcd | 0xdd | 0xd9 | 0xc5 | 0xd5 | 0xc1 | 0xd1 => {
+                self.cmp(&opcode.mode, self.register_a);
+            }
 
-                0x4e | 0x5e | 0x46 | 0x56 => {
-                    self.lsr(&opcode.mode);
-                }
+            0xe0 | 0xec | 0xe4 => {
+                self.cmp(&opcode.mode, self.register_x);
+            }
 
-                0x2e | 0x3e | 0x26 | 0x36 => {
-                    self.rol(&opcode.mode);
-                }
+            0xc0 | 0xcc | 0xc4 => {
+                self.cmp(&opcode.mode, self.register_y);
+            }
 
-                0x6e | 0x7e | 0x66 | 0x76 => {
-                    self.ror(&opcode.mode);
-                }
+            0xe9 | 0xed | 0xfd | 0xf9 | 0xe5 | 0xf5 | 0xe1 | 0xf1 => {
+                self.sbc(&opcode.mode);
+            }
 
-                /* Load */
+            /* Stack */
 
-                0xa9 | 0xa5 | 0xb5 | 0xad | 0xbd | 0xb9 | 0xa1 | 0xb1 => {
-                    self.lda(&opcode.mode);
-                }
+            0x48 => self.stack_push(self.register_a),
+            0x08 => self.php(),
+            0x68 => self.pla(),
+            0x28 => self.plp(),
 
-                0xa2 | 0xae | 0xbe | 0xa6 | 0xb6 => {
-                    self.ldx(&opcode.mode);
-                }
+            /* Logic */
 
-                0xa0 | 0xac | 0xbc | 0xa4 | 0xb4 => {
-                    self.ldy(&opcode.mode);
-                }
+            0x29 | 0x25 | 0x35 | 0x2d | 0x3d | 0x39 | 0x21 | 0x31 => {
+                self.and(&opcode.mode);
+            }
 
-                0x85 | 0x95 | 0x8d | 0x9d | 0x99 | 0x81 | 0x91 => {
-                    self.sta(&opcode.mode);
-                }
+            0x24 | 0x2c => self.bit(&opcode.mode),
+            
+            0x49 | 0x4d | 0x5d | 0x59 | 0x45 | 0x55 | 0x41 | 0x51 => {
+                self.eor(&opcode.mode);
+            }
 
-                0x8e | 0x86 | 0x96 => {
-                    self.stx(&opcode.mode);
-                }
+            0x09 | 0x0d | 0x1d | 0x19 | 0x05 | 0x15 | 0x01 | 0x11 => {
+                self.ora(&opcode.mode);
+            }
 
-                0x8c | 0x84 | 0x94 => {
-                    self.sty(&opcode.mode);
-                }
+            /* Shift */
 
-                /* Branch */
-
-                0x90 => self.b(!self.status.contains(Flags::CARRY)),
-                0xb0 => self.b(self.status.contains(Flags::CARRY)),
-                0xf0 => self.b(self.status.contains(Flags::ZERO)),
-                0x30 => self.b(self.status.contains(Flags::NEGATIVE)),
-                0xd0 => self.b(!self.status.contains(Flags::ZERO)),
-                0x10 => self.b(!self.status.contains(Flags::NEGATIVE)),
-                0x50 => self.b(!self.status.contains(Flags::OVERFLOW)),
-                0x70 => self.b(self.status.contains(Flags::OVERFLOW)),
-
-                /* Flags */
-
-                0x18 => self.status.remove(Flags::CARRY),
-                0xd8 => self.status.remove(Flags::DECIMAL),
-                0x58 => self.status.remove(Flags::INTERRUPT),
-                0xb8 => self.status.remove(Flags::OVERFLOW),
-                0x38 => self.status.insert(Flags::CARRY),
-                0xf8 => self.status.insert(Flags::DECIMAL),
-                0x78 => self.status.insert(Flags::INTERRUPT),
-                
-                /* Trans */
-
-                0xaa => {
-                    self.register_x = self.register_a;
-                    self.update_z_n_flags(self.register_x);
-                }
+            0x0a => self.asl_acc(),
+            0x4a => self.lsr_acc(),
+            0x2a => self.rol_acc(),
+            0x6a => self.ror_acc(),
 
-                0xa8 => {
-                    self.register_y = self.register_a;
-                    self.update_z_n_flags(self.register_y);
-                }
+            0x06 | 0x16 | 0x0e | 0x1e => {
+                self.asl(&opcode.mode);
+            }
 
-                0xba => {
-                    self.register_x = self.stack_pointer;
-                    self.update_z_n_flags(self.register_x);
-                }
+            0x4e | 0x5e | 0x46 | 0x56 => {
+                self.lsr(&opcode.mode);
+            }
 
-                0x8a => {
-                    self.set_a(self.register_x);
-                }
+            0x2e | 0x3e | 0x26 | 0x36 => {
+                self.rol(&opcode.mode);
+            }
 
-                0x9a => {
-                    self.stack_pointer = self.register_x;
-                    self.update_z_n_flags(self.stack_pointer);
-                }
+            0x6e | 0x7e | 0x66 | 0x76 => {
+                self.ror(&opcode.mode);
+            }
 
-                0x98 => {
-                    self.set_a(self.register_y);
-                }
+            /* Load */
 
-                /* Inc */
+            0xa9 | 0xa5 | 0xb5 | 0xad | 0xbd | 0xb9 | 0xa1 | 0xb1 => {
+                self.lda(&opcode.mode);
+            }
 
-                0xce | 0xde | 0xc6 | 0xd6 => {
-                    self.dec(&opcode.mode);
-                }
+            0xa2 | 0xae | 0xbe | 0xa6 | 0xb6 => {
+                self.ldx(&opcode.mode);
+            }
 
-                0xee | 0xfe | 0xe6 | 0xf6 => {
-                    self.inc(&opcode.mode);
-                }
+            0xa0 | 0xac | 0xbc | 0xa4 | 0xb4 => {
+                self.ldy(&opcode.mode);
+            }
 
-                0xca => self.dex(),
-                0x88 => self.dey(),
-                0xe8 => self.inx(),
-                0xc8 => self.iny(),
+            0x85 | 0x95 | 0x8d | 0x9d | 0x99 | 0x81 | 0x91 => {
+                self.sta(&opcode.mode);
+            }
 
-                /* Ctrl */
+            0x8e | 0x86 | 0x96 => {
+                self.stx(&opcode.mode);
+            }
 
-                0x4c => {
-                    let mem_address = self.mem_read_u16(self.program_counter);
-                    self.program_counter = mem_address;
-                }
+            0x8c | 0x84 | 0x94 => {
+                self.sty(&opcode.mode);
+            }
 
-                0x6c => {
-                    let mem_address = self.mem_read_u16(self.program_counter);
-                    let reference = if mem_address & 0x00FF == 0x00FF {
-                        let lo = self.mem_read(mem_address);
-                        let hi = self.mem_read(mem_address & 0xFF00);
-                        (hi as u16) << 8 | (lo as u16)
-                    } else {
-                        self.mem_read_u16(mem_address)
-                    };
-
-                    self.program_counter = reference;
-                }
+            /* Branch */
+
+            0x90 => self.b(!self.status.contains(Flags::CARRY)),
+            0xb0 => self.b(self.status.contains(Flags::CARRY)),
+            0xf0 => self.b(self.status.contains(Flags::ZERO)),
+            0x30 => self.b(self.status.contains(Flags::NEGATIVE)),
+            0xd0 => self.b(!self.status.contains(Flags::ZERO)),
+            0x10 => self.b(!self.status.contains(Flags::NEGATIVE)),
+            0x50 => self.b(!self.status.contains(Flags::OVERFLOW)),
+            0x70 => self.b(self.status.contains(Flags::OVERFLOW)),
+
+            /* Flags */
+
+            0x18 => self.status.remove(Flags::CARRY),
+            0xd8 => self.status.remove(Flags::DECIMAL),
+            0x58 => self.status.remove(Flags::INTERRUPT),
+            0xb8 => self.status.remove(Flags::OVERFLOW),
+            0x38 => self.status.insert(Flags::CARRY),
+            0xf8 => self.status.insert(Flags::DECIMAL),
+            0x78 => self.status.insert(Flags::INTERRUPT),
+            
+            /* Trans */
+
+            0xaa => {
+                self.register_x = self.register_a;
+                self.update_z_n_flags(self.register_x);
+            }
 
-                0x20 => {
-                    self.stack_push_u16(self.program_counter + 2 - 1);
-                    let target = self.mem_read_u16(self.program_counter);
-                    self.program_counter = target
-                }
+            0xa8 => {
+                self.register_y = self.register_a;
+                self.update_z_n_flags(self.register_y);
+            }
+
+            0xba => {
+                self.register_x = self.stack_pointer;
+                self.update_z_n_flags(self.register_x);
+            }
+
+            0x8a => {
+                self.set_a(self.register_x);
+            }
 
-                0x40 => {
-                    self.status.bits = self.stack_pop();
-                    self.status.remove(Flags::BREAK);
-                    self.status.insert(Flags::BREAKBIS);
+            // the only transfer that leaves the flags alone
+            0x9a => self.stack_pointer = self.register_x,
 
-                    self.program_counter = self.stack_pop_u16();
+            0x98 => {
+                self.set_a(self.register_y);
+            }
+
+            /* Inc */
+
+            0xce | 0xde | 0xc6 | 0xd6 => {
+                self.dec(&opcode.mode);
+            }
+
+            0xee | 0xfe | 0xe6 | 0xf6 => {
+                self.inc(&opcode.mode);
+            }
+
+            0xca => self.dex(),
+            0x88 => self.dey(),
+            0xe8 => self.inx(),
+            0xc8 => self.iny(),
+
+            /* Ctrl */
+
+            0x4c => {
+                let mem_address = self.mem_read_u16(self.program_counter);
+                self.program_counter = mem_address;
+            }
+
+            0x6c => {
+                let mem_address = self.mem_read_u16(self.program_counter);
+                let reference = if mem_address & 0x00FF == 0x00FF {
+                    let lo = self.mem_read(mem_address);
+                    let hi = self.mem_read(mem_address & 0xFF00);
+                    (hi as u16) << 8 | (lo as u16)
+                } else {
+                    self.mem_read_u16(mem_address)
+                };
+
+                self.program_counter = reference;
+            }
+
+            0x20 => {
+                self.stack_push_u16(self.program_counter + 2 - 1);
+                let target = self.mem_read_u16(self.program_counter);
+                self.program_counter = target
+            }
+
+            0x40 => {
+                self.status.bits = self.stack_pop();
+                self.status.remove(Flags::BREAK);
+                self.status.insert(Flags::BREAKBIS);
+
+                self.program_counter = self.stack_pop_u16();
+            }
+
+            0x60 => {
+                self.program_counter = self.stack_pop_u16() + 1;
+            }
+
+            /* NOP */
+
+            0xea => {}
+
+            /* Unofficial */
+
+            0x1a | 0x3a | 0x5a | 0x7a | 0xda | 0xfa => {}
+
+            // the operand is still read, page-cross cycle included
+            0x80 | 0x82 | 0x89 | 0xc2 | 0xe2 | 0x04 | 0x44 | 0x64 | 0x14 | 0x34 | 0x54 | 0x74 | 0xd4 | 0xf4
+            | 0x0c | 0x1c | 0x3c | 0x5c | 0x7c | 0xdc | 0xfc => {
+                self.read_operand(&opcode.mode);
+            }
+
+            // LDA and LDX in one
+            0xa7 | 0xb7 | 0xaf | 0xbf | 0xa3 | 0xb3 => {
+                let data = self.read_operand(&opcode.mode);
+                self.set_a(data);
+                self.register_x = data;
+            }
+
+            // 0x0b | 0x2b => {
+            //     let address = self.get_operand_address(&opcode.mode);
+            //     let data = self.mem_read(address);
+            //     self.set_a(data & self.register_a);
+            //     if self.status.contains(Flags::NEGATIVE) {
+            //         self.status.insert(Flags::CARRY);
+            //     } else {
+            //         self.status.remove(Flags::CARRY);
+            //     }
+            // }
+
+            // 0x87 | 0x97 | 0x83 | 0x8f => {
+            //     let address = self.get_operand_address(&opcode.mode);
+            //     let data = self.mem_read(address);
+            //     self.mem_write(address, self.register_x & data);
+            //     self.update_z_n_flags(data & self.register_x);
+            // }
+
+            // 0x6b => {
+            //     let address = self.get_operand_address(&opcode.mode);
+            //     let data = self.mem_read(address);
+            //     self.set_a(data & self.register_a);
+            //     self.ror_acc();
+
+            //     let bit_5 = (self.register_a >> 5) & 1;
+            //     let bit_6 = (self.register_a >> 6) & 1;
+
+            //     if bit_6 == 1 {
+            //         self.status.insert(Flags::CARRY);
+            //     } else {
+            //         self.status.remove(Flags::CARRY);
+            //     }
+
+            //     if bit_5 ^ bit_6 == 1 {
+            //         self.status.insert(Flags::OVERFLOW);
+            //     } else {
+            //         self.status.remove(Flags::OVERFLOW);
+            //     }
+
+            //     self.update_z_n_flags(self.register_a);
+            // }
+
+            // 0x4b => {
+            //     let address = self.get_operand_address(&opcode.mode);
+            //     let data = self.mem_read(address);
+            //     self.set_a(self.register_a & data);
+            //     self.lsr_acc();
+            // }
+
+            // 0xab => {
+            //     let address = self.get_operand_address(&opcode.mode);
+            //     let data = self.mem_read(address);
+            //     self.set_a(self.register_a & data);
+            //     self.register_x = self.register_a;
+            //     self.update_z_n_flags(self.register_x);
+            // }
+
+            // 0x9f | 0x93 => {
+            //     let address = self.get_operand_address(&opcode.mode);
+            //     let result = self.register_a & self.register_x;
+            //     let data = result & 7;
+            //     self.mem_write(address, data);
+            // }
+
+
+
+            /* Jam */
+
+            0x02 | 0x12 | 0x22 | 0x32 | 0x42 | 0x52 | 0x62 | 0x72 | 0x92 | 0xb2 | 0xd2 | 0xf2 => {
+                if self.jam_policy == JamPolicy::Halt {
+                    // stay stuck on the opcode
+                    self.program_counter -= 1;
+                    return Ok(false);
                 }
+            }
 
-                0x60 => {
-                    self.program_counter = self.stack_pop_u16() + 1;
+            0x00 => {
+                // no handler installed, e.g. a bare program from load_and_run
+                if self.halt_on_brk || self.peek_u16(0xFFFE) == 0x0000 {
+                    return Ok(false);
                 }
+                self.brk();
+            }
+            _ => return Err(CpuError::Unimplemented { code, pc: program_counter_state - 1 }),
+        }
 
-                /* NOP */
+        if !transfers_control(code) {
+            self.program_counter += (opcode.len - 1) as u16;
+        }
+        self.cycles += self.cycle_overrides[code as usize].unwrap_or(opcode.cycles) as usize;
 
-                0xea => {}
+        // OAM DMA runs once the instruction is done and halts the CPU for 513
+        // cycles, plus one to realign when it starts on an odd cycle
+        if self.bus.poll_oam_dma() {
+            self.cycles += 513 + self.cycles % 2;
+        }
 
-                /* Unofficial */
+        self.bus.tick((self.cycles - start - self.ticked) as u16);
 
-                // 0x0b | 0x2b => {
-                //     let address = self.get_operand_address(&opcode.mode);
-                //     let data = self.mem_read(address);
-                //     self.set_a(data & self.register_a);
-                //     if self.status.contains(Flags::NEGATIVE) {
-                //         self.status.insert(Flags::CARRY);
-                //     } else {
-                //         self.status.remove(Flags::CARRY);
-                //     }
-                // }
+        // the DMC's sample fetches halt the CPU for 4 cycles
+        if self.bus.poll_dmc_dma() {
+            self.cycles += 4;
+            self.bus.tick(4);
+        }
 
-                // 0x87 | 0x97 | 0x83 | 0x8f => {
-                //     let address = self.get_operand_address(&opcode.mode);
-                //     let data = self.mem_read(address);
-                //     self.mem_write(address, self.register_x & data);
-                //     self.update_z_n_flags(data & self.register_x);
-                // }
+        let frames = self.bus.ppu().frame_count();
+        if frames != self.frames_seen {
+            self.frames_seen = frames;
+            self.last_frame_cycles = self.cycles - self.frame_start;
+            self.frame_start = self.cycles;
+        }
+        Ok(true)
+    }
+}
 
-                // 0x6b => {
-                //     let address = self.get_operand_address(&opcode.mode);
-                //     let data = self.mem_read(address);
-                //     self.set_a(data & self.register_a);
-                //     self.ror_acc();
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::selftest::scratch_cpu;
+    use crate::joypad::JoypadButton;
+    use crate::cartridge::{Mirroring, Rom};
+    use crate::mapper::Mapper;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    // LDX #$0a; LDA #$00; loop: CLC; ADC #$03; STA $10,X; DEX; BNE loop; BRK
+    const SUM_LOOP: [u8; 13] = [0xa2, 0x0a, 0xa9, 0x00, 0x18, 0x69, 0x03, 0x95, 0x10, 0xca, 0xd0, 0xf8, 0x00];
+
+    #[test]
+    fn test_run_fast_matches_run() {
+        let mut slow = scratch_cpu(&SUM_LOOP);
+        slow.run().unwrap();
+        let mut fast = scratch_cpu(&SUM_LOOP);
+        fast.run_fast().unwrap();
+
+        assert_eq!(fast.register_a, slow.register_a);
+        assert_eq!(fast.register_x, slow.register_x);
+        assert_eq!(fast.status, slow.status);
+        assert_eq!(fast.stack_pointer, slow.stack_pointer);
+        assert_eq!(fast.program_counter, slow.program_counter);
+        assert_eq!(fast.total_cycles(), slow.total_cycles());
+        for addr in 0..0x0800 {
+            assert_eq!(fast.peek(addr), slow.peek(addr), "RAM differs at {:04x}", addr);
+        }
+        assert_eq!(fast.register_a, 30);
+    }
 
-                //     let bit_5 = (self.register_a >> 5) & 1;
-                //     let bit_6 = (self.register_a >> 6) & 1;
+    #[test]
+    fn test_step() {
+        // LDA #$05; TAX; INX; BRK
+        let mut cpu = scratch_cpu(&[0xa9, 0x05, 0xaa, 0xe8, 0x00]);
+
+        assert_eq!(cpu.step(), Ok(2));
+        assert_eq!((cpu.register_a, cpu.register_x, cpu.program_counter), (0x05, 0x00, 0x8002));
+        assert_eq!(cpu.step(), Ok(2));
+        assert_eq!((cpu.register_a, cpu.register_x, cpu.program_counter), (0x05, 0x05, 0x8003));
+        assert_eq!(cpu.step(), Ok(2));
+        assert_eq!((cpu.register_a, cpu.register_x, cpu.program_counter), (0x05, 0x06, 0x8004));
+        // halted on the BRK
+        assert_eq!(cpu.step(), Ok(0));
+    }
+
+    #[test]
+    fn test_halting_brk_in_nmi_handler_stops_run() {
+        // loop: JMP loop, the NMI handler at 0x8010 is BRK; INX
+        let mut program = vec![0x4c, 0x00, 0x80];
+        program.resize(0x10, 0xea);
+        program.extend(&[0x00, 0xe8]);
+        let mut cpu = scratch_cpu(&program);
+        cpu.bus.patch_prg_rom(0xFFFA, 0x10);
+        cpu.bus.patch_prg_rom(0xFFFB, 0x80);
+
+        cpu.trigger_nmi();
+        cpu.run().unwrap();
+        assert_eq!(cpu.register_x, 0);
+        assert_eq!(cpu.program_counter, 0x8011);
+
+        let mut cpu = scratch_cpu(&program);
+        cpu.bus.patch_prg_rom(0xFFFA, 0x10);
+        cpu.bus.patch_prg_rom(0xFFFB, 0x80);
+        cpu.trigger_nmi();
+        assert_eq!(cpu.step(), Ok(0));
+        assert_eq!(cpu.register_x, 0);
+    }
 
-                //     if bit_6 == 1 {
-                //         self.status.insert(Flags::CARRY);
-                //     } else {
-                //         self.status.remove(Flags::CARRY);
-                //     }
+    #[test]
+    fn test_inspection_has_no_side_effects() {
+        // LDA ($10),Y; BRK
+        let mut cpu = scratch_cpu(&[0xb1, 0x10, 0x00]);
+        cpu.mem_write(0x10, 0x00);
+        cpu.mem_write(0x11, 0x03);
+        cpu.register_y = 0x02;
+        cpu.bus.enable_access_stats();
+
+        let opcode = opcodes::OPCODES_ARRAY[0xb1].unwrap();
+        assert_eq!(cpu.effective_address(opcode), Some(0x0302));
+        log(&cpu);
+        disasm::decode(&cpu, 0x8000);
+        assert_eq!(cpu.bus.access_stats(), Some(crate::bus::AccessStats::default()));
+    }
 
-                //     if bit_5 ^ bit_6 == 1 {
-                //         self.status.insert(Flags::OVERFLOW);
-                //     } else {
-                //         self.status.remove(Flags::OVERFLOW);
-                //     }
+    #[test]
+    fn test_run_until_mem() {
+        // LDX #$00; loop: INX; STX $40; CPX #$05; BNE loop; LDA #$ff; BRK
+        let program = [0xa2, 0x00, 0xe8, 0x86, 0x40, 0xe0, 0x05, 0xd0, 0xf9, 0xa9, 0xff, 0x00];
 
-                //     self.update_z_n_flags(self.register_a);
-                // }
+        let mut cpu = scratch_cpu(&program);
+        assert_eq!(cpu.run_until_mem(0x40, 0x03, 10_000), Ok(RunOutcome::ValueReached));
+        // stopped right after the STX that wrote 3
+        assert_eq!(cpu.program_counter, 0x8005);
+        assert_eq!(cpu.register_x, 3);
 
-                // 0x4b => {
-                //     let address = self.get_operand_address(&opcode.mode);
-                //     let data = self.mem_read(address);
-                //     self.set_a(self.register_a & data);
-                //     self.lsr_acc();
-                // }
+        let mut cpu = scratch_cpu(&program);
+        assert_eq!(cpu.run_until_mem(0x40, 0x03, 10), Ok(RunOutcome::OutOfCycles));
 
-                // 0xab => {
-                //     let address = self.get_operand_address(&opcode.mode);
-                //     let data = self.mem_read(address);
-                //     self.set_a(self.register_a & data);
-                //     self.register_x = self.register_a;
-                //     self.update_z_n_flags(self.register_x);
-                // }
+        let mut cpu = scratch_cpu(&program);
+        assert_eq!(cpu.run_until_mem(0x40, 0x06, 10_000), Ok(RunOutcome::Halted));
+        assert_eq!(cpu.register_a, 0xff);
+    }
 
-                // 0x9f | 0x93 => {
-                //     let address = self.get_operand_address(&opcode.mode);
-                //     let result = self.register_a & self.register_x;
-                //     let data = result & 7;
-                //     self.mem_write(address, data);
-                // }
+    #[test]
+    fn test_run_until_mem_does_not_ack_vblank() {
+        let mut cpu = scratch_cpu(&[0xea, 0xea, 0x00]);
+        cpu.bus.ppu_mut().status.insert(crate::ppu::StatusRegister::VBLANK_STARTED);
+        assert_eq!(cpu.run_until_mem(0x2002, 0x00, 10_000), Ok(RunOutcome::Halted));
+        assert!(cpu.bus.ppu().status.contains(crate::ppu::StatusRegister::VBLANK_STARTED));
+    }
 
+    #[test]
+    fn test_data_trap() {
+        // LDA #$ea; JMP ($0010), pointing at the LDA operand
+        let program = [0xa9, 0xea, 0x6c, 0x10, 0x00];
+        let mut cpu = scratch_cpu(&program);
+        cpu.mem_write_u16(0x10, 0x8001);
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        // a NOP when the trap is off
+        assert_eq!(cpu.step(), Ok(2));
+
+        let mut cpu = scratch_cpu(&program);
+        cpu.mem_write_u16(0x10, 0x8001);
+        cpu.enable_data_trap(0x8000);
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert_eq!(cpu.step(), Err(CpuError::ExecutingData { pc: 0x8001 }));
+    }
+
+    #[test]
+    fn test_data_trap_follows_brk_and_vectors() {
+        // BRK; padding; INX; JAM, with an RTI-only IRQ handler at 0x8010
+        let mut program = vec![0x00, 0xea, 0xe8, 0x02];
+        program.resize(0x10, 0xff);
+        program.push(0x40);
+        let mut cpu = scratch_cpu(&program);
+        cpu.set_halt_on_brk(false);
+        cpu.bus.patch_prg_rom(0xFFFE, 0x10);
+        cpu.bus.patch_prg_rom(0xFFFF, 0x80);
+
+        cpu.enable_data_trap(0x8000);
+        assert_eq!(cpu.run(), Ok(()));
+        assert_eq!(cpu.register_x, 1);
+    }
+
+    #[test]
+    fn test_data_trap_doesnt_touch_the_bus() {
+        let mut cpu = scratch_cpu(&SUM_LOOP);
+        cpu.bus.enable_access_stats();
+        cpu.enable_data_trap(0x8000);
+        // the trace only peeks, nothing shows up as a CPU access
+        assert_eq!(cpu.bus.access_stats(), Some(Default::default()));
+    }
 
+    // nestest.log trimmed to the official-opcode section, without the PPU column
+    #[test]
+    fn test_nestest_trace() {
+        let rom = Rom::from_file(concat!(env!("CARGO_MANIFEST_DIR"), "/nestest.nes")).unwrap();
+        let expected = std::fs::read_to_string(concat!(env!("CARGO_MANIFEST_DIR"), "/nestest.log")).unwrap();
+        let mut cpu = CPU::new(Bus::new(rom));
+        cpu.reset();
+        // automated mode starts at 0xC000
+        cpu.program_counter = 0xC000;
+
+        for (n, want) in expected.lines().enumerate() {
+            let got = format!("{} CYC:{}", log(&cpu), cpu.total_cycles());
+            assert!(got == want, "line {} diverges\nexpected: {}\n     got: {}", n + 1, want, got);
+            assert_eq!(cpu.execute_next(), Ok(true));
+        }
 
-                0x00 => return,
-                _ => todo!(),
+        // no failures reported
+        assert_eq!(cpu.peek(0x02), 0);
+        assert_eq!(cpu.peek(0x03), 0);
+        assert_eq!(cpu.total_cycles(), 14579);
+
+        // the unofficial opcode tests start here: the NOPs and LAX pass, SAX
+        // is next
+        let stopped = loop {
+            match cpu.execute_next() {
+                Ok(true) => {}
+                other => break other,
             }
+        };
+        assert_eq!(stopped, Err(CpuError::Unimplemented { code: 0x83, pc: 0xE757 }));
+        assert_eq!(cpu.peek(0x02), 0);
+        assert_eq!(cpu.peek(0x03), 0);
+    }
+
+    // The return address the last interrupt pushed
+    fn stacked_return(cpu: &CPU) -> u16 {
+        let sp = cpu.stack_pointer as u16;
+        cpu.peek_u16(STACK + sp + 2)
+    }
+
+    #[test]
+    fn test_nmi_hijacks_brk() {
+        // BRK; padding, IRQ handler at 8010 and NMI handler at 8020, both NOP
+        let mut program = vec![0x00, 0xea];
+        program.resize(0x30, 0xea);
+        let setup = |hijacking: bool| {
+            let mut cpu = scratch_cpu(&program);
+            cpu.set_halt_on_brk(false);
+            cpu.set_interrupt_hijacking(hijacking);
+            cpu.bus.patch_prg_rom(0xFFFA, 0x20);
+            cpu.bus.patch_prg_rom(0xFFFB, 0x80);
+            cpu.bus.patch_prg_rom(0xFFFE, 0x10);
+            cpu.bus.patch_prg_rom(0xFFFF, 0x80);
+            // vblank NMI a dot after BRK starts
+            cpu.bus.mem_write(0x2000, 0x80);
+            cpu.bus.ppu_mut().advance_to_scanline(241, 0).unwrap();
+            cpu
+        };
+
+        let mut cpu = setup(true);
+        cpu.step().unwrap();
+        assert_eq!(cpu.program_counter, 0x8020);
+        assert_eq!(stacked_return(&cpu), 0x8002);
+        let pushed = cpu.peek(STACK + cpu.stack_pointer as u16 + 1);
+        assert!(Flags::from_bits_truncate(pushed).contains(Flags::BREAK));
+        // the NMI was used up
+        cpu.step().unwrap();
+        assert_eq!(cpu.program_counter, 0x8021);
+
+        // off by default: BRK goes to its handler, the NMI right after
+        let mut cpu = setup(false);
+        cpu.step().unwrap();
+        assert_eq!(cpu.program_counter, 0x8010);
+        cpu.step().unwrap();
+        assert_eq!(cpu.program_counter, 0x8021);
+    }
+
+    #[test]
+    fn test_nmi_before_irq() {
+        // CLI; NOP..., NMI handler at 8020 and IRQ handler at 8030: NOP; RTI
+        let mut program = vec![0x58];
+        program.resize(0x20, 0xea);
+        program.extend(&[0xea, 0x40]);
+        program.resize(0x30, 0xea);
+        program.extend(&[0xea, 0x40]);
+        let mut cpu = scratch_cpu(&program);
+        cpu.bus.patch_prg_rom(0xFFFA, 0x20);
+        cpu.bus.patch_prg_rom(0xFFFB, 0x80);
+        cpu.bus.patch_prg_rom(0xFFFE, 0x30);
+        cpu.bus.patch_prg_rom(0xFFFF, 0x80);
+        cpu.step().unwrap();
+
+        cpu.trigger_nmi();
+        cpu.bus.set_irq(true);
+        cpu.step().unwrap();
+        assert_eq!(cpu.program_counter, 0x8021);
+        assert_eq!(stacked_return(&cpu), 0x8001);
+
+        // the IRQ waits for RTI to clear INTERRUPT again
+        cpu.step().unwrap();
+        assert_eq!(cpu.program_counter, 0x8001);
+        cpu.step().unwrap();
+        assert_eq!(cpu.program_counter, 0x8031);
+        assert_eq!(stacked_return(&cpu), 0x8001);
+    }
+
+    #[test]
+    fn test_interrupt_log() {
+        // NOP; BRK; NOP, both handlers are RTI
+        let mut program = vec![0xea, 0x00, 0x00, 0xea];
+        program.resize(0x20, 0xea);
+        program.push(0x40);
+        program.resize(0x30, 0xea);
+        program.push(0x40);
+        let mut cpu = scratch_cpu(&program);
+        cpu.set_halt_on_brk(false);
+        cpu.bus.patch_prg_rom(0xFFFA, 0x20);
+        cpu.bus.patch_prg_rom(0xFFFB, 0x80);
+        cpu.bus.patch_prg_rom(0xFFFE, 0x30);
+        cpu.bus.patch_prg_rom(0xFFFF, 0x80);
+        assert_eq!(cpu.interrupt_log(), None);
+        cpu.enable_interrupt_log();
+
+        cpu.trigger_nmi();
+        for _ in 0..4 {
+            cpu.step().unwrap();
+        }
+        assert_eq!(cpu.program_counter, 0x8003);
+        assert_eq!(
+            cpu.interrupt_log().unwrap(),
+            &[
+                InterruptEvent { kind: InterruptKind::Nmi, cycle: 7, pc: 0x8000, vector: 0xFFFA, handler: 0x8020 },
+                // 7 for the NMI, RTI and NOP
+                InterruptEvent { kind: InterruptKind::Brk, cycle: 22, pc: 0x8001, vector: 0xFFFE, handler: 0x8030 },
+            ]
+        );
+    }
 
-            if program_counter_state == self.program_counter {
-                self.program_counter += (opcode.len - 1) as u16;
+    #[test]
+    fn test_set_opcode_cycles() {
+        let mut cpu = scratch_cpu(&[0xea, 0xea, 0xea, 0xea, 0xea, 0xea]);
+        cpu.set_opcode_cycles(0xea, 5);
+        for _ in 0..3 {
+            assert_eq!(cpu.step(), Ok(5));
+        }
+        assert_eq!(cpu.total_cycles(), 7 + 3 * 5);
+
+        cpu.clear_opcode_cycles(0xea);
+        assert_eq!(cpu.step(), Ok(2));
+    }
+
+    // 32KB of PRG, raises IRQ once countdown CPU cycles have passed, any
+    // write to the ROM acknowledges it
+    struct CycleIrqMapper {
+        prg: Vec<u8>,
+        countdown: usize,
+        pending: bool,
+        cleared: usize,
+    }
+
+    impl Mapper for CycleIrqMapper {
+        fn cpu_read(&self, addr: u16) -> u8 {
+            self.prg[addr as usize - 0x8000]
+        }
+
+        fn cpu_write(&mut self, _addr: u16, _data: u8) {
+            self.clear_irq();
+        }
+
+        fn ppu_read(&self, _addr: u16) -> u8 {
+            0
+        }
+
+        fn ppu_write(&mut self, _addr: u16, _data: u8) {}
+
+        fn patch_prg(&mut self, addr: u16, data: u8) {
+            self.prg[addr as usize - 0x8000] = data;
+        }
+
+        fn cpu_clock(&mut self, cycles: u16) {
+            if self.countdown > 0 {
+                self.countdown = self.countdown.saturating_sub(cycles as usize);
+                self.pending = self.countdown == 0;
             }
+        }
 
-            callback(self);
+        fn irq_pending(&self) -> bool {
+            self.pending
         }
+
+        fn clear_irq(&mut self) {
+            self.pending = false;
+            self.cleared += 1;
+        }
+
+        fn save_state(&self) -> Vec<u8> {
+            vec![]
+        }
+
+        fn load_state(&mut self, _state: &[u8]) -> Result<(), String> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_mapper_irq() {
+        // CLI; loop: INX; JMP loop. The handler acknowledges: STA $8000; INY; RTI
+        let mut prg = vec![0xea; 0x8000];
+        prg[..5].copy_from_slice(&[0x58, 0xe8, 0x4c, 0x01, 0x80]);
+        prg[0x10..0x15].copy_from_slice(&[0x8d, 0x00, 0x80, 0xc8, 0x40]);
+        prg[0x7FFC..].copy_from_slice(&[0x00, 0x80, 0x10, 0x80]);
+        let mapper = Rc::new(RefCell::new(CycleIrqMapper { prg, countdown: 30, pending: false, cleared: 0 }));
+        let mut cpu = CPU::new(Bus::with_mapper(mapper.clone(), Mirroring::Horizontal));
+        cpu.reset();
+
+        while !cpu.bus.mapper_irq_pending() {
+            cpu.step().unwrap();
+        }
+        assert_eq!(cpu.register_y, 0);
+        // taken before the next instruction, the handler runs to RTI
+        for _ in 0..3 {
+            cpu.step().unwrap();
+        }
+        assert_eq!(cpu.register_y, 1);
+        assert_eq!(mapper.borrow().cleared, 1);
+        assert!(!cpu.bus.mapper_irq_pending());
+
+        // back in the loop, no second IRQ
+        for _ in 0..20 {
+            cpu.step().unwrap();
+        }
+        assert_eq!(cpu.register_y, 1);
+        assert!(cpu.program_counter < 0x8005);
+    }
+
+    #[test]
+    fn test_load_and_run_stops_on_brk_without_vector() {
+        let mut cpu = CPU::new(Bus::new(crate::cartridge::test::nrom()));
+        // LDA #$05; BRK; INX
+        assert_eq!(cpu.load_and_run(vec![0xa9, 0x05, 0x00, 0xe8]), Ok(()));
+        assert_eq!(cpu.register_a, 5);
+        assert_eq!(cpu.register_x, 0);
+        // nothing was pushed
+        assert_eq!(cpu.stack_pointer, STACK_R);
+    }
+
+    #[test]
+    fn test_run_cycles() {
+        // loop: INX; DEY; JMP loop
+        let program = [0xe8, 0x88, 0x4c, 0x00, 0x80];
+        let mut cpu = scratch_cpu(&program);
+        let ran = cpu.run_cycles(100).unwrap();
+        // 7 cycles is the longest instruction
+        assert!((100..107).contains(&ran), "{}", ran);
+        assert_eq!(cpu.total_cycles(), 7 + ran);
+
+        let mut stepped = scratch_cpu(&program);
+        let mut cycles = 0;
+        while cycles < 100 {
+            cycles += stepped.step().unwrap() as usize;
+        }
+        assert_eq!(cycles, ran);
+        assert_eq!(
+            (cpu.program_counter, cpu.register_x, cpu.register_y, cpu.status),
+            (stepped.program_counter, stepped.register_x, stepped.register_y, stepped.status)
+        );
+
+        // a stopped CPU comes back early
+        let mut cpu = scratch_cpu(&[0xe8, 0x00]);
+        assert_eq!(cpu.run_cycles(100), Ok(2));
+    }
+
+    #[test]
+    fn test_last_frame_cycles() {
+        // loop: INX; JMP loop
+        let mut cpu = scratch_cpu(&[0xe8, 0x4c, 0x00, 0x80]);
+        let run_to_frame = |cpu: &mut CPU, frame: u64| {
+            while cpu.bus.ppu().frame_count() < frame {
+                cpu.step().unwrap();
+            }
+        };
+        run_to_frame(&mut cpu, 1);
+        assert_eq!(cpu.last_frame_cycles(), cpu.total_cycles());
+        run_to_frame(&mut cpu, 2);
+        // 89342 dots with rendering off, 29780.67 cycles
+        let plain = cpu.last_frame_cycles();
+        assert!((29778..29784).contains(&plain), "{}", plain);
+
+        // an OAM DMA just before the end of the frame stalls past it
+        while cpu.bus.ppu().scanline() != 261 || cpu.bus.ppu().dot() < 300 {
+            cpu.step().unwrap();
+        }
+        cpu.mem_write(0x4014, 0x02);
+        run_to_frame(&mut cpu, 3);
+        let with_dma = cpu.last_frame_cycles();
+        assert!(with_dma > plain + 500, "{}", with_dma);
+        run_to_frame(&mut cpu, 4);
+        assert!((2 * 29778..2 * 29784).contains(&(with_dma + cpu.last_frame_cycles())));
+    }
+
+    #[test]
+    fn test_reset_cancels_oam_dma() {
+        // LDA #$02; STA $4014; INX
+        let mut cpu = scratch_cpu(&[0xa9, 0x02, 0x8d, 0x14, 0x40, 0xe8]);
+        cpu.bus.mem_write(0x0200, 0x77);
+        cpu.step().unwrap();
+        // the write starts the DMA, reset comes before it transfers
+        cpu.mem_write(0x4014, 0x02);
+        cpu.reset();
+        assert!(!cpu.bus.poll_oam_dma());
+        assert_eq!(cpu.bus.ppu().oam_data[0], 0);
+
+        // no stall left over, and a new DMA runs normally
+        assert_eq!(cpu.step(), Ok(2));
+        assert_eq!(cpu.step(), Ok(4 + 513 + 1));
+        assert_eq!(cpu.bus.ppu().oam_data[0], 0x77);
+        assert_eq!(cpu.step(), Ok(2));
+
+        // same for a DMC sample fetch
+        cpu.mem_write(0x4013, 0x00);
+        cpu.mem_write(0x4015, 0x10);
+        assert!(cpu.bus.apu().dmc_fetch_address().is_some());
+        cpu.reset();
+        assert!(!cpu.bus.poll_dmc_dma());
+        assert_eq!(cpu.step(), Ok(2));
+    }
+
+    #[test]
+    fn test_dmc_read_corruption() {
+        // DMC on, looping one byte at the fastest rate, then polls the pad
+        // 256 times, each time rolling the 8 bits into $10 and storing them
+        // at $0200,Y
+        let program = [
+            0xa9, 0x4f, 0x8d, 0x10, 0x40, 0xa9, 0x00, 0x8d, 0x13, 0x40, 0xa9, 0x10, 0x8d, 0x15, 0x40,
+            0xa0, 0x00,
+            0xa9, 0x01, 0x8d, 0x16, 0x40, 0xa9, 0x00, 0x8d, 0x16, 0x40,
+            0xa2, 0x08, 0xad, 0x16, 0x40, 0x4a, 0x26, 0x10, 0xca, 0xd0, 0xf7,
+            0xa5, 0x10, 0x99, 0x00, 0x02, 0xc8, 0xd0, 0xe3, 0x00,
+        ];
+        let polls = |corruption: bool| {
+            let mut cpu = scratch_cpu(&program);
+            cpu.bus.set_dmc_read_corruption(corruption);
+            cpu.bus.set_buttons(JoypadButton::BUTTON_A | JoypadButton::SELECT);
+            cpu.run().unwrap();
+            (0..256).map(|i| cpu.peek(0x0200 + i)).collect::<Vec<u8>>()
+        };
+
+        // A, B, Select, ... with A first in the top bit
+        assert!(polls(false).iter().all(|&buttons| buttons == 0b1010_0000));
+        let corrupted = polls(true);
+        assert!(corrupted.iter().any(|&buttons| buttons != 0b1010_0000));
+        assert!(corrupted.iter().filter(|&&buttons| buttons == 0b1010_0000).count() > 128);
+    }
+
+    #[test]
+    fn test_seeded_ram_is_deterministic() {
+        // adds up 0x0300-0x03FF into 0x00, like a program seeding its RNG
+        // from power-on RAM
+        let program = [0xa2, 0x00, 0xa9, 0x00, 0x18, 0x7d, 0x00, 0x03, 0xe8, 0xd0, 0xf9, 0x85, 0x00, 0x00];
+        let run = |seed: u64| {
+            let mut cpu = scratch_cpu(&program);
+            cpu.bus.fill_ram(seed);
+            cpu.run().unwrap();
+            (cpu.peek(0x00), cpu.state_hash())
+        };
+
+        let (sum, hash) = run(42);
+        assert_eq!(run(42), (sum, hash));
+        assert_ne!(run(43).1, hash);
+        // unseeded RAM is all zeroes
+        let mut cpu = scratch_cpu(&program);
+        cpu.run().unwrap();
+        assert_eq!(cpu.peek(0x00), 0);
+        assert_ne!(cpu.state_hash(), hash);
+    }
+
+    #[test]
+    fn test_load_too_large() {
+        let mut cpu = scratch_cpu(&[]);
+        assert_eq!(
+            cpu.load(vec![0xe8; 0x201]),
+            Err(LoadError::TooLarge { len: 0x201, available: 0x200 })
+        );
+        // nothing was written
+        assert_eq!(cpu.mem_read(0x0600), 0);
+        assert_eq!(
+            cpu.load_and_run(vec![0xe8; 0x201]),
+            Err(CpuError::Load(LoadError::TooLarge { len: 0x201, available: 0x200 }))
+        );
+
+        assert_eq!(cpu.load(vec![0xe8; 0x200]), Ok(()));
+        assert_eq!(cpu.mem_read(0x07FF), 0xe8);
+    }
+
+    #[test]
+    fn test_load_into_prg_ram() {
+        let mut cpu = scratch_cpu(&[]);
+        // LDX #$03; INX; BRK
+        assert_eq!(cpu.load_at(vec![0xa2, 0x03, 0xe8, 0x00], 0x6000), Ok(()));
+        cpu.reset();
+        assert_eq!(cpu.program_counter, 0x6000);
+        cpu.run().unwrap();
+        assert_eq!(cpu.register_x, 4);
+
+        // PRG RAM ends where ROM starts
+        assert_eq!(
+            cpu.load_at(vec![0xea; 0x11], 0x7FF0),
+            Err(LoadError::TooLarge { len: 0x11, available: 0x10 })
+        );
+        assert_eq!(cpu.load_at(vec![0xea; 0x10], 0x7FF0), Ok(()));
+        assert_eq!(cpu.mem_read(0x7FFF), 0xea);
+    }
+
+    #[test]
+    fn test_zero_page_access_stats() {
+        // LDX #$05; loop: INC $10; LDA $11; STA $12; DEX; BNE loop; BRK
+        let program = [0xa2, 0x05, 0xe6, 0x10, 0xa5, 0x11, 0x85, 0x12, 0xca, 0xd0, 0xf7, 0x00];
+        let mut cpu = scratch_cpu(&program);
+        cpu.bus.enable_access_stats();
+        cpu.run().unwrap();
+
+        let stats = cpu.bus.access_stats().unwrap();
+        // INC reads and writes, LDA reads, STA writes
+        assert_eq!(stats.ram, crate::bus::RegionStats { reads: 10, writes: 10 });
+        assert_eq!(stats.prg_rom.writes, 0);
+        assert_eq!(stats.ppu_regs, crate::bus::RegionStats::default());
+        assert_eq!(cpu.mem_read(0x10), 5);
+    }
+
+    #[test]
+    fn test_branch_target() {
+        // relative to 8002, past the operand at 8001
+        assert_eq!(branch_target(0x8001, 0x10), 0x8012);
+        assert_eq!(branch_target(0x8001, -0x02), 0x8000);
+        assert_eq!(branch_target(0x80F0, 0x20), 0x8111);
+        assert_eq!(branch_target(0x8100, -0x10), 0x80F1);
+        assert_eq!(branch_target(0xFFF0, 0x7F), 0x0070);
+
+        // and the CPU takes the same one: BNE $8012 from 8000
+        let mut program = vec![0xd0, 0x10];
+        program.resize(0x12, 0xea);
+        program.push(0xe8);
+        let mut cpu = scratch_cpu(&program);
+        cpu.step().unwrap();
+        assert_eq!(cpu.program_counter, 0x8012);
+    }
+
+    #[test]
+    fn test_branch_offset_range() {
+        // 8000: BNE +127 to 8081, past the operand byte
+        // 8081: BNE -128 back to 8003
+        // 8003: BNE +0 to the very next instruction at 8005
+        let mut program = vec![0xd0, 0x7f, 0xea, 0xd0, 0x00];
+        program.resize(0x81, 0xea);
+        program.extend_from_slice(&[0xd0, 0x80]);
+        let mut cpu = scratch_cpu(&program);
+
+        cpu.step().unwrap();
+        assert_eq!(cpu.program_counter, 0x8081);
+        cpu.step().unwrap();
+        assert_eq!(cpu.program_counter, 0x8003);
+        cpu.step().unwrap();
+        assert_eq!(cpu.program_counter, 0x8005);
+    }
+
+    #[test]
+    fn test_load_listing() {
+        let listing = "
+            ; stores 1 at 0x0200, then X = 5
+            0600: a9 01     ; LDA #$01
+            0602: 8d 00 02  ; STA $0200
+
+            0605: a2 05 00  ; LDX #$05; BRK
+        ";
+        let mut from_listing = scratch_cpu(&[]);
+        from_listing.load_listing(listing).unwrap();
+        from_listing.reset();
+        from_listing.run().unwrap();
+
+        let mut from_bytes = scratch_cpu(&[]);
+        from_bytes.load(vec![0xa9, 0x01, 0x8d, 0x00, 0x02, 0xa2, 0x05, 0x00]).unwrap();
+        from_bytes.reset();
+        from_bytes.run().unwrap();
+
+        assert_eq!(from_listing.mem_read(0x0200), 1);
+        assert_eq!(from_listing.register_x, 5);
+        assert_eq!(
+            (from_listing.register_a, from_listing.register_x, from_listing.program_counter, from_listing.total_cycles()),
+            (from_bytes.register_a, from_bytes.register_x, from_bytes.program_counter, from_bytes.total_cycles())
+        );
+
+        let mut cpu = scratch_cpu(&[]);
+        assert_eq!(cpu.load_listing("0600: a9 01\n0602 8d"), Err(LoadError::InvalidListing { line: 2 }));
+        assert_eq!(cpu.load_listing("0600: a9 1g"), Err(LoadError::InvalidListing { line: 1 }));
+    }
+
+    #[test]
+    fn test_store_cycles_ignore_page_cross() {
+        // LDA $10F0,X; STA $10F0,X, X=0x20 crosses into 0x11xx
+        for &(x, load, store) in &[(0x00, 4, 5), (0x0f, 4, 5), (0x20, 5, 5)] {
+            let mut cpu = scratch_cpu(&[0xbd, 0xf0, 0x10, 0x9d, 0xf0, 0x10]);
+            cpu.register_x = x;
+            assert_eq!(cpu.step(), Ok(load), "LDA with X={:02x}", x);
+            assert_eq!(cpu.step(), Ok(store), "STA with X={:02x}", x);
+        }
+    }
+
+    #[test]
+    fn test_compare() {
+        // LDA #$10; CMP #$10; BRK
+        let mut cpu = scratch_cpu(&[0xa9, 0x10, 0xc9, 0x10, 0x00]);
+        cpu.run().unwrap();
+        assert!(cpu.status.contains(Flags::CARRY | Flags::ZERO));
+        assert!(!cpu.status.contains(Flags::NEGATIVE));
+
+        // LDX #$05; CPX #$06; BRK
+        let mut cpu = scratch_cpu(&[0xa2, 0x05, 0xe0, 0x06, 0x00]);
+        cpu.run().unwrap();
+        assert!(!cpu.status.intersects(Flags::CARRY | Flags::ZERO));
+        assert!(cpu.status.contains(Flags::NEGATIVE));
+
+        // LDY #$07; CPY #$06; BRK
+        let mut cpu = scratch_cpu(&[0xa0, 0x07, 0xc0, 0x06, 0x00]);
+        cpu.run().unwrap();
+        assert!(cpu.status.contains(Flags::CARRY));
+        assert!(!cpu.status.contains(Flags::ZERO));
+    }
+
+    #[test]
+    fn test_sbc_immediate() {
+        // SEC; LDA #$05; SBC #$03; BRK
+        let mut cpu = scratch_cpu(&[0x38, 0xa9, 0x05, 0xe9, 0x03, 0x00]);
+        cpu.run().unwrap();
+        assert_eq!(cpu.register_a, 2);
+        assert!(cpu.status.contains(Flags::CARRY));
+    }
+
+    #[test]
+    fn test_ora_eor_immediate() {
+        // LDA #$0F; ORA #$F0; BRK
+        let mut cpu = scratch_cpu(&[0xa9, 0x0f, 0x09, 0xf0, 0x00]);
+        cpu.run().unwrap();
+        assert_eq!(cpu.register_a, 0xff);
+        assert!(cpu.status.contains(Flags::NEGATIVE));
+
+        // LDA #$FF; EOR #$0F; BRK
+        let mut cpu = scratch_cpu(&[0xa9, 0xff, 0x49, 0x0f, 0x00]);
+        cpu.run().unwrap();
+        assert_eq!(cpu.register_a, 0xf0);
+    }
+
+    #[test]
+    fn test_rotate_memory_through_carry() {
+        // LDA #$81; STA $10; SEC; ROL $10; BRK
+        let mut cpu = scratch_cpu(&[0xa9, 0x81, 0x85, 0x10, 0x38, 0x26, 0x10, 0x00]);
+        cpu.run().unwrap();
+        assert_eq!(cpu.mem_read(0x10), 0x03);
+        assert!(cpu.status.contains(Flags::CARRY));
+
+        // LDA #$03; STA $10; CLC; ROR $10; LSR $10; BRK
+        let mut cpu = scratch_cpu(&[
+            0xa9, 0x03, 0x85, 0x10, 0x18, 0x66, 0x10, 0x46, 0x10, 0x00,
+        ]);
+        cpu.run().unwrap();
+        assert_eq!(cpu.mem_read(0x10), 0x00);
+        assert!(cpu.status.contains(Flags::CARRY | Flags::ZERO));
+    }
+
+    #[test]
+    fn test_stx_then_ldy() {
+        // LDX #$42; STX $20; LDY $20; BRK
+        let mut cpu = scratch_cpu(&[0xa2, 0x42, 0x86, 0x20, 0xa4, 0x20, 0x00]);
+        cpu.run().unwrap();
+        assert_eq!(cpu.mem_read(0x20), 0x42);
+        assert_eq!(cpu.register_y, 0x42);
+    }
+
+    #[test]
+    fn test_jsr_rts_round_trip() {
+        // 8000: JSR $8010; 8010: INX; RTS
+        let mut program = vec![0x20, 0x10, 0x80];
+        program.resize(0x10, 0xea);
+        program.extend_from_slice(&[0xe8, 0x60]);
+        let mut cpu = scratch_cpu(&program);
+
+        cpu.step().unwrap();
+        assert_eq!(cpu.program_counter, 0x8010);
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert_eq!(cpu.program_counter, 0x8003);
+        assert_eq!(cpu.register_x, 1);
+        assert_eq!(cpu.stack_pointer, 0xfd);
+    }
+
+    #[test]
+    fn test_pha_pla_round_trip() {
+        // LDA #$5A; PHA; LDA #$00; PLA; BRK
+        let mut cpu = scratch_cpu(&[0xa9, 0x5a, 0x48, 0xa9, 0x00, 0x68, 0x00]);
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert_eq!(cpu.register_a, 0);
+        cpu.step().unwrap();
+        assert_eq!(cpu.register_a, 0x5a);
+        assert_eq!(cpu.stack_pointer, 0xfd);
+        assert!(!cpu.status.intersects(Flags::ZERO | Flags::NEGATIVE));
+    }
+
+    #[test]
+    fn test_trace_to_writer() {
+        // LDA #$01; INX; BRK
+        let mut cpu = scratch_cpu(&[0xa9, 0x01, 0xe8, 0x00]);
+        let mut out = Vec::new();
+        assert_eq!(cpu.trace_to_writer(&mut out, 100).unwrap(), 3);
+
+        let text = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(
+            lines,
+            vec![
+                "8000  A9 01     LDA #$01                        A:00 X:00 Y:00 P:24 SP:FD",
+                "8002  E8        INX                             A:01 X:00 Y:00 P:24 SP:FD",
+                "8003  00        BRK                             A:01 X:01 Y:00 P:24 SP:FD",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_ror_accumulator() {
+        // SEC; LDA #$01; ROR A; BRK
+        let mut cpu = scratch_cpu(&[0x38, 0xa9, 0x01, 0x6a, 0x00]);
+        cpu.run().unwrap();
+        // carry rotates into bit 7 and bit 0 of $01 rotates out into carry
+        assert_eq!(cpu.register_a, 0x80);
+        assert!(cpu.status.contains(Flags::NEGATIVE | Flags::CARRY));
+        assert!(!cpu.status.contains(Flags::ZERO));
+    }
+
+    #[test]
+    fn test_adc_overflow() {
+        // CLC; LDA #a; ADC #b; BRK
+        let adc = |a: u8, b: u8| {
+            let mut cpu = scratch_cpu(&[0x18, 0xa9, a, 0x69, b, 0x00]);
+            cpu.run().unwrap();
+            (cpu.register_a, cpu.status.contains(Flags::OVERFLOW))
+        };
+
+        // 127 + 1 leaves the signed range
+        assert_eq!(adc(0x7f, 0x01), (0x80, true));
+        // so does -128 + -1, wrapping to +127
+        assert_eq!(adc(0x80, 0xff), (0x7f, true));
+        // mixed signs never overflow
+        assert_eq!(adc(0x01, 0xff), (0x00, false));
+        assert_eq!(adc(0x7f, 0x80), (0xff, false));
+        // nor do small values of the same sign
+        assert_eq!(adc(0x10, 0x20), (0x30, false));
+        assert_eq!(adc(0xff, 0xff), (0xfe, false));
+
+        // SEC; LDA #$80; SBC #$01 is -128 - 1
+        let mut cpu = scratch_cpu(&[0x38, 0xa9, 0x80, 0xe9, 0x01, 0x00]);
+        cpu.run().unwrap();
+        assert_eq!(cpu.register_a, 0x7f);
+        assert!(cpu.status.contains(Flags::OVERFLOW));
+    }
+
+    #[test]
+    fn test_jam_policy() {
+        // JAM; INX; BRK
+        let program = [0x02, 0xe8, 0x00];
+
+        let mut cpu = scratch_cpu(&program);
+        assert_eq!(cpu.run(), Ok(()));
+        assert_eq!(cpu.program_counter, 0x8000);
+        assert_eq!(cpu.register_x, 0);
+
+        let mut cpu = scratch_cpu(&program);
+        cpu.set_jam_policy(JamPolicy::SkipAndContinue);
+        assert_eq!(cpu.run(), Ok(()));
+        assert_eq!(cpu.register_x, 1);
+    }
+
+    #[test]
+    fn test_effective_address() {
+        // LDX #$05; STA $0300,X; BRK
+        let mut cpu = scratch_cpu(&[0xa2, 0x05, 0x9d, 0x00, 0x03, 0x00]);
+        cpu.register_a = 0x42;
+        cpu.step().unwrap();
+
+        let sta = opcodes::OPCODES_ARRAY[0x9d].unwrap();
+        assert_eq!(cpu.effective_address(sta), Some(0x0305));
+        assert_eq!(cpu.mem_read(0x0305), 0);
+        assert_eq!(cpu.program_counter, 0x8002);
+
+        let ldx = opcodes::OPCODES_ARRAY[0xa2].unwrap();
+        assert_eq!(cpu.effective_address(ldx), None);
+    }
+
+    #[test]
+    fn test_brk_takes_irq_vector() {
+        // BRK with the handler at 0x8010
+        let mut cpu = scratch_cpu(&[0x00]);
+        cpu.set_halt_on_brk(false);
+        cpu.bus.patch_prg_rom(0xFFFE, 0x10);
+        cpu.bus.patch_prg_rom(0xFFFF, 0x80);
+
+        assert_eq!(cpu.step(), Ok(7));
+        assert_eq!(cpu.program_counter, 0x8010);
+        assert_eq!(cpu.stack_pointer, 0xfa);
+        // PC + 2, then the status with BREAK set
+        assert_eq!(stacked_return(&cpu), 0x8002);
+        let pushed = Flags::from_bits_truncate(cpu.peek(STACK + 0xfb));
+        assert!(pushed.contains(Flags::BREAK | Flags::BREAKBIS));
+        assert!(cpu.status.contains(Flags::INTERRUPT));
+    }
+
+    #[test]
+    fn test_nmi_vector() {
+        // NOPs, with a NOP handler at 0x8010
+        let mut cpu = scratch_cpu(&[0xea; 0x11]);
+        cpu.bus.patch_prg_rom(0xFFFA, 0x10);
+        cpu.bus.patch_prg_rom(0xFFFB, 0x80);
+        cpu.step().unwrap();
+
+        cpu.trigger_nmi();
+        cpu.step().unwrap();
+        // the handler's first instruction ran too
+        assert_eq!(cpu.program_counter, 0x8011);
+        assert_eq!(stacked_return(&cpu), 0x8001);
+        let pushed = Flags::from_bits_truncate(cpu.peek(STACK + cpu.stack_pointer as u16 + 1));
+        assert!(!pushed.contains(Flags::BREAK));
+        assert!(cpu.status.contains(Flags::INTERRUPT));
+    }
+
+    #[test]
+    fn test_irq_gated_by_interrupt_flag() {
+        // NOP; CLI; NOP..., IRQ handler at 0x8010: NOP; RTI
+        let mut program = vec![0xea, 0x58];
+        program.resize(0x10, 0xea);
+        program.extend(&[0xea, 0x40]);
+        let mut cpu = scratch_cpu(&program);
+        cpu.bus.patch_prg_rom(0xFFFE, 0x10);
+        cpu.bus.patch_prg_rom(0xFFFF, 0x80);
+
+        // the reset leaves INTERRUPT set: ignored
+        cpu.bus.set_irq(true);
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert_eq!(cpu.program_counter, 0x8002);
+
+        // clear now, so the line fires
+        cpu.step().unwrap();
+        assert_eq!(cpu.program_counter, 0x8011);
+        assert_eq!(stacked_return(&cpu), 0x8002);
+
+        // still asserted after the RTI: fires again
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert_eq!(cpu.program_counter, 0x8011);
+
+        cpu.bus.set_irq(false);
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert_eq!(cpu.program_counter, 0x8003);
+    }
+
+    #[test]
+    fn test_subroutine_and_stack_cycles() {
+        // JSR $8010; RTI; 8010: PHA; PLA; PHP; PLP; RTS
+        let mut program = vec![0x20, 0x10, 0x80, 0x40];
+        program.resize(0x10, 0xea);
+        program.extend(&[0x48, 0x68, 0x08, 0x28, 0x60]);
+        let mut cpu = scratch_cpu(&program);
+
+        let cycles: Vec<u16> = (0..6).map(|_| cpu.step().unwrap()).collect();
+        assert_eq!(cycles, vec![6, 3, 4, 3, 4, 6]);
+        assert_eq!(cpu.program_counter, 0x8003);
+
+        // the RTI returns through a hand-built interrupt frame
+        cpu.stack_pointer = 0xfa;
+        cpu.mem_write(0x01fb, 0x24);
+        cpu.mem_write(0x01fc, 0x00);
+        cpu.mem_write(0x01fd, 0x90);
+        assert_eq!(cpu.step(), Ok(6));
+        assert_eq!(cpu.program_counter, 0x9000);
+    }
+
+    #[test]
+    fn test_total_cycles() {
+        // LDA #$05; STA $10; INC $10; TAX; BRK
+        let mut cpu = scratch_cpu(&[0xa9, 0x05, 0x85, 0x10, 0xe6, 0x10, 0xaa, 0x00]);
+        // the reset sequence
+        assert_eq!(cpu.total_cycles(), 7);
+        cpu.run().unwrap();
+        assert_eq!(cpu.total_cycles(), 7 + 2 + 3 + 5 + 2);
+    }
+
+    #[test]
+    fn test_indexed_read_page_cross_cycle() {
+        // LDX #$00; LDA $00FF,X; LDX #$01; LDA $00FF,X
+        let mut cpu = scratch_cpu(&[0xa2, 0x00, 0xbd, 0xff, 0x00, 0xa2, 0x01, 0xbd, 0xff, 0x00]);
+        cpu.step().unwrap();
+        assert_eq!(cpu.step(), Ok(4));
+        cpu.step().unwrap();
+        // 0x00FF + 1 is on the next page
+        assert_eq!(cpu.step(), Ok(5));
+
+        // LDY #$01; LDA $00FF,Y; LDA ($10),Y with 0x10 -> 0x00FF
+        let mut cpu = scratch_cpu(&[0xa0, 0x01, 0xb9, 0xff, 0x00, 0xb1, 0x10]);
+        cpu.mem_write(0x10, 0xff);
+        cpu.step().unwrap();
+        assert_eq!(cpu.step(), Ok(5));
+        assert_eq!(cpu.step(), Ok(6));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_unofficial_nop_operands() {
+        // LDX #$01; NOP $00FF,X; NOP $0010,X; INX
+        let mut cpu = scratch_cpu(&[0xa2, 0x01, 0x1c, 0xff, 0x00, 0xdc, 0x10, 0x00, 0xe8]);
+        cpu.step().unwrap();
+        // the dummy read at 0x0100 crosses the page
+        assert_eq!(cpu.step(), Ok(5));
+        assert_eq!(cpu.program_counter, 0x8005);
+        assert_eq!(cpu.step(), Ok(4));
+        assert_eq!(cpu.program_counter, 0x8008);
+        cpu.step().unwrap();
+        assert_eq!(cpu.register_x, 2);
+
+        // NOP #$ea; NOP $10; NOP (implied)
+        let mut cpu = scratch_cpu(&[0x80, 0xea, 0x04, 0x10, 0x1a]);
+        assert_eq!(cpu.step(), Ok(2));
+        assert_eq!(cpu.step(), Ok(3));
+        assert_eq!(cpu.step(), Ok(2));
+        assert_eq!(cpu.program_counter, 0x8005);
+    }
+
+    #[test]
+    fn test_branch_penalties() {
+        // 8000: BEQ +2 (not taken); BNE +2 to 8006; 80F0: BNE +$20 to 8112
+        let mut program = vec![0xf0, 0x02, 0xd0, 0x02];
+        program.resize(0x06, 0xea);
+        program.extend(&[0x4c, 0xf0, 0x80]);
+        program.resize(0xf0, 0xea);
+        program.extend(&[0xd0, 0x20]);
+        program.resize(0x113, 0xea);
+        let mut cpu = scratch_cpu(&program);
+
+        assert_eq!(cpu.step(), Ok(2));
+        assert_eq!(cpu.step(), Ok(3));
+        assert_eq!(cpu.program_counter, 0x8006);
+        cpu.step().unwrap();
+        assert_eq!(cpu.step(), Ok(4));
+        assert_eq!(cpu.program_counter, 0x8112);
+    }
+
+    #[test]
+    fn test_load_at() {
+        // LDA #$42; BRK, in PRG ROM
+        let mut cpu = scratch_cpu(&[]);
+        cpu.load_at(vec![0xa9, 0x42, 0x00], 0x8100).unwrap();
+        cpu.reset();
+        assert_eq!(cpu.program_counter, 0x8100);
+        cpu.run().unwrap();
+        assert_eq!(cpu.register_a, 0x42);
+
+        // and in RAM
+        cpu.load_at(vec![0xa2, 0x07, 0x00], 0x0300).unwrap();
+        cpu.reset();
+        assert_eq!(cpu.program_counter, 0x0300);
+        cpu.run().unwrap();
+        assert_eq!(cpu.register_x, 0x07);
+    }
+
+    #[test]
+    fn test_disabled_opcode() {
+        // LDA #$01; LAX $10; LAX $10
+        let program = [0xa9, 0x01, 0xa7, 0x10, 0xa7, 0x10];
+        let mut cpu = scratch_cpu(&program);
+        cpu.mem_write(0x10, 0x42);
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert_eq!((cpu.register_a, cpu.register_x), (0x42, 0x42));
+
+        let mut cpu = scratch_cpu(&program);
+        cpu.mem_write(0x10, 0x42);
+        cpu.set_opcode_enabled(0xa7, false);
+        // other opcodes keep working, LAX goes the way of any unimplemented
+        // opcode and nothing gets loaded
+        cpu.step().unwrap();
+        assert_eq!(cpu.step(), Err(CpuError::Unimplemented { code: 0xa7, pc: 0x8002 }));
+        assert_eq!((cpu.register_a, cpu.register_x), (0x01, 0x00));
+
+        cpu.set_opcode_enabled(0xa7, true);
+        cpu.program_counter = 0x8004;
+        cpu.step().unwrap();
+        assert_eq!(cpu.register_x, 0x42);
+    }
+
+    #[test]
+    fn test_unknown_opcode_is_an_error() {
+        // LDA #$01; 0x07 is the unofficial SLO, not in the table
+        let mut cpu = scratch_cpu(&[0xa9, 0x01, 0x07, 0x00]);
+        assert_eq!(cpu.run(), Err(CpuError::Unimplemented { code: 0x07, pc: 0x8002 }));
+        assert_eq!(cpu.register_a, 1);
+    }
+}
@@ -0,0 +1,146 @@
+use crate::cpu::branch_target;
+use crate::cpu::AddressingMode;
+use crate::cpu::CPU;
+use crate::opcodes;
+use std::collections::BTreeMap;
+
+pub struct DecodedInstruction {
+    pub pc: u16,
+    pub opcode: &'static opcodes::OpCode,
+    pub mode: AddressingMode,
+    pub operand_bytes: Vec<u8>,
+    // resolved against the CPU state at decode time, None for implied ops
+    pub effective_addr: Option<u16>,
+    pub value: Option<u8>,
+}
+
+pub fn decode(cpu: &CPU, pc: u16) -> Option<DecodedInstruction> {
+    let opcode = opcodes::OPCODES_ARRAY[cpu.peek(pc) as usize]?;
+
+    let operand_bytes = (1..opcode.len as u16)
+        .map(|i| cpu.peek(pc.wrapping_add(i)))
+        .collect();
+
+    let effective_addr = match opcode.mode {
+        AddressingMode::Accumulator | AddressingMode::NoneAddressing => None,
+        AddressingMode::Immediate => Some(pc.wrapping_add(1)),
+        _ => Some(cpu.peek_absolute_address(&opcode.mode, pc.wrapping_add(1)).0),
+    };
+
+    Some(DecodedInstruction {
+        pc,
+        opcode,
+        mode: opcode.mode,
+        operand_bytes,
+        effective_addr,
+        value: effective_addr.map(|addr| cpu.peek(addr)),
+    })
+}
+
+// Recursive-descent disassembly: follows fall-through, branches, JSR and
+// absolute JMP targets. Stops a path at RTS/RTI and at indirect jumps,
+// whose target can't be known statically. BRK returns past its padding
+// byte, so the path goes on there.
+pub fn trace_reachable(cpu: &CPU, start: u16) -> BTreeMap<u16, &'static opcodes::OpCode> {
+    trace_reachable_from(cpu, &[start])
+}
+
+// Same, from several entry points, e.g. the reset and interrupt vectors
+pub fn trace_reachable_from(cpu: &CPU, starts: &[u16]) -> BTreeMap<u16, &'static opcodes::OpCode> {
+    let mut code = BTreeMap::new();
+    let mut pending = starts.to_vec();
+
+    while let Some(mut pc) = pending.pop() {
+        while !code.contains_key(&pc) {
+            let op = match opcodes::OPCODES_ARRAY[cpu.peek(pc) as usize] {
+                Some(op) => op,
+                None => break,
+            };
+            code.insert(pc, op);
+
+            let next = pc.wrapping_add(op.len as u16);
+            match op.code {
+                /* JMP absolute */
+                0x4c => {
+                    pending.push(cpu.peek_u16(pc.wrapping_add(1)));
+                    break;
+                }
+
+                /* JSR */
+                0x20 => pending.push(cpu.peek_u16(pc.wrapping_add(1))),
+
+                /* Branch */
+                0x90 | 0xb0 | 0xf0 | 0x30 | 0xd0 | 0x10 | 0x50 | 0x70 => {
+                    let offset = cpu.peek(pc.wrapping_add(1)) as i8;
+                    pending.push(branch_target(pc.wrapping_add(1), offset));
+                }
+
+                /* BRK */
+                0x00 => {
+                    pending.push(pc.wrapping_add(2));
+                    break;
+                }
+
+                /* RTI, RTS, JMP indirect */
+                0x40 | 0x60 | 0x6c => break,
+
+                _ => {}
+            }
+            pc = next;
+        }
+    }
+
+    code
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::Mem;
+    use crate::selftest::scratch_cpu;
+
+    #[test]
+    fn test_decode_indirect_y() {
+        // LDA ($10),Y with ($10) = 0x0300 and Y = 2
+        let mut cpu = scratch_cpu(&[0xb1, 0x10]);
+        cpu.mem_write_u16(0x10, 0x0300);
+        cpu.mem_write(0x0302, 0x99);
+        cpu.register_y = 2;
+
+        let decoded = decode(&cpu, 0x8000).unwrap();
+        assert_eq!(decoded.pc, 0x8000);
+        assert_eq!(decoded.opcode.mnemonic, "LDA");
+        assert_eq!(decoded.mode, AddressingMode::Indirect_Y);
+        assert_eq!(decoded.operand_bytes, vec![0x10]);
+        assert_eq!(decoded.effective_addr, Some(0x0302));
+        assert_eq!(decoded.value, Some(0x99));
+
+        // nothing to resolve for implied instructions
+        let cpu = scratch_cpu(&[0xe8]);
+        let decoded = decode(&cpu, 0x8000).unwrap();
+        assert!(decoded.operand_bytes.is_empty());
+        assert_eq!(decoded.effective_addr, None);
+    }
+
+    #[test]
+    fn test_trace_reachable() {
+        let mut program = vec![
+            0xa2, 0x00,       // 8000 LDX #$00
+            0xf0, 0x06,       // 8002 BEQ $800A
+            0x20, 0x20, 0x80, // 8004 JSR $8020
+            0x4c, 0x00, 0x80, // 8007 JMP $8000
+            0xe8,             // 800A INX
+            0x4c, 0x00, 0x80, // 800B JMP $8000
+            // data, would decode as LDA #$01; STA $02
+            0xa9, 0x01, 0x85, 0x02,
+        ];
+        program.resize(0x20, 0);
+        program.extend(&[0xc8, 0x60]); // 8020 INY; RTS
+        let cpu = scratch_cpu(&program);
+
+        let code = trace_reachable(&cpu, 0x8000);
+        let addrs: Vec<u16> = code.keys().cloned().collect();
+        assert_eq!(addrs, vec![0x8000, 0x8002, 0x8004, 0x8007, 0x800A, 0x800B, 0x8020, 0x8021]);
+        assert_eq!(code[&0x8020].mnemonic, "INY");
+    }
+}
@@ -0,0 +1,68 @@
+// RGB pixels, 3 bytes each, in rows of width pixels
+#[derive(Debug, PartialEq, Clone)]
+pub struct Frame {
+    pub width: usize,
+    pub height: usize,
+    pub data: Vec<u8>,
+}
+
+impl Frame {
+    pub fn new(width: usize, height: usize) -> Self {
+        Frame { width, height, data: vec![0; width * height * 3] }
+    }
+
+    pub fn set_pixel(&mut self, x: usize, y: usize, rgb: (u8, u8, u8)) {
+        let base = (y * self.width + x) * 3;
+        self.data[base] = rgb.0;
+        self.data[base + 1] = rgb.1;
+        self.data[base + 2] = rgb.2;
+    }
+
+    pub fn pixel(&self, x: usize, y: usize) -> (u8, u8, u8) {
+        let base = (y * self.width + x) * 3;
+        (self.data[base], self.data[base + 1], self.data[base + 2])
+    }
+}
+
+// Part of a frame, in pixels
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Rect {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+pub const DIFF_TILE_SIZE: usize = 8;
+
+impl Frame {
+    // The 8x8 tiles where self differs from previous, row by row, clipped
+    // at the frame's edges. A frame of another size differs everywhere.
+    pub fn changed_tiles(&self, previous: &Frame) -> Vec<Rect> {
+        let same_size = (self.width, self.height) == (previous.width, previous.height);
+        let mut changed = Vec::new();
+
+        for y in (0..self.height).step_by(DIFF_TILE_SIZE) {
+            for x in (0..self.width).step_by(DIFF_TILE_SIZE) {
+                let rect = Rect {
+                    x,
+                    y,
+                    width: DIFF_TILE_SIZE.min(self.width - x),
+                    height: DIFF_TILE_SIZE.min(self.height - y),
+                };
+                if !same_size || self.differs_in(previous, rect) {
+                    changed.push(rect);
+                }
+            }
+        }
+        changed
+    }
+
+    fn differs_in(&self, other: &Frame, rect: Rect) -> bool {
+        (rect.y..rect.y + rect.height).any(|y| {
+            let start = (y * self.width + rect.x) * 3;
+            let end = start + rect.width * 3;
+            self.data[start..end] != other.data[start..end]
+        })
+    }
+}
@@ -0,0 +1,95 @@
+const LETTERS: &str = "APZLGITYEOXUKSVN";
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct GameGenie {
+    pub addr: u16,
+    pub value: u8,
+    // 8 letter codes only patch the read when the ROM holds this byte
+    pub compare: Option<u8>,
+}
+
+impl GameGenie {
+    pub fn decode(code: &str) -> Result<GameGenie, String> {
+        let n = code
+            .chars()
+            .map(|c| {
+                LETTERS
+                    .find(c.to_ascii_uppercase())
+                    .map(|i| i as u16)
+                    .ok_or(format!("'{}' is not a Game Genie letter", c))
+            })
+            .collect::<Result<Vec<u16>, String>>()?;
+
+        if n.len() != 6 && n.len() != 8 {
+            return Err(format!("Game Genie codes are 6 or 8 letters long, got {}", n.len()));
+        }
+
+        let addr = 0x8000
+            | ((n[3] & 7) << 12)
+            | ((n[4] & 8) << 8)
+            | ((n[5] & 7) << 8)
+            | ((n[1] & 8) << 4)
+            | ((n[2] & 7) << 4)
+            | (n[3] & 8)
+            | (n[4] & 7);
+
+        let value = ((n[0] & 8) << 4) | ((n[1] & 7) << 4) | (n[0] & 7);
+
+        if n.len() == 6 {
+            Ok(GameGenie {
+                addr,
+                value: (value | (n[5] & 8)) as u8,
+                compare: None,
+            })
+        } else {
+            let compare = ((n[6] & 8) << 4) | ((n[7] & 7) << 4) | (n[5] & 8) | (n[6] & 7);
+            Ok(GameGenie {
+                addr,
+                value: (value | (n[7] & 8)) as u8,
+                compare: Some(compare as u8),
+            })
+        }
+    }
+
+    pub fn apply(&self, addr: u16, data: u8) -> u8 {
+        if addr != self.addr {
+            return data;
+        }
+        match self.compare {
+            Some(compare) if compare != data => data,
+            _ => self.value,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::cartridge::test::nrom;
+    use crate::cpu::Mem;
+
+    #[test]
+    fn test_decode_and_patch() {
+        // Super Mario Bros. infinite lives
+        let lives = GameGenie::decode("SXIOPO").unwrap();
+        assert_eq!(lives, GameGenie { addr: 0x91D9, value: 0xAD, compare: None });
+        let compared = GameGenie::decode("zexpygla").unwrap();
+        assert_eq!(compared, GameGenie { addr: 0x94A7, value: 0x02, compare: Some(0x03) });
+        assert!(GameGenie::decode("SXIOP").is_err());
+        assert!(GameGenie::decode("SXIOPB").is_err());
+
+        let mut bus = Bus::new(nrom());
+        bus.patch_prg_rom(0x91D9, 0xCE);
+        bus.patch_prg_rom(0x94A7, 0x03);
+        bus.add_game_genie(lives);
+        bus.add_game_genie(compared);
+        assert_eq!(bus.mem_read(0x91D9), 0xAD);
+        assert_eq!(bus.mem_read(0x94A7), 0x02);
+        assert_eq!(bus.mem_read(0x91DA), 0x00);
+
+        // the compare byte doesn't match any more
+        bus.patch_prg_rom(0x94A7, 0x04);
+        assert_eq!(bus.mem_read(0x94A7), 0x04);
+    }
+}
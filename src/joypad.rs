@@ -0,0 +1,144 @@
+use std::collections::HashMap;
+
+bitflags! {
+
+    // In the order the shift register reports them
+    pub struct JoypadButton: u8 {
+        const BUTTON_A = 0b00000001;
+        const BUTTON_B = 0b00000010;
+        const SELECT = 0b00000100;
+        const START = 0b00001000;
+        const UP = 0b00010000;
+        const DOWN = 0b00100000;
+        const LEFT = 0b01000000;
+        const RIGHT = 0b10000000;
+    }
+
+}
+
+// Frontend input names (keys, gamepad buttons) to NES buttons, so every
+// frontend remaps the same way
+#[derive(Debug, Clone, Default)]
+pub struct ButtonMap {
+    bindings: HashMap<String, JoypadButton>,
+}
+
+impl ButtonMap {
+    pub fn new() -> Self {
+        ButtonMap::default()
+    }
+
+    // Replaces whatever input was bound to before
+    pub fn bind(&mut self, input: &str, button: JoypadButton) {
+        self.bindings.insert(input.to_string(), button);
+    }
+
+    pub fn button(&self, input: &str) -> Option<JoypadButton> {
+        self.bindings.get(input).cloned()
+    }
+}
+
+pub struct Joypad {
+    strobe: bool,
+    button_index: u8,
+    button_status: JoypadButton,
+    button_map: ButtonMap,
+}
+
+impl Joypad {
+    pub fn new() -> Self {
+        Joypad::with_button_map(ButtonMap::new())
+    }
+
+    pub fn with_button_map(button_map: ButtonMap) -> Self {
+        Joypad {
+            strobe: false,
+            button_index: 0,
+            button_status: JoypadButton::empty(),
+            button_map,
+        }
+    }
+
+    // While strobe is high the register keeps reloading, so reads return A
+    pub fn write(&mut self, data: u8) {
+        self.strobe = data & 1 == 1;
+        if self.strobe {
+            self.button_index = 0;
+        }
+    }
+
+    // One button per read, A first. Official pads return 1 once all eight
+    // have been shifted out.
+    pub fn read(&mut self) -> u8 {
+        let response = self.peek();
+        if self.button_index <= 7 && !self.strobe {
+            self.button_index += 1;
+        }
+        response
+    }
+
+    // The bit the next read returns, without shifting
+    pub fn peek(&self) -> u8 {
+        if self.button_index > 7 {
+            return 1;
+        }
+        (self.button_status.bits() >> self.button_index) & 1
+    }
+
+    pub fn set_button_pressed(&mut self, button: JoypadButton, pressed: bool) {
+        self.button_status.set(button, pressed);
+    }
+
+    // Every button at once, for movie recording and playback
+    pub fn buttons(&self) -> JoypadButton {
+        self.button_status
+    }
+
+    pub fn set_buttons(&mut self, buttons: JoypadButton) {
+        self.button_status = buttons;
+    }
+
+    // Goes through the button map. Returns false for unbound inputs.
+    pub fn set_input_pressed(&mut self, input: &str, pressed: bool) -> bool {
+        match self.button_map.button(input) {
+            Some(button) => {
+                self.set_button_pressed(button, pressed);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl Default for Joypad {
+    fn default() -> Self {
+        Joypad::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_button_map() {
+        let mut map = ButtonMap::new();
+        map.bind("X", JoypadButton::BUTTON_A);
+        map.bind("Z", JoypadButton::BUTTON_B);
+        let mut joypad = Joypad::with_button_map(map);
+
+        assert!(joypad.set_input_pressed("X", true));
+        assert!(!joypad.set_input_pressed("Q", true));
+        joypad.write(1);
+        joypad.write(0);
+        // A, then nothing else
+        assert_eq!(joypad.read(), 1);
+        for _ in 1..8 {
+            assert_eq!(joypad.read(), 0);
+        }
+
+        joypad.set_input_pressed("X", false);
+        joypad.write(1);
+        assert_eq!(joypad.read(), 0);
+    }
+}
@@ -1,34 +1,36 @@
+use crate::cpu::branch_target;
 use crate::cpu::AddressingMode;
-use crate::cpu::Mem;
 use crate::cpu::CPU;
 use crate::opcodes;
-use std::collections::HashMap;
 
 pub fn log(cpu: &CPU) -> String {
-    let ref opscodes: HashMap<u8, &'static opcodes::OpCode> = *opcodes::OPCODES_MAP;
+    let code = cpu.peek(cpu.program_counter);
+    let begin = cpu.program_counter;
 
-    let code = cpu.mem_read(cpu.program_counter);
-    let ops = opscodes.get(&code).unwrap();
+    // not in the opcode table: show the byte, the CPU will refuse to run it
+    let ops = match opcodes::OPCODES_ARRAY[code as usize] {
+        Some(ops) => ops,
+        None => return with_registers(cpu, format!("{:04x}  {:02x}        ???", begin, code)),
+    };
 
-    let begin = cpu.program_counter;
     let mut hex_dump = vec![];
     hex_dump.push(code);
 
     let (mem_addr, stored_value) = match ops.mode {
-        AddressingMode::Immediate | AddressingMode::NoneAddressing => (0, 0),
+        AddressingMode::Immediate | AddressingMode::Accumulator | AddressingMode::NoneAddressing => (0, 0),
         _ => {
-            let addr = cpu.get_absolute_address(&ops.mode, begin + 1);
-            (addr, cpu.mem_read(addr))
+            let (addr, _) = cpu.peek_absolute_address(&ops.mode, begin + 1);
+            (addr, cpu.peek(addr))
         }
     };
 
     let tmp = match ops.len {
-        1 => match ops.code {
-            0x0a | 0x4a | 0x2a | 0x6a => format!("A "),
+        1 => match ops.mode {
+            AddressingMode::Accumulator => String::from("A "),
             _ => String::from(""),
         },
         2 => {
-            let address: u8 = cpu.mem_read(begin + 1);
+            let address: u8 = cpu.peek(begin + 1);
             // let value = cpu.mem_read(address));
             hex_dump.push(address);
 
@@ -59,9 +61,7 @@ pub fn log(cpu: &CPU) -> String {
                 ),
                 AddressingMode::NoneAddressing => {
                     // assuming local jumps: BNE, BVS, etc....
-                    let address: usize =
-                        (begin as usize + 2).wrapping_add((address as i8) as usize);
-                    format!("${:04x}", address)
+                    format!("${:04x}", branch_target(begin + 1, address as i8))
                 }
 
                 _ => panic!(
@@ -71,23 +71,23 @@ pub fn log(cpu: &CPU) -> String {
             }
         }
         3 => {
-            let address_lo = cpu.mem_read(begin + 1);
-            let address_hi = cpu.mem_read(begin + 2);
+            let address_lo = cpu.peek(begin + 1);
+            let address_hi = cpu.peek(begin + 2);
             hex_dump.push(address_lo);
             hex_dump.push(address_hi);
 
-            let address = cpu.mem_read_u16(begin + 1);
+            let address = cpu.peek_u16(begin + 1);
 
             match ops.mode {
                 AddressingMode::NoneAddressing => {
                     if ops.code == 0x6c {
                         //jmp indirect
                         let jmp_addr = if address & 0x00FF == 0x00FF {
-                            let lo = cpu.mem_read(address);
-                            let hi = cpu.mem_read(address & 0xFF00);
+                            let lo = cpu.peek(address);
+                            let hi = cpu.peek(address & 0xFF00);
                             (hi as u16) << 8 | (lo as u16)
                         } else {
-                            cpu.mem_read_u16(address)
+                            cpu.peek_u16(address)
                         };
 
                         // let jmp_addr = cpu.mem_read_u16(address);
@@ -123,9 +123,37 @@ pub fn log(cpu: &CPU) -> String {
         .trim()
         .to_string();
 
+    with_registers(cpu, asm_str)
+}
+
+fn with_registers(cpu: &CPU, asm_str: String) -> String {
     format!(
         "{:47} A:{:02x} X:{:02x} Y:{:02x} P:{:02x} SP:{:02x}",
         asm_str, cpu.register_a, cpu.register_x, cpu.register_y, cpu.status, cpu.stack_pointer,
     )
     .to_ascii_uppercase()
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::selftest::scratch_cpu;
+
+    #[test]
+    fn test_log_unknown_opcode() {
+        let cpu = scratch_cpu(&[0x07, 0xa9]);
+        assert_eq!(
+            log(&cpu),
+            "8000  07        ???                             A:00 X:00 Y:00 P:24 SP:FD"
+        );
+    }
+
+    #[test]
+    fn test_log_accumulator_operand() {
+        // ASL A; INX
+        let mut cpu = scratch_cpu(&[0x0a, 0xe8]);
+        assert_eq!(log(&cpu)[..47].trim_end(), "8000  0A        ASL A");
+        cpu.step().unwrap();
+        assert_eq!(log(&cpu)[..47].trim_end(), "8001  E8        INX");
+    }
+}
@@ -4,7 +4,20 @@ pub mod cpu;
 pub mod opcodes;
 pub mod bus;
 pub mod cartridge;
+pub mod mapper;
+pub mod ppu;
+pub mod apu;
+pub mod frame;
+pub mod palette;
+pub mod joypad;
 pub mod log;
+pub mod disasm;
+pub mod timing;
+pub mod selftest;
+pub mod gamegenie;
+pub mod movie;
+
+pub use selftest::self_test;
 
 use cpu::Mem;
 use cpu::CPU;
@@ -80,6 +93,17 @@ fn handle_user_input(cpu: &mut CPU, event_pump: &mut EventPump) {
 }
 
 fn main() {
+    if std::env::args().any(|arg| arg == "--self-test") {
+        match self_test() {
+            Ok(()) => println!("self test passed"),
+            Err(e) => {
+                println!("self test failed: {}", e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
     // init sdl2
     println!("First step");
     let sdl_context = sdl2::init().unwrap();
@@ -97,8 +121,7 @@ fn main() {
     let mut texture = creator
         .create_texture_target(PixelFormatEnum::RGB24, 32, 32).unwrap();
     
-    let game_bytes: Vec<u8> = std::fs::read("nestest.nes").unwrap();
-    let rom = Rom::new(&game_bytes).unwrap();
+    let rom = Rom::from_file("nestest.nes").unwrap();
     let bus = Bus::new(rom);
     let mut cpu = CPU::new(bus);
     cpu.reset();
@@ -122,6 +145,6 @@ fn main() {
         // }
         
         // std::thread::sleep(Duration::from_nanos(70000));
-    });
+    }).unwrap();
 
 }
\ No newline at end of file
@@ -0,0 +1,86 @@
+use super::{split_state, Chr, Mapper};
+
+const CHR_BANK_SIZE: usize = 0x2000;
+
+// Mapper 3: PRG fixed like NROM, any write selects the 8KB CHR bank
+pub struct Cnrom {
+    prg_rom: Vec<u8>,
+    chr: Chr,
+    chr_bank: usize,
+}
+
+impl Cnrom {
+    pub fn new(prg_rom: Vec<u8>, chr: Chr) -> Self {
+        Cnrom {
+            prg_rom,
+            chr,
+            chr_bank: 0,
+        }
+    }
+
+    // a 16KB ROM is mirrored at 0xC000
+    fn prg_index(&self, addr: u16) -> usize {
+        (addr - 0x8000) as usize % self.prg_rom.len()
+    }
+
+    fn chr_index(&self, addr: u16) -> usize {
+        self.chr_bank * CHR_BANK_SIZE + addr as usize
+    }
+}
+
+impl Mapper for Cnrom {
+    fn cpu_read(&self, addr: u16) -> u8 {
+        self.prg_rom[self.prg_index(addr)]
+    }
+
+    fn cpu_write(&mut self, _addr: u16, data: u8) {
+        self.chr_bank = data as usize;
+    }
+
+    // Chr wraps the bank number to the CHR size
+    fn ppu_read(&self, addr: u16) -> u8 {
+        self.chr.read(self.chr_index(addr))
+    }
+
+    fn ppu_write(&mut self, addr: u16, data: u8) {
+        let index = self.chr_index(addr);
+        self.chr.write(index, data);
+    }
+
+    fn patch_prg(&mut self, addr: u16, data: u8) {
+        let index = self.prg_index(addr);
+        self.prg_rom[index] = data;
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        let mut state = vec![self.chr_bank as u8];
+        state.extend_from_slice(self.chr.ram());
+        state
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> Result<(), String> {
+        let (registers, chr_ram) = split_state(state, 1)?;
+        self.chr.restore_ram(chr_ram)?;
+        self.chr_bank = registers[0] as usize;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chr_bank_switch() {
+        // 4 banks of 8KB, each filled with its bank number
+        let chr_rom = (0..4).flat_map(|bank| vec![bank as u8; CHR_BANK_SIZE]).collect();
+        let mut mapper = Cnrom::new(vec![0; 0x4000], Chr::new(chr_rom, 0));
+        assert_eq!(mapper.ppu_read(0x0000), 0);
+
+        mapper.cpu_write(0x8000, 1);
+        assert_eq!((mapper.ppu_read(0x0000), mapper.ppu_read(0x1FFF)), (1, 1));
+        // any address in ROM space selects, the number wraps to the ROM size
+        mapper.cpu_write(0xFFFF, 6);
+        assert_eq!(mapper.ppu_read(0x1000), 2);
+    }
+}
@@ -0,0 +1,214 @@
+use super::{split_state, Chr, Mapper, PRG_BANK_SIZE};
+use crate::cartridge::Mirroring;
+
+const CHR_BANK_SIZE: usize = 0x1000;
+
+// Mapper 1. Registers are loaded one bit per write through a 5-bit shift
+// register, the fifth write picks the register from address bits 13-14.
+pub struct Mmc1 {
+    prg_rom: Vec<u8>,
+    chr: Chr,
+    // a 1 marks where the shift register is full
+    shift: u8,
+    control: u8,
+    chr_bank0: u8,
+    chr_bank1: u8,
+    prg_bank: u8,
+}
+
+impl Mmc1 {
+    pub fn new(prg_rom: Vec<u8>, chr: Chr) -> Self {
+        Mmc1 {
+            prg_rom,
+            chr,
+            shift: 0b10000,
+            // power on in PRG mode 3: last bank fixed at 0xC000
+            control: 0b01100,
+            chr_bank0: 0,
+            chr_bank1: 0,
+            prg_bank: 0,
+        }
+    }
+
+    fn prg_banks(&self) -> usize {
+        self.prg_rom.len() / PRG_BANK_SIZE
+    }
+
+    fn prg_index(&self, addr: u16) -> usize {
+        let bank = (self.prg_bank & 0b1111) as usize;
+        let bank = match ((self.control >> 2) & 0b11, addr < 0xC000) {
+            // 32KB mode, the low bit of the bank number is ignored
+            (0, true) | (1, true) => bank & !1,
+            (0, false) | (1, false) => bank | 1,
+            // first bank fixed at 0x8000
+            (2, true) => 0,
+            (2, false) => bank,
+            // last bank fixed at 0xC000
+            (_, true) => bank,
+            (_, false) => self.prg_banks() - 1,
+        };
+        (bank % self.prg_banks()) * PRG_BANK_SIZE + (addr as usize % PRG_BANK_SIZE)
+    }
+
+    fn chr_index(&self, addr: u16) -> usize {
+        let bank = if self.control & 0b10000 == 0 {
+            // 8KB mode, the low bit of the bank number is ignored
+            (self.chr_bank0 & !1) as usize + (addr as usize / CHR_BANK_SIZE)
+        } else if addr < 0x1000 {
+            self.chr_bank0 as usize
+        } else {
+            self.chr_bank1 as usize
+        };
+        bank * CHR_BANK_SIZE + (addr as usize % CHR_BANK_SIZE)
+    }
+}
+
+impl Mapper for Mmc1 {
+    fn cpu_read(&self, addr: u16) -> u8 {
+        self.prg_rom[self.prg_index(addr)]
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        // bit 7 resets the shift register and goes back to PRG mode 3
+        if data & 0b1000_0000 != 0 {
+            self.shift = 0b10000;
+            self.control |= 0b01100;
+            return;
+        }
+
+        let full = self.shift & 1 == 1;
+        self.shift = (self.shift >> 1) | ((data & 1) << 4);
+        if !full {
+            return;
+        }
+
+        let value = self.shift;
+        match addr {
+            0x8000 ..= 0x9FFF => self.control = value,
+            0xA000 ..= 0xBFFF => self.chr_bank0 = value,
+            0xC000 ..= 0xDFFF => self.chr_bank1 = value,
+            _ => self.prg_bank = value,
+        }
+        self.shift = 0b10000;
+    }
+
+    fn ppu_read(&self, addr: u16) -> u8 {
+        self.chr.read(self.chr_index(addr))
+    }
+
+    fn ppu_write(&mut self, addr: u16, data: u8) {
+        let index = self.chr_index(addr);
+        self.chr.write(index, data);
+    }
+
+    fn patch_prg(&mut self, addr: u16, data: u8) {
+        let index = self.prg_index(addr);
+        self.prg_rom[index] = data;
+    }
+
+    fn mirroring(&self) -> Option<Mirroring> {
+        Some(match self.control & 0b11 {
+            0 => Mirroring::SingleScreenLower,
+            1 => Mirroring::SingleScreenUpper,
+            2 => Mirroring::Vertical,
+            _ => Mirroring::Horizontal,
+        })
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        let mut state = vec![self.shift, self.control, self.chr_bank0, self.chr_bank1, self.prg_bank];
+        state.extend_from_slice(self.chr.ram());
+        state
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> Result<(), String> {
+        let (registers, chr_ram) = split_state(state, 5)?;
+        self.chr.restore_ram(chr_ram)?;
+        self.shift = registers[0];
+        self.control = registers[1];
+        self.chr_bank0 = registers[2];
+        self.chr_bank1 = registers[3];
+        self.prg_bank = registers[4];
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 8 banks of 16KB, each filled with its bank number, and CHR RAM
+    fn test_mmc1() -> Mmc1 {
+        let prg_rom = (0..8).flat_map(|bank| vec![bank as u8; PRG_BANK_SIZE]).collect();
+        Mmc1::new(prg_rom, Chr::new(vec![], 0))
+    }
+
+    // the five serial writes, low bit first
+    fn write_register(mapper: &mut Mmc1, addr: u16, value: u8) {
+        for bit in 0..5 {
+            mapper.cpu_write(addr, (value >> bit) & 1);
+        }
+    }
+
+    #[test]
+    fn test_prg_bank_switch() {
+        let mut mapper = test_mmc1();
+        assert_eq!((mapper.cpu_read(0x8000), mapper.cpu_read(0xC000)), (0, 7));
+
+        // nothing changes until the fifth write
+        for bit in 0..4 {
+            mapper.cpu_write(0xE000, (2 >> bit) & 1);
+            assert_eq!(mapper.cpu_read(0x8000), 0);
+        }
+        mapper.cpu_write(0xE000, 0);
+        assert_eq!((mapper.cpu_read(0x8000), mapper.cpu_read(0xC000)), (2, 7));
+
+        // mode 2: first bank fixed at 0x8000, the switch moves to 0xC000
+        write_register(&mut mapper, 0x8000, 0b01000);
+        assert_eq!((mapper.cpu_read(0x8000), mapper.cpu_read(0xC000)), (0, 2));
+        // 32KB mode ignores the low bit
+        write_register(&mut mapper, 0x8000, 0b00000);
+        write_register(&mut mapper, 0xE000, 5);
+        assert_eq!((mapper.cpu_read(0x8000), mapper.cpu_read(0xC000)), (4, 5));
+
+        // bit 7 throws away a half-written value and restores mode 3
+        mapper.cpu_write(0xE000, 1);
+        mapper.cpu_write(0xE000, 1);
+        mapper.cpu_write(0xE000, 0x80);
+        write_register(&mut mapper, 0xE000, 1);
+        assert_eq!((mapper.cpu_read(0x8000), mapper.cpu_read(0xC000)), (1, 7));
+    }
+
+    #[test]
+    fn test_save_load_state() {
+        let mut mapper = test_mmc1();
+        write_register(&mut mapper, 0xE000, 3);
+        // PRG mode 3, vertical mirroring
+        write_register(&mut mapper, 0x8000, 0b11110);
+        mapper.ppu_write(0x0042, 0xAB);
+        // two bits into the next register write
+        mapper.cpu_write(0xE000, 1);
+        mapper.cpu_write(0xE000, 0);
+        let state = mapper.save_state();
+
+        mapper.cpu_write(0x8000, 0x80);
+        write_register(&mut mapper, 0xE000, 5);
+        write_register(&mut mapper, 0x8000, 0b01111);
+        mapper.ppu_write(0x0042, 0);
+        assert_eq!(mapper.cpu_read(0x8000), 5);
+
+        mapper.load_state(&state).unwrap();
+        assert_eq!(mapper.cpu_read(0x8000), 3);
+        assert_eq!(mapper.cpu_read(0xC000), 7);
+        assert_eq!(mapper.mirroring(), Some(Mirroring::Vertical));
+        assert_eq!(mapper.ppu_read(0x0042), 0xAB);
+        // the restored shift register still holds 1, 0: three more bits make 0b00101
+        mapper.cpu_write(0xE000, 1);
+        mapper.cpu_write(0xE000, 0);
+        mapper.cpu_write(0xE000, 0);
+        assert_eq!(mapper.cpu_read(0x8000), 5);
+
+        assert!(mapper.load_state(&state[..4]).is_err());
+        assert!(mapper.load_state(&state[..state.len() - 1]).is_err());
+    }
+}
@@ -0,0 +1,237 @@
+use super::{split_state, Chr, Mapper};
+use crate::cartridge::Mirroring;
+
+const PRG_BANK_SIZE: usize = 0x2000;
+const CHR_BANK_SIZE: usize = 0x0400;
+
+// Mapper 4. 0x8000 selects which of the 8 bank registers 0x8001 writes,
+// 0xA000 sets mirroring and 0xC000-0xE001 drive the scanline IRQ counter.
+// Even and odd addresses are different registers.
+pub struct Mmc3 {
+    prg_rom: Vec<u8>,
+    chr: Chr,
+    four_screen: bool,
+    bank_select: u8,
+    // R0-R5 are CHR banks (R0/R1 in 2KB units), R6/R7 PRG banks
+    banks: [u8; 8],
+    mirroring: Mirroring,
+    irq_latch: u8,
+    irq_counter: u8,
+    irq_reload: bool,
+    irq_enabled: bool,
+    irq_pending: bool,
+}
+
+impl Mmc3 {
+    pub fn new(prg_rom: Vec<u8>, chr: Chr, mirroring: Mirroring) -> Self {
+        Mmc3 {
+            prg_rom,
+            chr,
+            four_screen: mirroring == Mirroring::FourScreen,
+            bank_select: 0,
+            banks: [0; 8],
+            mirroring,
+            irq_latch: 0,
+            irq_counter: 0,
+            irq_reload: false,
+            irq_enabled: false,
+            irq_pending: false,
+        }
+    }
+
+    fn prg_banks(&self) -> usize {
+        self.prg_rom.len() / PRG_BANK_SIZE
+    }
+
+    fn prg_index(&self, addr: u16) -> usize {
+        let second_last = self.prg_banks() - 2;
+        let swap = self.bank_select & 0b0100_0000 != 0;
+        let bank = match ((addr - 0x8000) / PRG_BANK_SIZE as u16, swap) {
+            (0, false) | (2, true) => self.banks[6] as usize,
+            (0, true) | (2, false) => second_last,
+            (1, _) => self.banks[7] as usize,
+            _ => self.prg_banks() - 1,
+        };
+        (bank % self.prg_banks()) * PRG_BANK_SIZE + (addr as usize % PRG_BANK_SIZE)
+    }
+
+    fn chr_index(&self, addr: u16) -> usize {
+        // bit 7 swaps the 2KB and the 1KB halves
+        let addr = if self.bank_select & 0b1000_0000 != 0 { addr ^ 0x1000 } else { addr };
+        let bank = match addr / CHR_BANK_SIZE as u16 {
+            0 => self.banks[0] & !1,
+            1 => self.banks[0] | 1,
+            2 => self.banks[1] & !1,
+            3 => self.banks[1] | 1,
+            n => self.banks[n as usize - 2],
+        };
+        bank as usize * CHR_BANK_SIZE + (addr as usize % CHR_BANK_SIZE)
+    }
+}
+
+impl Mapper for Mmc3 {
+    fn cpu_read(&self, addr: u16) -> u8 {
+        self.prg_rom[self.prg_index(addr)]
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        let even = addr & 1 == 0;
+        match (addr, even) {
+            (0x8000 ..= 0x9FFF, true) => self.bank_select = data,
+            (0x8000 ..= 0x9FFF, false) => self.banks[(self.bank_select & 0b111) as usize] = data,
+            (0xA000 ..= 0xBFFF, true) => {
+                if !self.four_screen {
+                    self.mirroring = if data & 1 == 0 { Mirroring::Vertical } else { Mirroring::Horizontal };
+                }
+            }
+            // PRG RAM protect, not emulated
+            (0xA000 ..= 0xBFFF, false) => {}
+            (0xC000 ..= 0xDFFF, true) => self.irq_latch = data,
+            (0xC000 ..= 0xDFFF, false) => {
+                self.irq_counter = 0;
+                self.irq_reload = true;
+            }
+            // disabling also acknowledges a pending IRQ
+            (_, true) => {
+                self.irq_enabled = false;
+                self.clear_irq();
+            }
+            (_, false) => self.irq_enabled = true,
+        }
+    }
+
+    fn ppu_read(&self, addr: u16) -> u8 {
+        self.chr.read(self.chr_index(addr))
+    }
+
+    fn ppu_write(&mut self, addr: u16, data: u8) {
+        let index = self.chr_index(addr);
+        self.chr.write(index, data);
+    }
+
+    fn patch_prg(&mut self, addr: u16, data: u8) {
+        let index = self.prg_index(addr);
+        self.prg_rom[index] = data;
+    }
+
+    fn mirroring(&self) -> Option<Mirroring> {
+        Some(self.mirroring)
+    }
+
+    fn clock_scanline(&mut self) {
+        if self.irq_counter == 0 || self.irq_reload {
+            self.irq_counter = self.irq_latch;
+            self.irq_reload = false;
+        } else {
+            self.irq_counter -= 1;
+        }
+
+        if self.irq_counter == 0 && self.irq_enabled {
+            self.irq_pending = true;
+        }
+    }
+
+    fn irq_pending(&self) -> bool {
+        self.irq_pending
+    }
+
+    fn clear_irq(&mut self) {
+        self.irq_pending = false;
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        let mut state = vec![self.bank_select];
+        state.extend_from_slice(&self.banks);
+        state.extend_from_slice(&[
+            (self.mirroring == Mirroring::Horizontal) as u8,
+            self.irq_latch,
+            self.irq_counter,
+            self.irq_reload as u8,
+            self.irq_enabled as u8,
+            self.irq_pending as u8,
+        ]);
+        state.extend_from_slice(self.chr.ram());
+        state
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> Result<(), String> {
+        let (registers, chr_ram) = split_state(state, 15)?;
+        self.chr.restore_ram(chr_ram)?;
+        self.bank_select = registers[0];
+        self.banks.copy_from_slice(&registers[1..9]);
+        // four-screen boards ignore the mirroring register
+        if !self.four_screen {
+            self.mirroring = if registers[9] != 0 { Mirroring::Horizontal } else { Mirroring::Vertical };
+        }
+        self.irq_latch = registers[10];
+        self.irq_counter = registers[11];
+        self.irq_reload = registers[12] != 0;
+        self.irq_enabled = registers[13] != 0;
+        self.irq_pending = registers[14] != 0;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 8 PRG banks of 8KB and 8 CHR banks of 1KB, each filled with its number
+    fn test_mmc3() -> Mmc3 {
+        let prg_rom = (0..8).flat_map(|bank| vec![bank as u8; PRG_BANK_SIZE]).collect();
+        let chr_rom = (0..8).flat_map(|bank| vec![bank as u8; CHR_BANK_SIZE]).collect();
+        Mmc3::new(prg_rom, Chr::new(chr_rom, 0), Mirroring::Vertical)
+    }
+
+    #[test]
+    fn test_bank_select_and_data() {
+        let mut mapper = test_mmc3();
+        mapper.cpu_write(0x8000, 6);
+        mapper.cpu_write(0x8001, 3);
+        mapper.cpu_write(0x8000, 7);
+        mapper.cpu_write(0x8001, 4);
+        let prg = |mapper: &Mmc3| {
+            [0x8000, 0xA000, 0xC000, 0xE000].iter().map(|a| mapper.cpu_read(*a)).collect::<Vec<u8>>()
+        };
+        assert_eq!(prg(&mapper), vec![3, 4, 6, 7]);
+        // bit 6 swaps 0x8000 and 0xC000
+        mapper.cpu_write(0x8000, 0b0100_0000);
+        assert_eq!(prg(&mapper), vec![6, 4, 3, 7]);
+
+        // R0 is a 2KB bank at 0x0000, R2 a 1KB bank at 0x1000
+        mapper.cpu_write(0x8000, 0);
+        mapper.cpu_write(0x8001, 2);
+        mapper.cpu_write(0x8000, 2);
+        mapper.cpu_write(0x8001, 5);
+        assert_eq!((mapper.ppu_read(0x0000), mapper.ppu_read(0x0400), mapper.ppu_read(0x1000)), (2, 3, 5));
+        // bit 7 swaps the halves
+        mapper.cpu_write(0x8000, 0b1000_0000);
+        assert_eq!((mapper.ppu_read(0x1000), mapper.ppu_read(0x0000)), (2, 5));
+
+        mapper.cpu_write(0xA000, 1);
+        assert_eq!(mapper.mirroring(), Some(Mirroring::Horizontal));
+    }
+
+    #[test]
+    fn test_scanline_irq() {
+        let mut mapper = test_mmc3();
+        mapper.cpu_write(0xC000, 2);
+        mapper.cpu_write(0xC001, 0);
+        mapper.cpu_write(0xE001, 0);
+
+        // reload to 2, then 1, then 0 raises the line
+        mapper.clock_scanline();
+        mapper.clock_scanline();
+        assert!(!mapper.irq_pending());
+        mapper.clock_scanline();
+        assert!(mapper.irq_pending());
+
+        // 0xE000 acknowledges and disables
+        mapper.cpu_write(0xE000, 0);
+        assert!(!mapper.irq_pending());
+        for _ in 0..3 {
+            mapper.clock_scanline();
+        }
+        assert!(!mapper.irq_pending());
+    }
+}
@@ -0,0 +1,156 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+use crate::cartridge::{Mirroring, Rom};
+
+mod nrom;
+mod mmc1;
+mod uxrom;
+mod cnrom;
+mod mmc3;
+
+pub use self::nrom::Nrom;
+pub use self::mmc1::Mmc1;
+pub use self::uxrom::Uxrom;
+pub use self::cnrom::Cnrom;
+pub use self::mmc3::Mmc3;
+
+pub const PRG_BANK_SIZE: usize = 0x4000;
+const CHR_RAM_SIZE: usize = 0x2000;
+
+// The cartridge board: decides which PRG byte the CPU sees at 0x8000-0xFFFF
+// and which CHR byte the PPU sees at 0x0000-0x1FFF, and what writes there do
+pub trait Mapper {
+    fn cpu_read(&self, addr: u16) -> u8;
+
+    fn cpu_write(&mut self, addr: u16, data: u8);
+
+    fn ppu_read(&self, addr: u16) -> u8;
+
+    fn ppu_write(&mut self, addr: u16, data: u8);
+
+    // Loader access: overwrites the PRG ROM byte currently mapped at addr
+    fn patch_prg(&mut self, addr: u16, data: u8);
+
+    // For boards that control nametable mirroring, None keeps the header's
+    fn mirroring(&self) -> Option<Mirroring> {
+        None
+    }
+
+    // Called by the PPU once per rendered scanline, where the MMC3 sees PPU
+    // A12 rise
+    fn clock_scanline(&mut self) {}
+
+    // Called by the bus with the CPU cycles just spent, for boards whose IRQ
+    // counts CPU cycles rather than scanlines
+    fn cpu_clock(&mut self, _cycles: u16) {}
+
+    // Level of the cartridge IRQ line, see Bus::irq_asserted
+    fn irq_pending(&self) -> bool {
+        false
+    }
+
+    // Releases the IRQ line. Boards call this from whatever register write
+    // acknowledges their IRQ.
+    fn clear_irq(&mut self) {}
+
+    // Everything a save state needs to put the board back as it was: bank and
+    // shift registers, IRQ counters, then the CHR RAM. Not the ROM.
+    fn save_state(&self) -> Vec<u8>;
+
+    fn load_state(&mut self, state: &[u8]) -> Result<(), String>;
+}
+
+// Shared by the bus (PRG side) and the PPU (CHR side)
+pub type SharedMapper = Rc<RefCell<dyn Mapper>>;
+
+pub fn is_supported(mapper: u16) -> bool {
+    matches!(mapper, 0 ..= 4)
+}
+
+// Rom::new rejects the mapper numbers this can't handle
+pub fn from_rom(rom: Rom) -> SharedMapper {
+    let chr = Chr::new(rom.chr_rom, rom.chr_ram_size);
+    match rom.mapper {
+        0 => Rc::new(RefCell::new(Nrom::new(rom.prg_rom, chr))),
+        1 => Rc::new(RefCell::new(Mmc1::new(rom.prg_rom, chr))),
+        2 => Rc::new(RefCell::new(Uxrom::new(rom.prg_rom, chr))),
+        3 => Rc::new(RefCell::new(Cnrom::new(rom.prg_rom, chr))),
+        4 => Rc::new(RefCell::new(Mmc3::new(rom.prg_rom, chr, rom.screen_mirroring))),
+        n => panic!("Mapper {} is not supported", n),
+    }
+}
+
+// Splits a save state into the first registers bytes and the CHR RAM after them
+fn split_state(state: &[u8], registers: usize) -> Result<(&[u8], &[u8]), String> {
+    if state.len() < registers {
+        return Err(format!("mapper state is {} bytes, the registers alone are {}", state.len(), registers));
+    }
+    Ok(state.split_at(registers))
+}
+
+// Pattern memory: the cartridge's CHR ROM, or CHR RAM when it has none
+pub struct Chr {
+    data: Vec<u8>,
+    is_ram: bool,
+}
+
+impl Chr {
+    // ram_size is only used without CHR ROM, 8KB when the header gives none
+    pub fn new(chr_rom: Vec<u8>, ram_size: usize) -> Self {
+        if chr_rom.is_empty() {
+            let size = if ram_size == 0 { CHR_RAM_SIZE } else { ram_size };
+            Chr { data: vec![0; size], is_ram: true }
+        } else {
+            Chr { data: chr_rom, is_ram: false }
+        }
+    }
+
+    pub fn read(&self, index: usize) -> u8 {
+        self.data[index % self.data.len()]
+    }
+
+    // Writes to CHR ROM are ignored
+    pub fn write(&mut self, index: usize, data: u8) {
+        if self.is_ram {
+            let len = self.data.len();
+            self.data[index % len] = data;
+        }
+    }
+
+    // What a save state keeps: the contents of CHR RAM, nothing for CHR ROM
+    pub fn ram(&self) -> &[u8] {
+        if self.is_ram { &self.data } else { &[] }
+    }
+
+    pub fn restore_ram(&mut self, ram: &[u8]) -> Result<(), String> {
+        if ram.len() != self.ram().len() {
+            return Err(format!("{} bytes of CHR RAM in the state, the cartridge has {}", ram.len(), self.ram().len()));
+        }
+        if self.is_ram {
+            self.data.copy_from_slice(ram);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::test::test_rom;
+
+    #[test]
+    fn test_state_round_trip() {
+        for number in 0..=4 {
+            let mapper = from_rom(test_rom(number, &[0; 0x8000], &[]));
+            let mut mapper = mapper.borrow_mut();
+            mapper.ppu_write(0x0010, 0x5A);
+            let state = mapper.save_state();
+            mapper.ppu_write(0x0010, 0);
+
+            mapper.load_state(&state).unwrap();
+            assert_eq!(mapper.ppu_read(0x0010), 0x5A, "mapper {}", number);
+            assert_eq!(mapper.save_state(), state, "mapper {}", number);
+            assert!(mapper.load_state(&state[1..]).is_err(), "mapper {}", number);
+        }
+    }
+}
@@ -0,0 +1,72 @@
+use super::{split_state, Chr, Mapper};
+
+// Mapper 0: 16KB or 32KB of PRG, 8KB of CHR, no registers
+pub struct Nrom {
+    prg_rom: Vec<u8>,
+    chr: Chr,
+}
+
+impl Nrom {
+    pub fn new(prg_rom: Vec<u8>, chr: Chr) -> Self {
+        Nrom {
+            prg_rom,
+            chr,
+        }
+    }
+
+    // a 16KB ROM is mirrored at 0xC000
+    fn prg_index(&self, addr: u16) -> usize {
+        (addr - 0x8000) as usize % self.prg_rom.len()
+    }
+}
+
+impl Mapper for Nrom {
+    fn cpu_read(&self, addr: u16) -> u8 {
+        self.prg_rom[self.prg_index(addr)]
+    }
+
+    fn cpu_write(&mut self, _addr: u16, _data: u8) {}
+
+    fn ppu_read(&self, addr: u16) -> u8 {
+        self.chr.read(addr as usize)
+    }
+
+    fn ppu_write(&mut self, addr: u16, data: u8) {
+        self.chr.write(addr as usize, data);
+    }
+
+    fn patch_prg(&mut self, addr: u16, data: u8) {
+        let index = self.prg_index(addr);
+        self.prg_rom[index] = data;
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        self.chr.ram().to_vec()
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> Result<(), String> {
+        let (_, chr_ram) = split_state(state, 0)?;
+        self.chr.restore_ram(chr_ram)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prg_mirroring() {
+        let mut prg_rom = vec![0; 0x4000];
+        prg_rom[0] = 0x11;
+        prg_rom[0x3FFF] = 0x22;
+        let nrom = Nrom::new(prg_rom, Chr::new(vec![], 0));
+        assert_eq!((nrom.cpu_read(0x8000), nrom.cpu_read(0xC000)), (0x11, 0x11));
+        assert_eq!((nrom.cpu_read(0xBFFF), nrom.cpu_read(0xFFFF)), (0x22, 0x22));
+
+        // 32KB fills the whole window
+        let mut prg_rom = vec![0; 0x8000];
+        prg_rom[0x4000] = 0x33;
+        let nrom = Nrom::new(prg_rom, Chr::new(vec![], 0));
+        assert_eq!((nrom.cpu_read(0x8000), nrom.cpu_read(0xC000)), (0, 0x33));
+    }
+}
@@ -0,0 +1,68 @@
+use super::{split_state, Chr, Mapper, PRG_BANK_SIZE};
+
+// Mapper 2: a switchable 16KB bank at 0x8000, the last bank fixed at 0xC000
+pub struct Uxrom {
+    prg_rom: Vec<u8>,
+    chr: Chr,
+    prg_bank: usize,
+}
+
+impl Uxrom {
+    pub fn new(prg_rom: Vec<u8>, chr: Chr) -> Self {
+        Uxrom {
+            prg_rom,
+            chr,
+            prg_bank: 0,
+        }
+    }
+
+    fn prg_banks(&self) -> usize {
+        self.prg_rom.len() / PRG_BANK_SIZE
+    }
+
+    fn prg_index(&self, addr: u16) -> usize {
+        let bank = if addr < 0xC000 {
+            self.prg_bank
+        } else {
+            self.prg_banks() - 1
+        };
+        bank * PRG_BANK_SIZE + (addr as usize % PRG_BANK_SIZE)
+    }
+}
+
+impl Mapper for Uxrom {
+    fn cpu_read(&self, addr: u16) -> u8 {
+        self.prg_rom[self.prg_index(addr)]
+    }
+
+    // any write selects the bank at 0x8000
+    fn cpu_write(&mut self, _addr: u16, data: u8) {
+        self.prg_bank = data as usize % self.prg_banks();
+    }
+
+    fn ppu_read(&self, addr: u16) -> u8 {
+        self.chr.read(addr as usize)
+    }
+
+    fn ppu_write(&mut self, addr: u16, data: u8) {
+        self.chr.write(addr as usize, data);
+    }
+
+    fn patch_prg(&mut self, addr: u16, data: u8) {
+        let index = self.prg_index(addr);
+        self.prg_rom[index] = data;
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        let mut state = vec![self.prg_bank as u8];
+        state.extend_from_slice(self.chr.ram());
+        state
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> Result<(), String> {
+        let (registers, chr_ram) = split_state(state, 1)?;
+        self.chr.restore_ram(chr_ram)?;
+        self.prg_bank = registers[0] as usize % self.prg_banks();
+        Ok(())
+    }
+}
@@ -0,0 +1,144 @@
+use crate::joypad::JoypadButton;
+use std::path::Path;
+
+// Movies are the tag, then one byte per frame: controller 1's buttons in
+// shift register order, as JoypadButton stores them
+const MOVIE_TAG: [u8; 4] = [b'V', b'R', b'M', 0x1A];
+
+// Collects the buttons held on each frame
+#[derive(Debug, Default)]
+pub struct InputRecorder {
+    frames: Vec<u8>,
+}
+
+impl InputRecorder {
+    pub fn new() -> Self {
+        InputRecorder::default()
+    }
+
+    // Once per frame, in frame order
+    pub fn record_frame(&mut self, buttons: JoypadButton) {
+        self.frames.push(buttons.bits());
+    }
+
+    pub fn frame_count(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = MOVIE_TAG.to_vec();
+        bytes.extend_from_slice(&self.frames);
+        bytes
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), String> {
+        let path = path.as_ref();
+        std::fs::write(path, self.to_bytes()).map_err(|e| format!("Can't write {}: {}", path.display(), e))
+    }
+}
+
+// Hands a recording back one frame at a time
+#[derive(Debug)]
+pub struct InputPlayer {
+    frames: Vec<u8>,
+    next: usize,
+}
+
+impl InputPlayer {
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        if !bytes.starts_with(&MOVIE_TAG) {
+            return Err("Not an input movie".to_string());
+        }
+        Ok(InputPlayer { frames: bytes[MOVIE_TAG.len()..].to_vec(), next: 0 })
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, String> {
+        let path = path.as_ref();
+        let bytes = std::fs::read(path).map_err(|e| format!("Can't read {}: {}", path.display(), e))?;
+        InputPlayer::from_bytes(&bytes)
+    }
+
+    // None once the recording is over
+    pub fn next_frame(&mut self) -> Option<JoypadButton> {
+        let bits = *self.frames.get(self.next)?;
+        self.next += 1;
+        Some(JoypadButton::from_bits_truncate(bits))
+    }
+
+    pub fn frames_left(&self) -> usize {
+        self.frames.len() - self.next
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::CPU;
+    use crate::selftest::scratch_cpu;
+
+    // Strobes the pad and adds up the eight button bits into $10, forever
+    const POLL_LOOP: [u8; 26] = [
+        0xa9, 0x01, 0x8d, 0x16, 0x40, // LDA #1; STA $4016
+        0xa9, 0x00, 0x8d, 0x16, 0x40, // LDA #0; STA $4016
+        0xa2, 0x08,                   // LDX #8
+        0xad, 0x16, 0x40,             // LDA $4016
+        0x18, 0x65, 0x10, 0x85, 0x10, // CLC; ADC $10; STA $10
+        0xca, 0xd0, 0xf4,             // DEX; BNE -12
+        0x4c, 0x00, 0x80,             // JMP $8000
+    ];
+
+    fn run_frame(cpu: &mut CPU) {
+        let frame = cpu.bus.ppu().frame_count();
+        while cpu.bus.ppu().frame_count() == frame {
+            cpu.step().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_replay_matches_recording() {
+        let inputs = [
+            JoypadButton::empty(),
+            JoypadButton::BUTTON_A,
+            JoypadButton::BUTTON_A | JoypadButton::RIGHT,
+            JoypadButton::START,
+            JoypadButton::empty(),
+            JoypadButton::all(),
+        ];
+
+        let mut original = scratch_cpu(&POLL_LOOP);
+        let mut recorder = InputRecorder::new();
+        for &buttons in inputs.iter() {
+            original.bus.set_buttons(buttons);
+            recorder.record_frame(original.bus.buttons());
+            run_frame(&mut original);
+        }
+        assert_eq!(recorder.frame_count(), inputs.len());
+
+        let path = std::env::temp_dir().join(format!("vrnes-movie-{}.bin", std::process::id()));
+        recorder.save(&path).unwrap();
+        let mut player = InputPlayer::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let mut replay = scratch_cpu(&POLL_LOOP);
+        while let Some(buttons) = player.next_frame() {
+            replay.bus.set_buttons(buttons);
+            run_frame(&mut replay);
+        }
+        assert_eq!(player.frames_left(), 0);
+        assert_eq!(replay.state_hash(), original.state_hash());
+
+        // and the inputs did make it into the state
+        let mut idle = scratch_cpu(&POLL_LOOP);
+        for _ in inputs.iter() {
+            run_frame(&mut idle);
+        }
+        assert_ne!(idle.state_hash(), original.state_hash());
+    }
+
+    #[test]
+    fn test_player_rejects_foreign_data() {
+        assert!(InputPlayer::from_bytes(b"NES\x1a").is_err());
+        let mut player = InputPlayer::from_bytes(&InputRecorder::new().to_bytes()).unwrap();
+        assert_eq!(player.next_frame(), None);
+    }
+}
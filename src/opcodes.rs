@@ -44,7 +44,7 @@ lazy_static! {
         OpCode::new(0xa8, "TAY", 1, 2, AddressingMode::NoneAddressing),
         OpCode::new(0xba, "TSX", 1, 2, AddressingMode::NoneAddressing),
         OpCode::new(0x8a, "TXA", 1, 2, AddressingMode::NoneAddressing),
-        OpCode::new(0x9a, "TSX", 1, 2, AddressingMode::NoneAddressing),
+        OpCode::new(0x9a, "TXS", 1, 2, AddressingMode::NoneAddressing),
         OpCode::new(0x98, "TYA", 1, 2, AddressingMode::NoneAddressing),
 
         /* Inc */
@@ -109,7 +109,7 @@ lazy_static! {
         OpCode::new(0x29, "AND", 2, 2, AddressingMode::Immediate),
         OpCode::new(0x25, "AND", 2, 3, AddressingMode::ZeroPage),
         OpCode::new(0x35, "AND", 2, 4, AddressingMode::ZeroPage_X),
-        OpCode::new(0x2d, "AND", 2, 4, AddressingMode::Absolute),
+        OpCode::new(0x2d, "AND", 3, 4, AddressingMode::Absolute),
         OpCode::new(0x3d, "AND", 3, 4, AddressingMode::Absolute_X),
         OpCode::new(0x39, "AND", 3, 4, AddressingMode::Absolute_Y),
         OpCode::new(0x21, "AND", 2, 6, AddressingMode::Indirect_X),
@@ -138,25 +138,25 @@ lazy_static! {
 
         /* Shift */
 
-        OpCode::new(0x0a, "ASL", 1, 2, AddressingMode::NoneAddressing),
+        OpCode::new(0x0a, "ASL", 1, 2, AddressingMode::Accumulator),
         OpCode::new(0x06, "ASL", 2, 5, AddressingMode::ZeroPage),
         OpCode::new(0x16, "ASL", 2, 6, AddressingMode::ZeroPage_X),
         OpCode::new(0x0e, "ASL", 3, 6, AddressingMode::Absolute),
         OpCode::new(0x1e, "ASL", 3, 7, AddressingMode::Absolute_X),
 
-        OpCode::new(0x4a, "LSR", 1, 2, AddressingMode::NoneAddressing),
+        OpCode::new(0x4a, "LSR", 1, 2, AddressingMode::Accumulator),
         OpCode::new(0x4e, "LSR", 3, 6, AddressingMode::Absolute),
         OpCode::new(0x5e, "LSR", 3, 7, AddressingMode::Absolute_X),
         OpCode::new(0x46, "LSR", 2, 5, AddressingMode::ZeroPage),
         OpCode::new(0x56, "LSR", 2, 6, AddressingMode::ZeroPage_X),
 
-        OpCode::new(0x2a, "ROL", 1, 2, AddressingMode::NoneAddressing),
+        OpCode::new(0x2a, "ROL", 1, 2, AddressingMode::Accumulator),
         OpCode::new(0x2e, "ROL", 3, 6, AddressingMode::Absolute),
         OpCode::new(0x3e, "ROL", 3, 7, AddressingMode::Absolute_X),
         OpCode::new(0x26, "ROL", 2, 5, AddressingMode::ZeroPage),
         OpCode::new(0x36, "ROL", 2, 6, AddressingMode::ZeroPage_X),
 
-        OpCode::new(0x6a, "ROR", 1, 2, AddressingMode::NoneAddressing),
+        OpCode::new(0x6a, "ROR", 1, 2, AddressingMode::Accumulator),
         OpCode::new(0x6e, "ROR", 3, 6, AddressingMode::Absolute),
         OpCode::new(0x7e, "ROR", 3, 7, AddressingMode::Absolute_X),
         OpCode::new(0x66, "ROR", 2, 5, AddressingMode::ZeroPage),
@@ -234,6 +234,19 @@ lazy_static! {
 
         /* Unoficial */
 
+        OpCode::new(0x02, "*JAM", 1, 2, AddressingMode::NoneAddressing),
+        OpCode::new(0x12, "*JAM", 1, 2, AddressingMode::NoneAddressing),
+        OpCode::new(0x22, "*JAM", 1, 2, AddressingMode::NoneAddressing),
+        OpCode::new(0x32, "*JAM", 1, 2, AddressingMode::NoneAddressing),
+        OpCode::new(0x42, "*JAM", 1, 2, AddressingMode::NoneAddressing),
+        OpCode::new(0x52, "*JAM", 1, 2, AddressingMode::NoneAddressing),
+        OpCode::new(0x62, "*JAM", 1, 2, AddressingMode::NoneAddressing),
+        OpCode::new(0x72, "*JAM", 1, 2, AddressingMode::NoneAddressing),
+        OpCode::new(0x92, "*JAM", 1, 2, AddressingMode::NoneAddressing),
+        OpCode::new(0xb2, "*JAM", 1, 2, AddressingMode::NoneAddressing),
+        OpCode::new(0xd2, "*JAM", 1, 2, AddressingMode::NoneAddressing),
+        OpCode::new(0xf2, "*JAM", 1, 2, AddressingMode::NoneAddressing),
+
         OpCode::new(0x0b, "*ANC", 2, 2, AddressingMode::Immediate),
         OpCode::new(0x2b, "*ANC", 2, 2, AddressingMode::Immediate),
 
@@ -248,12 +261,48 @@ lazy_static! {
 
         OpCode::new(0xab, "*LXA", 2, 2, AddressingMode::Immediate),
 
+        OpCode::new(0xa7, "*LAX", 2, 3, AddressingMode::ZeroPage),
+        OpCode::new(0xb7, "*LAX", 2, 4, AddressingMode::ZeroPage_Y),
+        OpCode::new(0xaf, "*LAX", 3, 4, AddressingMode::Absolute),
+        OpCode::new(0xbf, "*LAX", 3, 4, AddressingMode::Absolute_Y),
+        OpCode::new(0xa3, "*LAX", 2, 6, AddressingMode::Indirect_X),
+        OpCode::new(0xb3, "*LAX", 2, 5, AddressingMode::Indirect_Y),
+
         OpCode::new(0x9f, "*SHA", 3, 5, AddressingMode::Absolute_Y),
         OpCode::new(0x93, "*SHA", 2, 6, AddressingMode::Indirect_Y),
 
+        OpCode::new(0x1a, "*NOP", 1, 2, AddressingMode::NoneAddressing),
+        OpCode::new(0x3a, "*NOP", 1, 2, AddressingMode::NoneAddressing),
+        OpCode::new(0x5a, "*NOP", 1, 2, AddressingMode::NoneAddressing),
+        OpCode::new(0x7a, "*NOP", 1, 2, AddressingMode::NoneAddressing),
+        OpCode::new(0xda, "*NOP", 1, 2, AddressingMode::NoneAddressing),
+        OpCode::new(0xfa, "*NOP", 1, 2, AddressingMode::NoneAddressing),
+        OpCode::new(0x80, "*NOP", 2, 2, AddressingMode::Immediate),
+        OpCode::new(0x82, "*NOP", 2, 2, AddressingMode::Immediate),
+        OpCode::new(0x89, "*NOP", 2, 2, AddressingMode::Immediate),
+        OpCode::new(0xc2, "*NOP", 2, 2, AddressingMode::Immediate),
+        OpCode::new(0xe2, "*NOP", 2, 2, AddressingMode::Immediate),
+        OpCode::new(0x04, "*NOP", 2, 3, AddressingMode::ZeroPage),
+        OpCode::new(0x44, "*NOP", 2, 3, AddressingMode::ZeroPage),
+        OpCode::new(0x64, "*NOP", 2, 3, AddressingMode::ZeroPage),
+        OpCode::new(0x14, "*NOP", 2, 4, AddressingMode::ZeroPage_X),
+        OpCode::new(0x34, "*NOP", 2, 4, AddressingMode::ZeroPage_X),
+        OpCode::new(0x54, "*NOP", 2, 4, AddressingMode::ZeroPage_X),
+        OpCode::new(0x74, "*NOP", 2, 4, AddressingMode::ZeroPage_X),
+        OpCode::new(0xd4, "*NOP", 2, 4, AddressingMode::ZeroPage_X),
+        OpCode::new(0xf4, "*NOP", 2, 4, AddressingMode::ZeroPage_X),
+        OpCode::new(0x0c, "*NOP", 3, 4, AddressingMode::Absolute),
+        OpCode::new(0x1c, "*NOP", 3, 4, AddressingMode::Absolute_X),
+        OpCode::new(0x3c, "*NOP", 3, 4, AddressingMode::Absolute_X),
+        OpCode::new(0x5c, "*NOP", 3, 4, AddressingMode::Absolute_X),
+        OpCode::new(0x7c, "*NOP", 3, 4, AddressingMode::Absolute_X),
+        OpCode::new(0xdc, "*NOP", 3, 4, AddressingMode::Absolute_X),
+        OpCode::new(0xfc, "*NOP", 3, 4, AddressingMode::Absolute_X),
+
     ];
 
 
+    // Kept for external users; the CPU and disassembler index OPCODES_ARRAY
     pub static ref OPCODES_MAP: HashMap<u8, &'static OpCode> = {
         let mut map = HashMap::new();
         for cpuop in &*OPS_CODES {
@@ -261,4 +310,120 @@ lazy_static! {
         }
         map
     };
-}
\ No newline at end of file
+
+    pub static ref OPCODES_ARRAY: [Option<&'static OpCode>; 256] = {
+        let mut table = [None; 256];
+        for cpuop in &*OPS_CODES {
+            table[cpuop.code as usize] = Some(cpuop);
+        }
+        table
+    };
+}
+
+// Bytes CPU::execute_next has a handler for, in the order of its match:
+// the official set, the unofficial NOPs and LAX, plus the jam opcodes whose
+// handler locks up the CPU (see JamPolicy). Anything else is
+// CpuError::Unimplemented.
+const HANDLED: &[u8] = &[
+    /* Arith */
+    0x69, 0x65, 0x75, 0x6d, 0x7d, 0x79, 0x61, 0x71, 0xc9, 0xcd, 0xdd, 0xd9,
+    0xc5, 0xd5, 0xc1, 0xd1, 0xe0, 0xec, 0xe4, 0xc0, 0xcc, 0xc4, 0xe9, 0xed,
+    0xfd, 0xf9, 0xe5, 0xf5, 0xe1, 0xf1,
+    /* Stack */
+    0x48, 0x08, 0x68, 0x28,
+    /* Logic */
+    0x29, 0x25, 0x35, 0x2d, 0x3d, 0x39, 0x21, 0x31, 0x24, 0x2c, 0x49, 0x4d,
+    0x5d, 0x59, 0x45, 0x55, 0x41, 0x51, 0x09, 0x0d, 0x1d, 0x19, 0x05, 0x15,
+    0x01, 0x11,
+    /* Shift */
+    0x0a, 0x4a, 0x2a, 0x6a, 0x06, 0x16, 0x0e, 0x1e, 0x4e, 0x5e, 0x46, 0x56,
+    0x2e, 0x3e, 0x26, 0x36, 0x6e, 0x7e, 0x66, 0x76,
+    /* Load */
+    0xa9, 0xa5, 0xb5, 0xad, 0xbd, 0xb9, 0xa1, 0xb1, 0xa2, 0xae, 0xbe, 0xa6,
+    0xb6, 0xa0, 0xac, 0xbc, 0xa4, 0xb4, 0x85, 0x95, 0x8d, 0x9d, 0x99, 0x81,
+    0x91, 0x8e, 0x86, 0x96, 0x8c, 0x84, 0x94,
+    /* Branch */
+    0x90, 0xb0, 0xf0, 0x30, 0xd0, 0x10, 0x50, 0x70,
+    /* Flags */
+    0x18, 0xd8, 0x58, 0xb8, 0x38, 0xf8, 0x78,
+    /* Trans */
+    0xaa, 0xa8, 0xba, 0x8a, 0x9a, 0x98,
+    /* Inc */
+    0xce, 0xde, 0xc6, 0xd6, 0xee, 0xfe, 0xe6, 0xf6, 0xca, 0x88, 0xe8, 0xc8,
+    /* Ctrl */
+    0x00, 0x4c, 0x6c, 0x20, 0x40, 0x60,
+    /* NOP */
+    0xea,
+    /* Unofficial NOP */
+    0x1a, 0x3a, 0x5a, 0x7a, 0xda, 0xfa, 0x80, 0x82, 0x89, 0xc2, 0xe2, 0x04,
+    0x44, 0x64, 0x14, 0x34, 0x54, 0x74, 0xd4, 0xf4, 0x0c, 0x1c, 0x3c, 0x5c,
+    0x7c, 0xdc, 0xfc,
+    /* Unofficial load */
+    0xa7, 0xb7, 0xaf, 0xbf, 0xa3, 0xb3,
+    /* Jam */
+    0x02, 0x12, 0x22, 0x32, 0x42, 0x52, 0x62, 0x72, 0x92, 0xb2, 0xd2, 0xf2,
+];
+
+lazy_static! {
+    static ref HANDLED_ARRAY: [bool; 256] = {
+        let mut table = [false; 256];
+        for code in HANDLED {
+            table[*code as usize] = true;
+        }
+        table
+    };
+}
+
+pub fn is_handled(code: u8) -> bool {
+    HANDLED_ARRAY[code as usize]
+}
+
+// Opcode bytes the CPU can't execute yet: either missing from OPS_CODES
+// or listed there without a handler
+pub fn unimplemented_opcodes() -> Vec<u8> {
+    (0..=0xffu8).filter(|code| !is_handled(*code)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::selftest;
+
+    #[test]
+    fn test_array_matches_map() {
+        assert_eq!(OPCODES_MAP.len(), OPS_CODES.len());
+        for (code, op) in OPCODES_MAP.iter() {
+            let entry = OPCODES_ARRAY[*code as usize].expect("missing from the array");
+            assert!(std::ptr::eq(entry, *op), "{:02x}", code);
+            assert_eq!(
+                (entry.code, entry.mnemonic, entry.len, entry.cycles, entry.mode),
+                (op.code, op.mnemonic, op.len, op.cycles, op.mode)
+            );
+        }
+        // and nothing else
+        let listed = OPCODES_ARRAY.iter().filter(|op| op.is_some()).count();
+        assert_eq!(listed, OPCODES_MAP.len());
+    }
+
+    #[test]
+    fn test_unimplemented_opcodes() {
+        let missing = unimplemented_opcodes();
+
+        // the whole official set is there, only unofficial opcodes are left
+        for op in OPS_CODES.iter() {
+            let handled = !op.mnemonic.starts_with('*') || op.mnemonic == "*JAM" || op.mnemonic == "*NOP" || op.mnemonic == "*LAX";
+            assert_eq!(missing.contains(&op.code), !handled, "{:02x} {}", op.code, op.mnemonic);
+        }
+        // not in the table at all
+        assert!(missing.contains(&0x07));
+        assert!(missing.contains(&0xff));
+        assert_eq!(missing.len(), 256 - HANDLED.len());
+    }
+
+    #[test]
+    fn test_handled_list_matches_dispatch() {
+        for code in 0..=0xffu8 {
+            assert_eq!(is_handled(code), selftest::has_handler(code), "opcode {:02x}", code);
+        }
+    }
+}
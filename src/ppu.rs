@@ -0,0 +1,1419 @@
+use crate::cartridge::Mirroring;
+use crate::frame::{Frame, Rect};
+use crate::mapper::SharedMapper;
+use crate::palette;
+
+const PALETTE: u16 = 0x3F00;
+const DOTS_PER_SCANLINE: u16 = 341;
+const SCANLINES_PER_FRAME: u16 = 262;
+const VBLANK_SCANLINE: u16 = 241;
+const PRE_RENDER_SCANLINE: u16 = 261;
+// vram, OAM, palette, 10 byte-sized registers, 3 u16 ones
+const STATE_LEN: usize = 0x1000 + 256 + 32 + 10 + 6;
+pub const SCREEN_WIDTH: usize = 256;
+pub const SCREEN_HEIGHT: usize = 240;
+// both pattern tables, 16x16 tiles each, one above the other
+pub const PATTERN_SHEET_WIDTH: usize = 128;
+pub const PATTERN_SHEET_HEIGHT: usize = 256;
+
+bitflags! {
+
+    // 0x2000, write only
+    pub struct ControlRegister: u8 {
+        const NAMETABLE1 = 0b00000001;
+        const NAMETABLE2 = 0b00000010;
+        const VRAM_ADD_INCREMENT = 0b00000100;
+        const SPRITE_PATTERN_ADDR = 0b00001000;
+        const BACKGROUND_PATTERN_ADDR = 0b00010000;
+        const SPRITE_SIZE = 0b00100000;
+        const MASTER_SLAVE_SELECT = 0b01000000;
+        const GENERATE_NMI = 0b10000000;
+    }
+
+}
+
+bitflags! {
+
+    // 0x2001, write only
+    pub struct MaskRegister: u8 {
+        const GREYSCALE = 0b00000001;
+        const LEFTMOST_8PXL_BACKGROUND = 0b00000010;
+        const LEFTMOST_8PXL_SPRITE = 0b00000100;
+        const SHOW_BACKGROUND = 0b00001000;
+        const SHOW_SPRITES = 0b00010000;
+        const EMPHASISE_RED = 0b00100000;
+        const EMPHASISE_GREEN = 0b01000000;
+        const EMPHASISE_BLUE = 0b10000000;
+    }
+
+}
+
+bitflags! {
+
+    // 0x2002, read only. The low 5 bits are open bus.
+    pub struct StatusRegister: u8 {
+        const SPRITE_OVERFLOW = 0b00100000;
+        const SPRITE_ZERO_HIT = 0b01000000;
+        const VBLANK_STARTED = 0b10000000;
+    }
+
+}
+
+impl ControlRegister {
+    pub fn vram_addr_increment(&self) -> u16 {
+        if self.contains(ControlRegister::VRAM_ADD_INCREMENT) {
+            32
+        } else {
+            1
+        }
+    }
+
+    pub fn sprite_height(&self) -> u16 {
+        if self.contains(ControlRegister::SPRITE_SIZE) {
+            16
+        } else {
+            8
+        }
+    }
+}
+
+pub struct NesPPU {
+    // pattern tables live on the cartridge
+    mapper: SharedMapper,
+    pub palette_table: [u8; 32],
+    // 2KB on the console, four-screen boards add the other 2KB
+    pub vram: [u8; 0x1000],
+    pub oam_addr: u8,
+    pub oam_data: [u8; 256],
+    pub mirroring: Mirroring,
+
+    pub ctrl: ControlRegister,
+    pub mask: MaskRegister,
+    pub status: StatusRegister,
+    pub scroll_x: u8,
+    pub scroll_y: u8,
+    // VRAM address set through 0x2006, 14 bits
+    addr: u16,
+    // first/second write toggle shared by 0x2005 and 0x2006
+    write_latch: bool,
+    // 0x2007 reads below the palette return the previous fetch
+    internal_data_buf: u8,
+    // last value put on the PPU data bus, what write-only registers read as
+    open_bus: u8,
+    // what render() returns
+    frame: Frame,
+    // drawn by the beam, see screen()
+    screen: Frame,
+    // scroll origin the current line is drawn from, see tick_dot
+    line_origin: (usize, usize),
+    // the current line's sprites, secondary_count entries of 4 bytes
+    secondary_oam: [u8; 32],
+    secondary_count: usize,
+    // position of the beam: 0-239 visible, 241-260 vblank, 261 pre-render
+    scanline: u16,
+    dot: u16,
+    // odd frames are a dot shorter while rendering
+    odd_frame: bool,
+    // frames completed since power on or the last reset_frame_count
+    frame_count: u64,
+}
+
+impl NesPPU {
+    pub fn new(mapper: SharedMapper, mirroring: Mirroring) -> Self {
+        NesPPU {
+            mapper,
+            palette_table: [0; 32],
+            vram: [0; 0x1000],
+            oam_addr: 0,
+            oam_data: [0; 256],
+            mirroring,
+            ctrl: ControlRegister::empty(),
+            mask: MaskRegister::empty(),
+            status: StatusRegister::empty(),
+            scroll_x: 0,
+            scroll_y: 0,
+            addr: 0,
+            write_latch: false,
+            internal_data_buf: 0,
+            open_bus: 0,
+            frame: Frame::new(SCREEN_WIDTH, SCREEN_HEIGHT),
+            screen: Frame::new(SCREEN_WIDTH, SCREEN_HEIGHT),
+            line_origin: (0, 0),
+            secondary_oam: [0xFF; 32],
+            secondary_count: 0,
+            scanline: 0,
+            dot: 0,
+            odd_frame: false,
+            frame_count: 0,
+        }
+    }
+
+    pub fn vram_addr(&self) -> u16 {
+        self.addr
+    }
+
+    pub fn open_bus(&self) -> u8 {
+        self.open_bus
+    }
+
+    pub fn scanline(&self) -> u16 {
+        self.scanline
+    }
+
+    pub fn dot(&self) -> u16 {
+        self.dot
+    }
+
+    pub fn frame_count(&self) -> u64 {
+        self.frame_count
+    }
+
+    pub fn reset_frame_count(&mut self) {
+        self.frame_count = 0;
+    }
+
+    // Background or sprites enabled through PPUMASK
+    pub fn is_rendering_enabled(&self) -> bool {
+        self.mask.intersects(MaskRegister::SHOW_BACKGROUND | MaskRegister::SHOW_SPRITES)
+    }
+
+    // Advances the beam. Returns true if vblank started with NMI enabled.
+    pub fn tick(&mut self, dots: usize) -> bool {
+        let mut nmi = false;
+        for _ in 0..dots {
+            nmi |= self.tick_dot();
+        }
+        nmi
+    }
+
+    // Ticks forward to (scanline, dot) within the current frame, for setting
+    // up tests at an exact raster position
+    pub fn advance_to_scanline(&mut self, scanline: u16, dot: u16) -> Result<(), String> {
+        if scanline >= SCANLINES_PER_FRAME || dot >= DOTS_PER_SCANLINE {
+            return Err(format!("no such position: scanline {} dot {}", scanline, dot));
+        }
+        if (scanline, dot) < (self.scanline, self.dot) {
+            return Err(format!(
+                "already past scanline {} dot {} (at scanline {} dot {})",
+                scanline, dot, self.scanline, self.dot
+            ));
+        }
+        while (self.scanline, self.dot) != (scanline, dot) {
+            self.tick_dot();
+            if (self.scanline, self.dot) == (0, 0) {
+                return Err(format!("scanline {} dot {} was skipped this frame", scanline, dot));
+            }
+        }
+        Ok(())
+    }
+
+    fn tick_dot(&mut self) -> bool {
+        let rendering = self.is_rendering_enabled();
+        self.dot += 1;
+        // odd frames skip the last dot of the pre-render line
+        if self.scanline == PRE_RENDER_SCANLINE && self.dot == DOTS_PER_SCANLINE - 1 && self.odd_frame && rendering {
+            self.dot += 1;
+        }
+        if self.dot == DOTS_PER_SCANLINE {
+            self.dot = 0;
+            self.scanline = (self.scanline + 1) % SCANLINES_PER_FRAME;
+            if self.scanline == 0 {
+                self.odd_frame = !self.odd_frame;
+                self.frame_count += 1;
+            }
+        }
+
+        let visible = (self.scanline as usize) < SCREEN_HEIGHT;
+
+        // each line picks up the horizontal scroll as it starts, the
+        // vertical one only changes on the pre-render line, like the
+        // hardware's copies into its VRAM address
+        if self.dot == 0 {
+            let (origin_x, origin_y) = self.scroll_origin();
+            if visible {
+                self.line_origin.0 = origin_x;
+                self.secondary_count = 0;
+                if rendering {
+                    let (secondary, count, overflow) = self.evaluate_sprites(self.scanline as usize);
+                    self.secondary_oam = secondary;
+                    self.secondary_count = count;
+                    if overflow {
+                        self.status.insert(StatusRegister::SPRITE_OVERFLOW);
+                    }
+                }
+            } else if self.scanline == PRE_RENDER_SCANLINE {
+                self.line_origin.1 = origin_y;
+            }
+        }
+        if visible && self.dot == 256 {
+            self.draw_screen_line();
+        }
+
+        // MMC3 counts scanlines off the sprite pattern fetches around dot 260
+        if self.dot == 260 && rendering && (visible || self.scanline == PRE_RENDER_SCANLINE) {
+            self.mapper.borrow_mut().clock_scanline();
+        }
+
+        // sprite tile loading for the next line holds OAMADDR at 0
+        if rendering && (visible || self.scanline == PRE_RENDER_SCANLINE) && matches!(self.dot, 257 ..= 320) {
+            self.oam_addr = 0;
+        }
+
+        // dots 1-256 output the pixels
+        if visible && matches!(self.dot, 1 ..= 256) && !self.status.contains(StatusRegister::SPRITE_ZERO_HIT)
+            && self.sprite_zero_hit(self.dot as usize - 1)
+        {
+            self.status.insert(StatusRegister::SPRITE_ZERO_HIT);
+        }
+
+        match (self.scanline, self.dot) {
+            (VBLANK_SCANLINE, 1) => {
+                self.status.insert(StatusRegister::VBLANK_STARTED);
+                return self.ctrl.contains(ControlRegister::GENERATE_NMI);
+            }
+            (PRE_RENDER_SCANLINE, 1) => {
+                self.status.remove(
+                    StatusRegister::VBLANK_STARTED | StatusRegister::SPRITE_ZERO_HIT | StatusRegister::SPRITE_OVERFLOW,
+                );
+            }
+            _ => {}
+        }
+        false
+    }
+
+    pub fn write_to_ctrl(&mut self, value: u8) {
+        self.open_bus = value;
+        self.ctrl = ControlRegister::from_bits_truncate(value);
+    }
+
+    pub fn write_to_mask(&mut self, value: u8) {
+        self.open_bus = value;
+        self.mask = MaskRegister::from_bits_truncate(value);
+    }
+
+    // Reading the status acknowledges vblank and resets the 0x2005/0x2006
+    // write toggle
+    pub fn read_status(&mut self) -> u8 {
+        let data = self.peek_status();
+        self.status.remove(StatusRegister::VBLANK_STARTED);
+        self.write_latch = false;
+        self.open_bus = data;
+        data
+    }
+
+    // What read_status would return, without acknowledging vblank
+    pub fn peek_status(&self) -> u8 {
+        self.status.bits() | (self.open_bus & 0b0001_1111)
+    }
+
+    // What read_data would return, without advancing the address or
+    // refilling the read buffer
+    pub fn peek_data(&self) -> u8 {
+        match self.addr {
+            PALETTE ..= 0x3FFF => self.palette_table[mirror_palette_addr(self.addr)],
+            _ => self.internal_data_buf,
+        }
+    }
+
+    pub fn write_to_oam_addr(&mut self, value: u8) {
+        self.open_bus = value;
+        self.oam_addr = value;
+    }
+
+    pub fn write_to_oam_data(&mut self, value: u8) {
+        self.open_bus = value;
+        self.oam_data[self.oam_addr as usize] = value;
+        self.oam_addr = self.oam_addr.wrapping_add(1);
+    }
+
+    pub fn read_oam_data(&mut self) -> u8 {
+        self.open_bus = self.peek_oam_data();
+        self.open_bus
+    }
+
+    pub fn peek_oam_data(&self) -> u8 {
+        self.oam_data[self.oam_addr as usize]
+    }
+
+    pub fn write_to_scroll(&mut self, value: u8) {
+        self.open_bus = value;
+        if self.write_latch {
+            self.scroll_y = value;
+        } else {
+            self.scroll_x = value;
+        }
+        self.write_latch = !self.write_latch;
+    }
+
+    // High byte first, then low byte
+    pub fn write_to_ppu_addr(&mut self, value: u8) {
+        self.open_bus = value;
+        if self.write_latch {
+            self.addr = (self.addr & 0xFF00) | value as u16;
+        } else {
+            self.addr = ((value as u16) << 8) | (self.addr & 0x00FF);
+        }
+        self.addr &= 0x3FFF;
+        self.write_latch = !self.write_latch;
+    }
+
+    fn increment_vram_addr(&mut self) {
+        self.addr = self.addr.wrapping_add(self.ctrl.vram_addr_increment()) & 0x3FFF;
+    }
+
+    pub fn write_to_data(&mut self, value: u8) {
+        self.open_bus = value;
+        let addr = self.addr;
+        match addr {
+            0x0000 ..= 0x1FFF => self.mapper.borrow_mut().ppu_write(addr, value),
+            0x2000 ..= 0x3EFF => self.vram[self.mirror_vram_addr(addr) as usize] = value,
+            PALETTE ..= 0x3FFF => self.palette_table[mirror_palette_addr(addr)] = value,
+            _ => unreachable!("VRAM address {:04x} out of range", addr),
+        }
+        self.increment_vram_addr();
+    }
+
+    // Pattern table memory through the mapper, leaving the PPUDATA address and
+    // read buffer alone. Writes only stick on CHR RAM.
+    pub fn read_chr(&self, addr: u16) -> u8 {
+        self.mapper.borrow().ppu_read(addr & 0x1FFF)
+    }
+
+    pub fn write_chr(&mut self, addr: u16, value: u8) {
+        self.mapper.borrow_mut().ppu_write(addr & 0x1FFF, value);
+    }
+
+    // Reads below the palette go through a one byte buffer: the value
+    // returned is the one fetched by the previous read
+    pub fn read_data(&mut self) -> u8 {
+        let addr = self.addr;
+        self.increment_vram_addr();
+
+        let data = match addr {
+            0x0000 ..= 0x1FFF => {
+                let result = self.internal_data_buf;
+                self.internal_data_buf = self.mapper.borrow().ppu_read(addr);
+                result
+            }
+            0x2000 ..= 0x3EFF => {
+                let result = self.internal_data_buf;
+                self.internal_data_buf = self.vram[self.mirror_vram_addr(addr) as usize];
+                result
+            }
+            PALETTE ..= 0x3FFF => {
+                // the buffer still fills, from the nametable under the palette.
+                // Mid-render the real PPU returns whatever its fetch left on
+                // the bus; we return the entry as if it were idle.
+                self.internal_data_buf = self.vram[self.mirror_vram_addr(addr - 0x1000) as usize];
+                self.palette_table[mirror_palette_addr(addr)]
+            }
+            _ => unreachable!("VRAM address {:04x} out of range", addr),
+        };
+        self.open_bus = data;
+        data
+    }
+
+    // The header's mirroring, unless the mapper switched it
+    pub fn current_mirroring(&self) -> Mirroring {
+        self.mapper.borrow().mirroring().unwrap_or(self.mirroring)
+    }
+
+    // Maps 0x2000-0x3EFF to an index in vram:
+    //   Horizontal: [ A a ]   Vertical: [ A B ]
+    //               [ B b ]             [ a b ]
+    pub fn mirror_vram_addr(&self, addr: u16) -> u16 {
+        // 0x3000-0x3EFF mirrors 0x2000-0x2EFF
+        let vram_index = (addr & 0x2FFF) - 0x2000;
+        let name_table = vram_index / 0x400;
+        match (self.current_mirroring(), name_table) {
+            (Mirroring::FourScreen, _) => vram_index,
+            (Mirroring::SingleScreenLower, _) => vram_index % 0x400,
+            (Mirroring::SingleScreenUpper, _) => 0x400 + vram_index % 0x400,
+            (Mirroring::Vertical, 2) | (Mirroring::Vertical, 3) => vram_index - 0x800,
+            (Mirroring::Horizontal, 1) | (Mirroring::Horizontal, 2) => vram_index - 0x400,
+            (Mirroring::Horizontal, 3) => vram_index - 0x800,
+            _ => vram_index,
+        }
+    }
+}
+
+impl NesPPU {
+    // Memory and registers only, not the cartridge side (CHR, mirroring):
+    // enough to set up rendering tests and return to a known state between
+    // cases
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut state = Vec::with_capacity(STATE_LEN);
+        state.extend_from_slice(&self.vram);
+        state.extend_from_slice(&self.oam_data);
+        state.extend_from_slice(&self.palette_table);
+        state.extend_from_slice(&[
+            self.oam_addr,
+            self.ctrl.bits(),
+            self.mask.bits(),
+            self.status.bits(),
+            self.scroll_x,
+            self.scroll_y,
+            self.internal_data_buf,
+            self.open_bus,
+            self.write_latch as u8,
+            self.odd_frame as u8,
+        ]);
+        for value in &[self.addr, self.scanline, self.dot] {
+            state.extend_from_slice(&value.to_le_bytes());
+        }
+        state
+    }
+
+    pub fn restore_state(&mut self, state: &[u8]) -> Result<(), String> {
+        if state.len() != STATE_LEN {
+            return Err(format!("PPU state is {} bytes, expected {}", state.len(), STATE_LEN));
+        }
+        let (vram, rest) = state.split_at(self.vram.len());
+        let (oam, rest) = rest.split_at(self.oam_data.len());
+        let (palette, regs) = rest.split_at(self.palette_table.len());
+        self.vram.copy_from_slice(vram);
+        self.oam_data.copy_from_slice(oam);
+        self.palette_table.copy_from_slice(palette);
+
+        self.oam_addr = regs[0];
+        self.ctrl = ControlRegister::from_bits_truncate(regs[1]);
+        self.mask = MaskRegister::from_bits_truncate(regs[2]);
+        self.status = StatusRegister::from_bits_truncate(regs[3]);
+        self.scroll_x = regs[4];
+        self.scroll_y = regs[5];
+        self.internal_data_buf = regs[6];
+        self.open_bus = regs[7];
+        self.write_latch = regs[8] != 0;
+        self.odd_frame = regs[9] != 0;
+        let u16_at = |i: usize| u16::from_le_bytes([regs[i], regs[i + 1]]);
+        self.addr = u16_at(10);
+        self.scanline = u16_at(12);
+        self.dot = u16_at(14);
+        Ok(())
+    }
+
+    // Draws the whole frame from the current PPU memory and returns it as
+    // RGB bytes. Every line uses the current scroll, mid-frame changes
+    // don't show.
+    pub fn render(&mut self) -> &[u8] {
+        let mut frame = std::mem::replace(&mut self.frame, Frame::new(0, 0));
+        self.render_frame_into(&mut frame);
+        self.frame = frame;
+        &self.frame.data
+    }
+
+    // render() for streaming the display: draws the frame the same way and
+    // returns the 8x8 tiles that changed since the previous render
+    pub fn render_changes(&mut self) -> Vec<Rect> {
+        let previous = self.frame.clone();
+        self.render();
+        self.frame.changed_tiles(&previous)
+    }
+
+    // The same one-shot render into a caller's frame, for screenshots and
+    // tests. frame must be SCREEN_WIDTH x SCREEN_HEIGHT.
+    pub fn render_frame_into(&self, frame: &mut Frame) {
+        assert!(
+            frame.width == SCREEN_WIDTH && frame.height == SCREEN_HEIGHT,
+            "{}x{} frame, the screen is {}x{}",
+            frame.width, frame.height, SCREEN_WIDTH, SCREEN_HEIGHT
+        );
+        let origin = self.scroll_origin();
+        for y in 0..SCREEN_HEIGHT {
+            // which pixels got a non-zero background color, for sprite priority
+            let mut background = [false; SCREEN_WIDTH];
+            self.render_background_line(frame, y, origin, &mut background);
+            let (sprites, count, _) = self.evaluate_sprites(y);
+            self.render_sprite_line(frame, y, &sprites[..count * 4], &background);
+        }
+    }
+
+    // The picture as the beam drew it, each line with the scroll it started
+    // with, so mid-frame scroll splits show. Lines are drawn at dot 256 and
+    // the frame is complete once scanline 239 is.
+    pub fn screen(&self) -> &Frame {
+        &self.screen
+    }
+
+    fn draw_screen_line(&mut self) {
+        let y = self.scanline as usize;
+        let mut screen = std::mem::replace(&mut self.screen, Frame::new(0, 0));
+        let mut background = [false; SCREEN_WIDTH];
+        self.render_background_line(&mut screen, y, self.line_origin, &mut background);
+        self.render_sprite_line(&mut screen, y, self.secondary_oam(), &background);
+        self.screen = screen;
+    }
+
+    // Sprites found for the current line by the evaluation at its start,
+    // up to 8 OAM entries. Empty while rendering is off.
+    pub fn secondary_oam(&self) -> &[u8] {
+        &self.secondary_oam[..self.secondary_count * 4]
+    }
+
+    // Sprite evaluation: the first 8 OAM entries that cover line y, in OAM
+    // order, how many there are and whether more were in range. Unused
+    // slots are 0xFF.
+    fn evaluate_sprites(&self, y: usize) -> ([u8; 32], usize, bool) {
+        let mut secondary = [0xFF; 32];
+        let mut count = 0;
+        let height = self.ctrl.sprite_height() as usize;
+
+        for entry in self.oam_data.chunks(4) {
+            // sprites show up one scanline below their Y
+            let top = entry[0] as usize + 1;
+            if y < top || y >= top + height {
+                continue;
+            }
+            if count == 8 {
+                return (secondary, count, true);
+            }
+            secondary[count * 4..count * 4 + 4].copy_from_slice(entry);
+            count += 1;
+        }
+        (secondary, count, false)
+    }
+
+    // CHR viewer: all 512 tiles, 0x0000 on top and 0x1000 below, colored with
+    // one of the 8 palettes (4-7 being the sprite ones). frame must be
+    // PATTERN_SHEET_WIDTH x PATTERN_SHEET_HEIGHT.
+    pub fn render_pattern_tables(&self, palette_row: u8, frame: &mut Frame) {
+        assert!(
+            frame.width == PATTERN_SHEET_WIDTH && frame.height == PATTERN_SHEET_HEIGHT,
+            "{}x{} frame, the sheet is {}x{}",
+            frame.width, frame.height, PATTERN_SHEET_WIDTH, PATTERN_SHEET_HEIGHT
+        );
+        let start = (palette_row % 8) as usize * 4;
+        let colors = [
+            self.color(self.palette_table[0]),
+            self.color(self.palette_table[start + 1]),
+            self.color(self.palette_table[start + 2]),
+            self.color(self.palette_table[start + 3]),
+        ];
+
+        for tile in 0..512 {
+            let left = (tile % 16) * 8;
+            let top = (tile / 256) * 128 + (tile % 256) / 16 * 8;
+            for row in 0..8 {
+                let addr = (tile * 16 + row) as u16;
+                for col in 0..8 {
+                    let value = self.pattern_value(addr, 7 - col);
+                    frame.set_pixel(left + col, top + row, colors[value as usize]);
+                }
+            }
+        }
+    }
+
+    fn color(&self, palette_entry: u8) -> (u8, u8, u8) {
+        if self.mask.contains(MaskRegister::GREYSCALE) {
+            palette::rgb(palette_entry & 0x30)
+        } else {
+            palette::rgb(palette_entry)
+        }
+    }
+
+    fn background_bank(&self) -> u16 {
+        if self.ctrl.contains(ControlRegister::BACKGROUND_PATTERN_ADDR) { 0x1000 } else { 0 }
+    }
+
+    // Where the top-left screen pixel sits once PPUSCROLL and the PPUCTRL
+    // nametable bits are applied. The four nametables form a 512x480 plane
+    // that wraps around.
+    fn scroll_origin(&self) -> (usize, usize) {
+        let base = (self.ctrl.bits() & 0b11) as usize;
+        (
+            self.scroll_x as usize + (base & 1) * SCREEN_WIDTH,
+            self.scroll_y as usize + (base >> 1) * SCREEN_HEIGHT,
+        )
+    }
+
+    // Nametable base address and tile column/row under a point of the plane
+    fn plane_tile(plane_x: usize, plane_y: usize) -> (u16, u16, u16) {
+        let (plane_x, plane_y) = (plane_x % (2 * SCREEN_WIDTH), plane_y % (2 * SCREEN_HEIGHT));
+        let nametable = plane_x / SCREEN_WIDTH + 2 * (plane_y / SCREEN_HEIGHT);
+        (
+            0x2000 + 0x400 * nametable as u16,
+            ((plane_x % SCREEN_WIDTH) / 8) as u16,
+            ((plane_y % SCREEN_HEIGHT) / 8) as u16,
+        )
+    }
+
+    // Nametable tile under screen pixel (x, y) with the current scroll
+    pub fn tile_at_pixel(&self, x: usize, y: usize) -> u8 {
+        let (origin_x, origin_y) = self.scroll_origin();
+        let (nametable, tile_x, tile_y) = Self::plane_tile(x + origin_x, y + origin_y);
+        self.vram[self.mirror_vram_addr(nametable + tile_y * 32 + tile_x) as usize]
+    }
+
+    // 2-bit background color at screen pixel (x, y), the screen starting at
+    // origin in the plane
+    fn background_value(&self, x: usize, y: usize, origin: (usize, usize)) -> u8 {
+        let (plane_x, plane_y) = (x + origin.0, y + origin.1);
+        let (nametable, tile_x, tile_y) = Self::plane_tile(plane_x, plane_y);
+        let tile = self.vram[self.mirror_vram_addr(nametable + tile_y * 32 + tile_x) as usize] as u16;
+        let addr = self.background_bank() + tile * 16 + (plane_y % 8) as u16;
+        self.pattern_value(addr, 7 - plane_x % 8)
+    }
+
+    fn render_background_line(&self, frame: &mut Frame, y: usize, origin: (usize, usize), opaque: &mut [bool]) {
+        if !self.mask.contains(MaskRegister::SHOW_BACKGROUND) {
+            let backdrop = self.color(self.palette_table[0]);
+            for (x, opaque) in opaque.iter_mut().enumerate() {
+                *opaque = false;
+                frame.set_pixel(x, y, backdrop);
+            }
+            return;
+        }
+
+        for (x, opaque) in opaque.iter_mut().enumerate() {
+            let value = self.background_value(x, y, origin);
+            let (nametable, tile_x, tile_y) = Self::plane_tile(x + origin.0, y + origin.1);
+            *opaque = value != 0;
+            frame.set_pixel(x, y, self.background_colors(nametable, tile_x, tile_y)[value as usize]);
+        }
+    }
+
+    // Each attribute byte covers 4x4 tiles, 2 bits per 2x2 quadrant
+    fn background_colors(&self, nametable: u16, tile_x: u16, tile_y: u16) -> [(u8, u8, u8); 4] {
+        let attr_addr = nametable + 0x3C0 + (tile_y / 4) * 8 + tile_x / 4;
+        let attr = self.vram[self.mirror_vram_addr(attr_addr) as usize];
+        let shift = ((tile_y % 4) / 2) * 4 + ((tile_x % 4) / 2) * 2;
+        let start = 1 + ((attr >> shift) & 0b11) as usize * 4;
+
+        [
+            self.color(self.palette_table[0]),
+            self.color(self.palette_table[start]),
+            self.color(self.palette_table[start + 1]),
+            self.color(self.palette_table[start + 2]),
+        ]
+    }
+
+    // Draws line y of the sprites among entries, OAM entries being (y, tile,
+    // attributes, x), as evaluate_sprites picked them. Earlier entries win
+    // where sprites overlap, even when the winner is behind the background.
+    fn render_sprite_line(&self, frame: &mut Frame, y: usize, entries: &[u8], background: &[bool]) {
+        if !self.mask.contains(MaskRegister::SHOW_SPRITES) {
+            return;
+        }
+
+        let mut covered = [false; SCREEN_WIDTH];
+        let height = self.ctrl.sprite_height();
+
+        for entry in entries.chunks(4) {
+            // sprites show up one scanline below their Y
+            let (top, tile, attr, left) = (entry[0] as usize + 1, entry[1] as u16, entry[2], entry[3] as usize);
+            if y < top || y >= top + height as usize {
+                continue;
+            }
+            let flip_h = attr & 0b0100_0000 != 0;
+            let flip_v = attr & 0b1000_0000 != 0;
+            let behind = attr & 0b0010_0000 != 0;
+
+            let start = 0x11 + (attr & 0b11) as usize * 4;
+            let colors = [
+                (0, 0, 0),
+                self.color(self.palette_table[start]),
+                self.color(self.palette_table[start + 1]),
+                self.color(self.palette_table[start + 2]),
+            ];
+
+            let row = (y - top) as u16;
+            let row = if flip_v { height - 1 - row } else { row };
+            let addr = self.sprite_pattern_addr(tile, row);
+            let (lo, hi) = {
+                let mapper = self.mapper.borrow();
+                (mapper.ppu_read(addr), mapper.ppu_read(addr + 8))
+            };
+
+            for col in 0..8 {
+                let px = left + col;
+                if px >= SCREEN_WIDTH {
+                    break;
+                }
+                let bit = if flip_h { col } else { 7 - col };
+                let value = (((hi >> bit) & 1) << 1) | ((lo >> bit) & 1);
+                if value == 0 || covered[px] {
+                    continue;
+                }
+                covered[px] = true;
+                if !(behind && background[px]) {
+                    frame.set_pixel(px, y, colors[value as usize]);
+                }
+            }
+        }
+    }
+
+    // Address of the low plane byte for a row of a sprite, flips already
+    // applied to the row
+    fn sprite_pattern_addr(&self, tile: u16, row: u16) -> u16 {
+        let (bank, tile) = if self.ctrl.sprite_height() == 16 {
+            ((tile & 1) * 0x1000, tile & !1)
+        } else if self.ctrl.contains(ControlRegister::SPRITE_PATTERN_ADDR) {
+            (0x1000, tile)
+        } else {
+            (0, tile)
+        };
+        // the bottom half of an 8x16 sprite is the next tile
+        bank + (tile + row / 8) * 16 + row % 8
+    }
+
+    // Whether an opaque pixel of OAM entry 0 lands on an opaque background
+    // pixel at x on the current scanline. Never at x=255, nor in the left
+    // column while either layer is clipped there.
+    fn sprite_zero_hit(&self, x: usize) -> bool {
+        if !self.mask.contains(MaskRegister::SHOW_BACKGROUND | MaskRegister::SHOW_SPRITES) || x == 255 {
+            return false;
+        }
+        if x < 8 && !self.mask.contains(MaskRegister::LEFTMOST_8PXL_BACKGROUND | MaskRegister::LEFTMOST_8PXL_SPRITE) {
+            return false;
+        }
+
+        let y = self.scanline as usize;
+        let top = self.oam_data[0] as usize + 1;
+        let left = self.oam_data[3] as usize;
+        let height = self.ctrl.sprite_height() as usize;
+        if y < top || y >= top + height || x < left || x >= left + 8 {
+            return false;
+        }
+
+        let attr = self.oam_data[2];
+        let row = (y - top) as u16;
+        let row = if attr & 0b1000_0000 != 0 { height as u16 - 1 - row } else { row };
+        let col = x - left;
+        let bit = if attr & 0b0100_0000 != 0 { col } else { 7 - col };
+        let addr = self.sprite_pattern_addr(self.oam_data[1] as u16, row);
+        if self.pattern_value(addr, bit) == 0 {
+            return false;
+        }
+
+        self.background_value(x, y, self.line_origin) != 0
+    }
+
+    // 2-bit color of one pixel, addr being the tile row's low plane
+    fn pattern_value(&self, addr: u16, bit: usize) -> u8 {
+        let mapper = self.mapper.borrow();
+        (((mapper.ppu_read(addr + 8) >> bit) & 1) << 1) | ((mapper.ppu_read(addr) >> bit) & 1)
+    }
+}
+
+// 0x3F10/0x3F14/0x3F18/0x3F1C are the backdrop entries of 0x3F00/04/08/0C
+fn mirror_palette_addr(addr: u16) -> usize {
+    let index = (addr - PALETTE) as usize % 32;
+    match index {
+        0x10 | 0x14 | 0x18 | 0x1C => index - 0x10,
+        _ => index,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::test::{nrom, test_rom};
+    use crate::cartridge::Rom;
+    use crate::mapper;
+
+    fn ppu_for(rom: Rom) -> NesPPU {
+        let mirroring = rom.screen_mirroring;
+        NesPPU::new(mapper::from_rom(rom), mirroring)
+    }
+
+    // NROM with CHR RAM
+    fn test_ppu() -> NesPPU {
+        ppu_for(nrom())
+    }
+
+    fn set_addr(ppu: &mut NesPPU, addr: u16) {
+        ppu.write_to_ppu_addr((addr >> 8) as u8);
+        ppu.write_to_ppu_addr(addr as u8);
+    }
+
+    // dots from here to the start of the next frame
+    fn dots_to_next_frame(ppu: &mut NesPPU) -> usize {
+        let mut dots = 0;
+        loop {
+            ppu.tick(1);
+            dots += 1;
+            if (ppu.scanline(), ppu.dot()) == (0, 0) {
+                return dots;
+            }
+        }
+    }
+
+    #[test]
+    fn test_is_rendering_enabled() {
+        let mut ppu = test_ppu();
+        assert!(!ppu.is_rendering_enabled());
+        ppu.write_to_mask(MaskRegister::SHOW_BACKGROUND.bits());
+        assert!(ppu.is_rendering_enabled());
+        ppu.write_to_mask(MaskRegister::SHOW_SPRITES.bits());
+        assert!(ppu.is_rendering_enabled());
+        ppu.write_to_mask(MaskRegister::GREYSCALE.bits() | MaskRegister::LEFTMOST_8PXL_BACKGROUND.bits());
+        assert!(!ppu.is_rendering_enabled());
+
+        // no dot skipped with rendering off
+        assert_eq!(dots_to_next_frame(&mut ppu), 89342);
+        assert_eq!(dots_to_next_frame(&mut ppu), 89342);
+
+        ppu.write_to_mask(MaskRegister::SHOW_BACKGROUND.bits());
+        assert_eq!(dots_to_next_frame(&mut ppu), 89342);
+        // the odd frame is one dot short
+        assert_eq!(dots_to_next_frame(&mut ppu), 89341);
+        assert_eq!(dots_to_next_frame(&mut ppu), 89342);
+    }
+
+    #[test]
+    fn test_frame_count() {
+        let mut ppu = test_ppu();
+        for frame in 1..=3 {
+            dots_to_next_frame(&mut ppu);
+            assert_eq!(ppu.frame_count(), frame);
+        }
+
+        // the frame completes on the dot that wraps to scanline 0
+        ppu.advance_to_scanline(261, 340).unwrap();
+        assert_eq!(ppu.frame_count(), 3);
+        ppu.tick(1);
+        assert_eq!((ppu.scanline(), ppu.dot()), (0, 0));
+        assert_eq!(ppu.frame_count(), 4);
+
+        ppu.reset_frame_count();
+        assert_eq!(ppu.frame_count(), 0);
+        dots_to_next_frame(&mut ppu);
+        assert_eq!(ppu.frame_count(), 1);
+    }
+
+    #[test]
+    fn test_oam_addr_reset_while_rendering() {
+        let mut ppu = test_ppu();
+        ppu.write_to_mask(MaskRegister::SHOW_SPRITES.bits());
+        ppu.advance_to_scanline(10, 256).unwrap();
+        ppu.write_to_oam_addr(0x40);
+        ppu.advance_to_scanline(10, 320).unwrap();
+        assert_eq!(ppu.oam_addr, 0);
+
+        ppu.write_to_mask(0);
+        ppu.advance_to_scanline(11, 256).unwrap();
+        ppu.write_to_oam_addr(0x40);
+        ppu.advance_to_scanline(11, 320).unwrap();
+        assert_eq!(ppu.oam_addr, 0x40);
+
+        // pre-render too, but not vblank
+        ppu.write_to_mask(MaskRegister::SHOW_BACKGROUND.bits());
+        ppu.advance_to_scanline(241, 0).unwrap();
+        ppu.write_to_oam_addr(0x40);
+        ppu.advance_to_scanline(250, 320).unwrap();
+        assert_eq!(ppu.oam_addr, 0x40);
+        ppu.advance_to_scanline(261, 320).unwrap();
+        assert_eq!(ppu.oam_addr, 0);
+    }
+
+    #[test]
+    fn test_read_write_chr() {
+        let chr_rom: Vec<u8> = (0..0x2000).map(|i| i as u8 ^ 0x5A).collect();
+        let mut ppu = ppu_for(test_rom(0, &[0; 0x4000], &chr_rom));
+        assert_eq!(ppu.read_chr(0x1234), 0x34 ^ 0x5A);
+        set_addr(&mut ppu, 0x1234);
+        ppu.read_data();
+        assert_eq!(ppu.read_data(), ppu.read_chr(0x1234));
+
+        // CHR ROM ignores writes
+        ppu.write_chr(0x1234, 0xFF);
+        assert_eq!(ppu.read_chr(0x1234), 0x34 ^ 0x5A);
+
+        let mut ppu = test_ppu();
+        ppu.write_chr(0x0010, 0xAB);
+        assert_eq!(ppu.read_chr(0x0010), 0xAB);
+        set_addr(&mut ppu, 0x0010);
+        ppu.read_data();
+        assert_eq!(ppu.read_data(), 0xAB);
+    }
+
+    // Tile 1 is solid color 1, tile 2 solid color 2. The top-left tile of
+    // the first nametable is 1, OAM entry 0 is tile 2 at (100, 50).
+    fn static_scene() -> NesPPU {
+        let mut ppu = test_ppu();
+        for row in 0..8 {
+            ppu.write_chr(0x10 + row, 0xFF);
+            ppu.write_chr(0x28 + row, 0xFF);
+        }
+        set_addr(&mut ppu, 0x2000);
+        ppu.write_to_data(1);
+        set_addr(&mut ppu, 0x3F00);
+        for color in &[0x0F, 0x21] {
+            ppu.write_to_data(*color);
+        }
+        set_addr(&mut ppu, 0x3F12);
+        ppu.write_to_data(0x16);
+        ppu.oam_data[..4].copy_from_slice(&[49, 2, 0, 100]);
+        ppu.write_to_mask(0b0001_1110);
+        ppu
+    }
+
+    #[test]
+    fn test_render_frame_into() {
+        let mut ppu = static_scene();
+        let mut frame = Frame::new(SCREEN_WIDTH, SCREEN_HEIGHT);
+        ppu.render_frame_into(&mut frame);
+
+        assert_eq!(frame.pixel(0, 0), palette::rgb(0x21));
+        assert_eq!(frame.pixel(7, 7), palette::rgb(0x21));
+        assert_eq!(frame.pixel(8, 0), palette::rgb(0x0F));
+        assert_eq!(frame.pixel(100, 50), palette::rgb(0x16));
+        assert_eq!(frame.pixel(107, 57), palette::rgb(0x16));
+        assert_eq!(frame.pixel(108, 50), palette::rgb(0x0F));
+
+        // scrolled by (4, 3), the tile moves up and left, the sprite stays
+        ppu.write_to_scroll(4);
+        ppu.write_to_scroll(3);
+        ppu.render_frame_into(&mut frame);
+        assert_eq!(frame.pixel(0, 0), palette::rgb(0x21));
+        assert_eq!(frame.pixel(3, 4), palette::rgb(0x21));
+        assert_eq!(frame.pixel(4, 0), palette::rgb(0x0F));
+        assert_eq!(frame.pixel(0, 5), palette::rgb(0x0F));
+        // x=252 is the left edge of the second nametable, a mirror of the first
+        assert_eq!(frame.pixel(251, 0), palette::rgb(0x0F));
+        assert_eq!(frame.pixel(252, 0), palette::rgb(0x21));
+        assert_eq!(frame.pixel(100, 50), palette::rgb(0x16));
+    }
+
+    #[test]
+    fn test_render_frame_into_matches_screen() {
+        let mut ppu = static_scene();
+        ppu.write_to_scroll(4);
+        ppu.write_to_scroll(3);
+        // through the pre-render line, which latches the vertical scroll,
+        // then a whole frame
+        dots_to_next_frame(&mut ppu);
+        ppu.advance_to_scanline(240, 0).unwrap();
+
+        let mut frame = Frame::new(SCREEN_WIDTH, SCREEN_HEIGHT);
+        ppu.render_frame_into(&mut frame);
+        assert_eq!(frame.pixel(0, 0), palette::rgb(0x21));
+        assert_eq!(frame.pixel(100, 50), palette::rgb(0x16));
+        assert!(ppu.screen() == &frame);
+    }
+
+    #[test]
+    fn test_screen_scroll_split() {
+        let mut ppu = test_ppu();
+        for row in 0..8 {
+            ppu.write_chr(0x10 + row, 0xFF);
+        }
+        // a solid column down the left edge of the first nametable
+        for row in 0..30 {
+            set_addr(&mut ppu, 0x2000 + row * 32);
+            ppu.write_to_data(1);
+        }
+        ppu.palette_table[..2].copy_from_slice(&[0x0F, 0x21]);
+        ppu.write_to_mask(MaskRegister::SHOW_BACKGROUND.bits());
+
+        // scroll by 4 in the hblank before scanline 30
+        ppu.advance_to_scanline(29, 340).unwrap();
+        ppu.write_to_scroll(4);
+        ppu.write_to_scroll(0);
+        ppu.advance_to_scanline(240, 0).unwrap();
+
+        let screen = ppu.screen();
+        for y in &[0, 29] {
+            assert_eq!(screen.pixel(0, *y), palette::rgb(0x21));
+            assert_eq!(screen.pixel(7, *y), palette::rgb(0x21));
+            assert_eq!(screen.pixel(8, *y), palette::rgb(0x0F));
+            assert_eq!(screen.pixel(252, *y), palette::rgb(0x0F));
+        }
+        for y in &[30, 239] {
+            assert_eq!(screen.pixel(3, *y), palette::rgb(0x21));
+            assert_eq!(screen.pixel(4, *y), palette::rgb(0x0F));
+            // the second nametable mirrors the first
+            assert_eq!(screen.pixel(252, *y), palette::rgb(0x21));
+        }
+
+        // the one-shot render applies the last scroll everywhere
+        let mut frame = Frame::new(SCREEN_WIDTH, SCREEN_HEIGHT);
+        ppu.render_frame_into(&mut frame);
+        assert_eq!(frame.pixel(4, 0), palette::rgb(0x0F));
+    }
+
+    #[test]
+    fn test_render_changes() {
+        let mut ppu = static_scene();
+        // everything the first time, against the blank frame
+        assert_eq!(ppu.render_changes().len(), 32 * 30);
+        assert!(ppu.render_changes().is_empty());
+
+        // tile (5, 3) goes solid
+        set_addr(&mut ppu, 0x2000 + 3 * 32 + 5);
+        ppu.write_to_data(1);
+        assert_eq!(ppu.render_changes(), vec![Rect { x: 40, y: 24, width: 8, height: 8 }]);
+        assert!(ppu.render_changes().is_empty());
+    }
+
+    #[test]
+    fn test_tile_at_pixel() {
+        let mut ppu = test_ppu();
+        ppu.mirroring = Mirroring::Vertical;
+        // row 3 of the first two nametables
+        set_addr(&mut ppu, 0x2000 + 3 * 32 + 2);
+        ppu.write_to_data(0x17);
+        set_addr(&mut ppu, 0x2000 + 3 * 32);
+        ppu.write_to_data(0x33);
+        set_addr(&mut ppu, 0x2400 + 3 * 32);
+        ppu.write_to_data(0x42);
+        ppu.write_to_data(0x66);
+        ppu.write_to_scroll(12);
+        ppu.write_to_scroll(20);
+
+        // (10, 5) scrolls to (22, 25): tile (2, 3)
+        assert_eq!(ppu.tile_at_pixel(10, 5), 0x17);
+        // (250, 5) scrolls past the right edge into the second nametable
+        assert_eq!(ppu.tile_at_pixel(250, 5), 0x42);
+
+        // starting from the second nametable, the plane wraps back to the first
+        ppu.write_to_ctrl(0b01);
+        assert_eq!(ppu.tile_at_pixel(0, 5), 0x66);
+        assert_eq!(ppu.tile_at_pixel(250, 5), 0x33);
+    }
+
+    #[test]
+    fn test_save_restore_state() {
+        let mut ppu = static_scene();
+        ppu.advance_to_scanline(30, 100).unwrap();
+        let state = ppu.save_state();
+        let vram = ppu.vram;
+        let frame = ppu.render().to_vec();
+
+        set_addr(&mut ppu, 0x2000);
+        ppu.write_to_data(2);
+        ppu.oam_data[3] = 0;
+        ppu.write_to_ctrl(0b1000_0001);
+        ppu.advance_to_scanline(200, 0).unwrap();
+        assert_ne!(ppu.render(), &frame[..]);
+
+        ppu.restore_state(&state).unwrap();
+        assert_eq!(&ppu.vram[..], &vram[..]);
+        assert_eq!(ppu.render(), &frame[..]);
+        assert_eq!((ppu.scanline(), ppu.dot()), (30, 100));
+        assert_eq!(ppu.save_state(), state);
+
+        assert!(ppu.restore_state(&state[1..]).is_err());
+    }
+
+    #[test]
+    fn test_secondary_oam_holds_first_8_sprites() {
+        let mut ppu = test_ppu();
+        for row in 0..8 {
+            ppu.write_chr(0x10 + row, 0xFF);
+        }
+        ppu.palette_table[0] = 0x0F;
+        ppu.palette_table[0x11] = 0x16;
+        // nine sprites on lines 20-27, with one elsewhere in between, and
+        // the rest hidden below the screen
+        ppu.oam_data = [0xFF; 256];
+        for sprite in 0..10 {
+            let y = if sprite == 3 { 100 } else { 19 };
+            ppu.oam_data[sprite * 4..sprite * 4 + 4].copy_from_slice(&[y, 1, 0, sprite as u8 * 10]);
+        }
+        ppu.write_to_mask(MaskRegister::SHOW_SPRITES.bits());
+
+        ppu.advance_to_scanline(19, 0).unwrap();
+        assert!(ppu.secondary_oam().is_empty());
+        assert!(!ppu.status.contains(StatusRegister::SPRITE_OVERFLOW));
+
+        ppu.advance_to_scanline(20, 0).unwrap();
+        let mut first_8 = ppu.oam_data[..12].to_vec();
+        first_8.extend_from_slice(&ppu.oam_data[16..36]);
+        assert_eq!(ppu.secondary_oam(), &first_8[..]);
+        assert!(ppu.status.contains(StatusRegister::SPRITE_OVERFLOW));
+
+        // the ninth, at x=90, isn't drawn
+        ppu.advance_to_scanline(240, 0).unwrap();
+        assert_eq!(ppu.screen().pixel(80, 20), palette::rgb(0x16));
+        assert_eq!(ppu.screen().pixel(90, 20), palette::rgb(0x0F));
+        let mut frame = Frame::new(SCREEN_WIDTH, SCREEN_HEIGHT);
+        ppu.render_frame_into(&mut frame);
+        assert_eq!(frame.pixel(90, 20), palette::rgb(0x0F));
+        assert_eq!(frame.pixel(30, 101), palette::rgb(0x16));
+    }
+
+    #[test]
+    fn test_sprite_pattern_table() {
+        let mut ppu = test_ppu();
+        // tile 1: color 1 in the low table, color 2 in the high one
+        for row in 0..8 {
+            ppu.write_chr(0x0010 + row, 0xFF);
+            ppu.write_chr(0x1018 + row, 0xFF);
+        }
+        ppu.palette_table[0x11] = 0x21;
+        ppu.palette_table[0x12] = 0x22;
+        ppu.oam_data[..4].copy_from_slice(&[9, 1, 0, 20]);
+        ppu.write_to_mask(MaskRegister::SHOW_SPRITES.bits());
+
+        ppu.write_to_ctrl(ControlRegister::SPRITE_PATTERN_ADDR.bits());
+        let mut frame = Frame::new(SCREEN_WIDTH, SCREEN_HEIGHT);
+        ppu.render_frame_into(&mut frame);
+        assert_eq!(frame.pixel(20, 10), palette::rgb(0x22));
+        assert_eq!(frame.pixel(27, 17), palette::rgb(0x22));
+
+        ppu.write_to_ctrl(0);
+        ppu.render_frame_into(&mut frame);
+        assert_eq!(frame.pixel(20, 10), palette::rgb(0x21));
+    }
+
+    // Opaque background tile in the top-right corner, and sprite 0 over it
+    // with only one opaque column, at x
+    fn sprite_zero_scene(x: usize) -> NesPPU {
+        let mut ppu = test_ppu();
+        for row in 0..8 {
+            ppu.write_chr(0x10 + row, 0xFF);
+            ppu.write_chr(0x20 + row, 0x80 >> (x - 248));
+        }
+        set_addr(&mut ppu, 0x2000 + 31);
+        ppu.write_to_data(1);
+        ppu.oam_data[..4].copy_from_slice(&[0, 2, 0, 248]);
+        ppu.write_to_mask((MaskRegister::SHOW_BACKGROUND | MaskRegister::SHOW_SPRITES).bits());
+        ppu
+    }
+
+    #[test]
+    fn test_sprite_zero_hit_not_at_x_255() {
+        let mut ppu = sprite_zero_scene(255);
+        ppu.advance_to_scanline(240, 0).unwrap();
+        assert!(!ppu.status.contains(StatusRegister::SPRITE_ZERO_HIT));
+
+        let mut ppu = sprite_zero_scene(254);
+        ppu.advance_to_scanline(1, 254).unwrap();
+        assert!(!ppu.status.contains(StatusRegister::SPRITE_ZERO_HIT));
+        // dot 255 draws x=254
+        ppu.advance_to_scanline(1, 255).unwrap();
+        assert!(ppu.status.contains(StatusRegister::SPRITE_ZERO_HIT));
+    }
+
+    #[test]
+    fn test_render_pattern_tables() {
+        let mut ppu = test_ppu();
+        // tile 0x13: left half color 1, right half color 3
+        for row in 0..8 {
+            ppu.write_chr(0x130 + row, 0xFF);
+            ppu.write_chr(0x138 + row, 0x0F);
+        }
+        // tile 0x101, the second table's tile 1: color 2 in the top row
+        ppu.write_chr(0x1018, 0xFF);
+        ppu.palette_table[..4].copy_from_slice(&[0x0F, 0x01, 0x02, 0x03]);
+        ppu.palette_table[0x14..0x18].copy_from_slice(&[0x00, 0x21, 0x22, 0x23]);
+
+        let mut frame = Frame::new(PATTERN_SHEET_WIDTH, PATTERN_SHEET_HEIGHT);
+        ppu.render_pattern_tables(0, &mut frame);
+        // row 1, column 3
+        assert_eq!(frame.pixel(24, 8), palette::rgb(0x01));
+        assert_eq!(frame.pixel(31, 15), palette::rgb(0x03));
+        assert_eq!(frame.pixel(32, 8), palette::rgb(0x0F));
+        assert_eq!(frame.pixel(8, 128), palette::rgb(0x02));
+        assert_eq!(frame.pixel(8, 129), palette::rgb(0x0F));
+
+        ppu.render_pattern_tables(5, &mut frame);
+        assert_eq!(frame.pixel(24, 8), palette::rgb(0x21));
+        assert_eq!(frame.pixel(31, 15), palette::rgb(0x23));
+        assert_eq!(frame.pixel(8, 128), palette::rgb(0x22));
+        // the backdrop, not the unused sprite entry
+        assert_eq!(frame.pixel(0, 0), palette::rgb(0x0F));
+    }
+
+    #[test]
+    fn test_advance_to_scanline() {
+        let mut ppu = test_ppu();
+        ppu.advance_to_scanline(241, 0).unwrap();
+        assert!(!ppu.status.contains(StatusRegister::VBLANK_STARTED));
+        ppu.advance_to_scanline(241, 1).unwrap();
+        assert!(ppu.status.contains(StatusRegister::VBLANK_STARTED));
+        assert_eq!((ppu.scanline(), ppu.dot()), (241, 1));
+
+        assert!(ppu.advance_to_scanline(100, 0).is_err());
+        assert!(ppu.advance_to_scanline(262, 0).is_err());
+        assert_eq!((ppu.scanline(), ppu.dot()), (241, 1));
+
+        // pre-render clears the frame's flags
+        ppu.status.insert(StatusRegister::SPRITE_ZERO_HIT | StatusRegister::SPRITE_OVERFLOW);
+        ppu.advance_to_scanline(261, 0).unwrap();
+        assert!(ppu.status.contains(StatusRegister::VBLANK_STARTED | StatusRegister::SPRITE_ZERO_HIT));
+        ppu.advance_to_scanline(261, 1).unwrap();
+        assert!(ppu.status.is_empty());
+    }
+
+    #[test]
+    fn test_status_read_resets_address_latch() {
+        let mut ppu = test_ppu();
+        ppu.write_to_ppu_addr(0x21);
+        ppu.read_status();
+        // high byte again, not the low byte
+        ppu.write_to_ppu_addr(0x23);
+        ppu.write_to_ppu_addr(0x45);
+        assert_eq!(ppu.vram_addr(), 0x2345);
+
+        // PPUSCROLL shares the latch
+        ppu.write_to_scroll(0x10);
+        ppu.read_status();
+        ppu.write_to_scroll(0x20);
+        ppu.write_to_scroll(0x30);
+        assert_eq!((ppu.scroll_x, ppu.scroll_y), (0x20, 0x30));
+    }
+
+    #[test]
+    fn test_palette_mirrors() {
+        let mut ppu = test_ppu();
+        set_addr(&mut ppu, 0x3F10);
+        ppu.write_to_data(0x2A);
+        set_addr(&mut ppu, 0x3F00);
+        assert_eq!(ppu.read_data(), 0x2A);
+
+        set_addr(&mut ppu, 0x3F00);
+        ppu.write_to_data(0x16);
+        set_addr(&mut ppu, 0x3F10);
+        assert_eq!(ppu.read_data(), 0x16);
+
+        // 0x3F1C is 0x3F0C, and the whole range repeats every 32 bytes
+        set_addr(&mut ppu, 0x3F1C);
+        ppu.write_to_data(0x30);
+        set_addr(&mut ppu, 0x3FEC);
+        assert_eq!(ppu.read_data(), 0x30);
+        // 0x3F11 has its own entry
+        set_addr(&mut ppu, 0x3F11);
+        ppu.write_to_data(0x05);
+        assert_eq!(ppu.palette_table[1], 0);
+    }
+
+    #[test]
+    fn test_palette_read_fills_buffer_from_nametable() {
+        let mut ppu = test_ppu();
+        set_addr(&mut ppu, 0x2F00);
+        ppu.write_to_data(0x55);
+        set_addr(&mut ppu, 0x3F00);
+        ppu.write_to_data(0x21);
+
+        set_addr(&mut ppu, 0x3F00);
+        assert_eq!(ppu.read_data(), 0x21);
+        // the next buffered read returns what sits under 0x3F00
+        set_addr(&mut ppu, 0x2000);
+        assert_eq!(ppu.read_data(), 0x55);
+    }
+
+    #[test]
+    fn test_palette_read_while_rendering() {
+        let mut ppu = test_ppu();
+        for (i, color) in [0x0F, 0x21, 0x16, 0x30].iter().enumerate() {
+            set_addr(&mut ppu, PALETTE + i as u16);
+            ppu.write_to_data(*color);
+        }
+        set_addr(&mut ppu, PALETTE);
+        let idle: Vec<u8> = (0..4).map(|_| ppu.read_data()).collect();
+        assert_eq!(idle, vec![0x0F, 0x21, 0x16, 0x30]);
+
+        // defined, if not hardware exact, in the middle of a visible line
+        ppu.write_to_mask(MaskRegister::SHOW_BACKGROUND.bits());
+        ppu.advance_to_scanline(100, 128).unwrap();
+        set_addr(&mut ppu, PALETTE + 1);
+        assert_eq!(ppu.read_data(), 0x21);
+    }
+
+    #[test]
+    fn test_render_background_tile() {
+        let mut ppu = test_ppu();
+        // tile 1 in the 0x1000 table: every row is colors 0, 0, 2, 2, 1, 1, 3, 3
+        for row in 0..8 {
+            ppu.write_chr(0x1010 + row, 0x0F);
+            ppu.write_chr(0x1018 + row, 0x33);
+        }
+        set_addr(&mut ppu, 0x2000);
+        ppu.write_to_data(1);
+        // the top-left quadrant uses background palette 1
+        set_addr(&mut ppu, 0x23C0);
+        ppu.write_to_data(0b01);
+        set_addr(&mut ppu, 0x3F00);
+        ppu.write_to_data(0x0F);
+        set_addr(&mut ppu, 0x3F05);
+        for color in &[0x21, 0x16, 0x30] {
+            ppu.write_to_data(*color);
+        }
+        ppu.write_to_ctrl(ControlRegister::BACKGROUND_PATTERN_ADDR.bits());
+        ppu.write_to_mask(MaskRegister::SHOW_BACKGROUND.bits() | MaskRegister::LEFTMOST_8PXL_BACKGROUND.bits());
+
+        let frame = ppu.render();
+        assert_eq!(frame.len(), SCREEN_WIDTH * SCREEN_HEIGHT * 3);
+        let pixel = |x: usize, y: usize| {
+            let i = (y * SCREEN_WIDTH + x) * 3;
+            (frame[i], frame[i + 1], frame[i + 2])
+        };
+        let expected = [0x0F, 0x0F, 0x16, 0x16, 0x21, 0x21, 0x30, 0x30];
+        for y in 0..8 {
+            for (x, color) in expected.iter().enumerate() {
+                assert_eq!(pixel(x, y), palette::rgb(*color), "pixel ({}, {})", x, y);
+            }
+        }
+        // tile 0 is blank, the backdrop shows
+        assert_eq!(pixel(8, 0), palette::rgb(0x0F));
+    }
+
+    #[test]
+    fn test_render_sprite_flips_and_priority() {
+        let mut ppu = test_ppu();
+        // sprite tile 2 has a single opaque pixel, its top-left one
+        ppu.write_chr(0x20, 0x80);
+        // background tile 1 is solid
+        for row in 0..8 {
+            ppu.write_chr(0x10 + row, 0xFF);
+        }
+        ppu.palette_table[0] = 0x0F;
+        ppu.palette_table[1] = 0x21;
+        ppu.palette_table[0x15] = 0x16;
+        ppu.write_to_mask(MaskRegister::SHOW_SPRITES.bits());
+
+        // (y, x) of the lit pixel for a sprite at (30, 20), by attribute
+        let cases = [
+            (0b0000_0001, (20, 30)),
+            (0b0100_0001, (20, 37)),
+            (0b1000_0001, (27, 30)),
+            (0b1100_0001, (27, 37)),
+        ];
+        for (attr, (y, x)) in cases.iter() {
+            ppu.oam_data[..4].copy_from_slice(&[19, 2, *attr, 30]);
+            let mut frame = Frame::new(SCREEN_WIDTH, SCREEN_HEIGHT);
+            ppu.render_frame_into(&mut frame);
+            for py in 20..28 {
+                for px in 30..38 {
+                    let expected = if (py, px) == (*y, *x) { 0x16 } else { 0x0F };
+                    assert_eq!(frame.pixel(px, py), palette::rgb(expected), "attr {:08b} ({}, {})", attr, px, py);
+                }
+            }
+        }
+
+        // behind an opaque background pixel the sprite is hidden
+        set_addr(&mut ppu, 0x2000 + 2 * 32 + 4);
+        ppu.write_to_data(1);
+        ppu.write_to_mask((MaskRegister::SHOW_BACKGROUND | MaskRegister::SHOW_SPRITES).bits());
+        let mut frame = Frame::new(SCREEN_WIDTH, SCREEN_HEIGHT);
+        ppu.oam_data[..4].copy_from_slice(&[19, 2, 0b0010_0001, 32]);
+        ppu.render_frame_into(&mut frame);
+        assert_eq!(frame.pixel(32, 20), palette::rgb(0x21));
+        ppu.oam_data[2] = 0b01;
+        ppu.render_frame_into(&mut frame);
+        assert_eq!(frame.pixel(32, 20), palette::rgb(0x16));
+    }
+
+    #[test]
+    fn test_sprite_zero_hit() {
+        // sprite 0 at y=0 covers scanlines 1-8, its opaque column at x=250
+        let mut ppu = sprite_zero_scene(250);
+        let mut dots = 0;
+        while !ppu.status.contains(StatusRegister::SPRITE_ZERO_HIT) {
+            ppu.tick(1);
+            dots += 1;
+            assert!(dots < 2 * DOTS_PER_SCANLINE as usize, "no hit by scanline 2");
+        }
+        // dot 251 draws x=250
+        assert_eq!((ppu.scanline(), ppu.dot()), (1, 251));
+        assert_eq!(ppu.read_status() & 0x40, 0x40);
+
+        // reading PPUSTATUS leaves it, the pre-render line clears it
+        ppu.advance_to_scanline(261, 0).unwrap();
+        assert!(ppu.status.contains(StatusRegister::SPRITE_ZERO_HIT));
+        ppu.advance_to_scanline(261, 2).unwrap();
+        assert!(!ppu.status.contains(StatusRegister::SPRITE_ZERO_HIT));
+    }
+}
@@ -0,0 +1,102 @@
+use crate::bus::Bus;
+use crate::cartridge::Rom;
+use crate::cpu::CpuError;
+use crate::cpu::Flags;
+use crate::cpu::CPU;
+use crate::opcodes;
+
+// NROM image with `program` at 0x8000 and the reset vector pointing to it
+pub fn scratch_cpu(program: &[u8]) -> CPU {
+    let mut raw = vec![0x4E, 0x45, 0x53, 0x1A, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+    let mut prg_rom = vec![0; 0x4000];
+    prg_rom[..program.len()].copy_from_slice(program);
+    prg_rom[0x3FFC] = 0x00;
+    prg_rom[0x3FFD] = 0x80;
+    raw.extend(prg_rom);
+
+    let mut cpu = CPU::new(Bus::new(Rom::new(&raw).unwrap()));
+    cpu.set_halt_on_brk(true);
+    cpu.reset();
+    cpu
+}
+
+pub fn has_handler(code: u8) -> bool {
+    // zeroed operands keep every access in RAM and control flow always
+    // ends on a BRK
+    let mut cpu = scratch_cpu(&[code, 0, 0, 0]);
+    !matches!(cpu.run(), Err(CpuError::Unimplemented { .. }))
+}
+
+// handled is has_handler outside of tests
+fn check_handlers<F: Fn(u8) -> bool>(handled: F) -> Result<(), String> {
+    // the official set must be handled, most unofficial opcodes are only in
+    // the table for tracing purposes
+    for op in opcodes::OPS_CODES.iter().filter(|op| !op.mnemonic.starts_with('*')) {
+        if !handled(op.code) {
+            return Err(format!("opcode {:02x} ({}) has no handler", op.code, op.mnemonic));
+        }
+    }
+    Ok(())
+}
+
+fn check_tables() -> Result<(), String> {
+    for code in 0..=0xffu8 {
+        let from_map = opcodes::OPCODES_MAP.get(&code).cloned();
+        let from_array = opcodes::OPCODES_ARRAY[code as usize];
+        let same = match (from_map, from_array) {
+            (Some(a), Some(b)) => std::ptr::eq(a, b),
+            (None, None) => true,
+            _ => false,
+        };
+        if !same {
+            return Err(format!("opcode {:02x} differs between OPCODES_MAP and OPCODES_ARRAY", code));
+        }
+    }
+    Ok(())
+}
+
+fn check_programs() -> Result<(), String> {
+    // LDA #$05; ADC #$03; BRK
+    let mut cpu = scratch_cpu(&[0xa9, 0x05, 0x69, 0x03, 0x00]);
+    cpu.run().map_err(|e| format!("{:?}", e))?;
+    if cpu.register_a != 0x08 {
+        return Err(format!("ADC: expected A = 08, got {:02x}", cpu.register_a));
+    }
+
+    // LDA #$ff; TAX; INX; BRK
+    let mut cpu = scratch_cpu(&[0xa9, 0xff, 0xaa, 0xe8, 0x00]);
+    cpu.run().map_err(|e| format!("{:?}", e))?;
+    if cpu.register_x != 0 || !cpu.status.contains(Flags::ZERO) {
+        return Err(format!("INX: expected X = 00 with ZERO set, got {:02x}", cpu.register_x));
+    }
+
+    // LDX #$03; loop: DEX; BNE loop; BRK
+    let mut cpu = scratch_cpu(&[0xa2, 0x03, 0xca, 0xd0, 0xfd, 0x00]);
+    cpu.run().map_err(|e| format!("{:?}", e))?;
+    if cpu.register_x != 0 {
+        return Err(format!("BNE loop: expected X = 00, got {:02x}", cpu.register_x));
+    }
+
+    Ok(())
+}
+
+pub fn self_test() -> Result<(), String> {
+    check_tables()?;
+    check_handlers(has_handler)?;
+    check_programs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_self_test() {
+        assert_eq!(self_test(), Ok(()));
+        // as if the ADC immediate arm had been deleted
+        assert_eq!(
+            check_handlers(|code| code != 0x69 && has_handler(code)),
+            Err(String::from("opcode 69 (ADC) has no handler"))
+        );
+    }
+}
@@ -0,0 +1,56 @@
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Region {
+    NTSC,
+    PAL,
+}
+
+// PPU dots per CPU cycle, as a ratio: 3/1 on NTSC, 16/5 (3.2) on PAL
+fn dots_per_cycle(region: Region) -> (usize, usize) {
+    match region {
+        Region::NTSC => (3, 1),
+        Region::PAL => (16, 5),
+    }
+}
+
+pub fn cpu_cycles_to_ppu_dots(cycles: usize, region: Region) -> usize {
+    let (dots, per_cycles) = dots_per_cycle(region);
+    cycles * dots / per_cycles
+}
+
+// Rounds down: a partially elapsed CPU cycle is not counted
+pub fn ppu_dots_to_cpu_cycles(dots: usize, region: Region) -> usize {
+    let (per_dots, cycles) = dots_per_cycle(region);
+    dots * cycles / per_dots
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_conversions() {
+        assert_eq!(cpu_cycles_to_ppu_dots(1, Region::NTSC), 3);
+        assert_eq!(ppu_dots_to_cpu_cycles(3, Region::NTSC), 1);
+        // 3.2 dots per cycle
+        assert_eq!(cpu_cycles_to_ppu_dots(5, Region::PAL), 16);
+        assert_eq!(ppu_dots_to_cpu_cycles(16, Region::PAL), 5);
+        // partial cycles round down
+        assert_eq!(ppu_dots_to_cpu_cycles(2, Region::NTSC), 0);
+        assert_eq!(cpu_cycles_to_ppu_dots(1, Region::PAL), 3);
+
+        for cycles in 0..1000 {
+            let dots = cpu_cycles_to_ppu_dots(cycles, Region::NTSC);
+            assert_eq!(ppu_dots_to_cpu_cycles(dots, Region::NTSC), cycles);
+
+            // exact on whole dots, every 5 cycles. In between the dropped
+            // fraction of a dot costs at most the last cycle.
+            let dots = cpu_cycles_to_ppu_dots(cycles, Region::PAL);
+            let back = ppu_dots_to_cpu_cycles(dots, Region::PAL);
+            if cycles % 5 == 0 {
+                assert_eq!(back, cycles);
+            } else {
+                assert!(back == cycles || back + 1 == cycles, "PAL {} -> {} -> {}", cycles, dots, back);
+            }
+        }
+    }
+}